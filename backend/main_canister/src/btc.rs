@@ -0,0 +1,376 @@
+use crate::memory::{self, Memory};
+use crate::StorableString;
+use base64::Engine;
+use bech32::{FromBase32, Variant};
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::bitcoin::{
+    bitcoin_get_utxos, BitcoinNetwork, GetUtxosRequest, GetUtxosResponse, UtxoFilter,
+};
+use ic_cdk::export::Principal;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use ripemd::Ripemd160;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use uuid::Uuid;
+
+// Bitcoin balance attestations. The caller proves control of a P2WPKH or P2TR
+// address with a BIP-322 "simple" signature over `attestation_message`, which
+// names the caller, so a signature cannot be replayed by anyone else. The balance
+// is then read from the management canister's Bitcoin API and only the threshold
+// it meets is attested, anchored to the tip block it was read at.
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum BtcNetwork {
+    Mainnet,
+    Testnet,
+    // A local dfx replica with bitcoind in regtest mode
+    Regtest,
+}
+
+impl BtcNetwork {
+    fn hrp(self) -> &'static str {
+        match self {
+            BtcNetwork::Mainnet => "bc",
+            BtcNetwork::Testnet => "tb",
+            BtcNetwork::Regtest => "bcrt",
+        }
+    }
+
+    fn api(self) -> BitcoinNetwork {
+        match self {
+            BtcNetwork::Mainnet => BitcoinNetwork::Mainnet,
+            BtcNetwork::Testnet => BitcoinNetwork::Testnet,
+            BtcNetwork::Regtest => BitcoinNetwork::Regtest,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BtcConfig {
+    pub network: BtcNetwork,
+    // UTXOs with fewer confirmations are not counted
+    pub min_confirmations: u32,
+}
+
+impl Default for BtcConfig {
+    fn default() -> Self {
+        BtcConfig { network: BtcNetwork::Mainnet, min_confirmations: 6 }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BtcAttestation {
+    pub id: String,
+    pub owner: Principal,
+    pub network: BtcNetwork,
+    pub min_satoshis: u64,
+    pub min_confirmations: u32,
+    pub tip_height: u32,
+    pub tip_block_hash: Vec<u8>,
+    pub attested_at: u64,
+}
+
+thread_local! {
+    static BTC_CONFIG: RefCell<StableCell<BtcConfig, Memory>> =
+        RefCell::new(StableCell::init(memory::get(memory::BTC_CONFIG), BtcConfig::default())
+            .expect("failed to initialize the Bitcoin config"));
+
+    // The attested address is deliberately not kept
+    static BTC_ATTESTATIONS: RefCell<StableBTreeMap<StorableString, BtcAttestation, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::BTC_ATTESTATIONS)));
+}
+
+pub fn config() -> BtcConfig {
+    BTC_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+pub fn set_config(config: BtcConfig) {
+    BTC_CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .expect("failed to write the Bitcoin config");
+    });
+}
+
+// What the owner of `address` signs to let `principal` attest its balance
+pub fn attestation_message(principal: &Principal, address: &str) -> String {
+    format!("Attest the balance of Bitcoin address {} for principal {}", address, principal)
+}
+
+// A native segwit output this module can verify signatures for
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    P2wpkh([u8; 20]),
+    P2tr([u8; 32]),
+}
+
+impl Address {
+    pub fn parse(address: &str, network: BtcNetwork) -> Result<Address, String> {
+        let (hrp, data, variant) = bech32::decode(address).map_err(|e| format!("Invalid address: {}", e))?;
+        if hrp != network.hrp() {
+            return Err(format!("{} is not a {:?} address", address, network));
+        }
+        let (version, program) = data.split_first().ok_or("Address has no witness program")?;
+        let program = Vec::<u8>::from_base32(program).map_err(|e| format!("Invalid address: {}", e))?;
+        match (version.to_u8(), variant, program.len()) {
+            (0, Variant::Bech32, 20) => Ok(Address::P2wpkh(program.try_into().unwrap())),
+            (1, Variant::Bech32m, 32) => Ok(Address::P2tr(program.try_into().unwrap())),
+            _ => Err("Only P2WPKH and P2TR addresses are supported".to_string()),
+        }
+    }
+
+    fn script_pubkey(&self) -> Vec<u8> {
+        match self {
+            Address::P2wpkh(hash) => [&[0x00, 0x14][..], hash].concat(),
+            Address::P2tr(key) => [&[0x51, 0x20][..], key].concat(),
+        }
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn double_sha256(data: &[u8]) -> [u8; 32] {
+    sha256(&sha256(data))
+}
+
+fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag = sha256(tag.as_bytes());
+    Sha256::new().chain_update(tag).chain_update(tag).chain_update(data).finalize().into()
+}
+
+fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(sha256(data)).into()
+}
+
+// Compact size prefix; nothing here is longer than 252 bytes
+fn var_bytes(bytes: &[u8]) -> Vec<u8> {
+    assert!(bytes.len() < 0xfd);
+    [&[bytes.len() as u8][..], bytes].concat()
+}
+
+fn read_var_bytes(data: &mut &[u8]) -> Result<Vec<u8>, String> {
+    let (&len, rest) = data.split_first().ok_or("Truncated signature")?;
+    if len >= 0xfd || rest.len() < len as usize {
+        return Err("Malformed signature".to_string());
+    }
+    let (item, rest) = rest.split_at(len as usize);
+    *data = rest;
+    Ok(item.to_vec())
+}
+
+// BIP-322: the message is committed to by a virtual transaction spending nothing
+// to the address, and signed as if spending that output in a second virtual
+// transaction with a single OP_RETURN output. Both have version 0, lock time 0 and
+// input sequence 0, and the output is worth 0.
+fn to_spend_txid(address: &Address, message: &str) -> [u8; 32] {
+    let message_hash = tagged_hash("BIP0322-signed-message", message.as_bytes());
+    let script_sig = [&[0x00, 0x20][..], &message_hash].concat();
+    let mut tx = Vec::new();
+    tx.extend_from_slice(&0u32.to_le_bytes());
+    tx.push(1);
+    tx.extend_from_slice(&[0; 32]);
+    tx.extend_from_slice(&u32::MAX.to_le_bytes());
+    tx.extend(var_bytes(&script_sig));
+    tx.extend_from_slice(&0u32.to_le_bytes());
+    tx.push(1);
+    tx.extend_from_slice(&0u64.to_le_bytes());
+    tx.extend(var_bytes(&address.script_pubkey()));
+    tx.extend_from_slice(&0u32.to_le_bytes());
+    double_sha256(&tx)
+}
+
+const OP_RETURN_OUTPUT: [u8; 10] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 0x6a];
+
+// BIP-143 digest of the to_sign input spending a P2WPKH output, SIGHASH_ALL
+fn segwit_v0_sighash(prevout_txid: &[u8; 32], pubkey_hash: &[u8; 20]) -> [u8; 32] {
+    let outpoint = [&prevout_txid[..], &0u32.to_le_bytes()].concat();
+    let script_code = [&[0x19, 0x76, 0xa9, 0x14][..], pubkey_hash, &[0x88, 0xac]].concat();
+    let mut preimage = Vec::new();
+    preimage.extend_from_slice(&0u32.to_le_bytes());
+    preimage.extend_from_slice(&double_sha256(&outpoint));
+    preimage.extend_from_slice(&double_sha256(&0u32.to_le_bytes()));
+    preimage.extend_from_slice(&outpoint);
+    preimage.extend_from_slice(&script_code);
+    preimage.extend_from_slice(&0u64.to_le_bytes());
+    preimage.extend_from_slice(&0u32.to_le_bytes());
+    preimage.extend_from_slice(&double_sha256(&OP_RETURN_OUTPUT));
+    preimage.extend_from_slice(&0u32.to_le_bytes());
+    preimage.extend_from_slice(&1u32.to_le_bytes());
+    double_sha256(&preimage)
+}
+
+// BIP-341 digest of the to_sign input spending a P2TR output by key path
+fn taproot_sighash(prevout_txid: &[u8; 32], script_pubkey: &[u8], hash_type: u8) -> [u8; 32] {
+    let outpoint = [&prevout_txid[..], &0u32.to_le_bytes()].concat();
+    let mut message = vec![0x00, hash_type];
+    message.extend_from_slice(&0u32.to_le_bytes());
+    message.extend_from_slice(&0u32.to_le_bytes());
+    message.extend_from_slice(&sha256(&outpoint));
+    message.extend_from_slice(&sha256(&0u64.to_le_bytes()));
+    message.extend_from_slice(&sha256(&var_bytes(script_pubkey)));
+    message.extend_from_slice(&sha256(&0u32.to_le_bytes()));
+    message.extend_from_slice(&sha256(&OP_RETURN_OUTPUT));
+    message.push(0x00);
+    message.extend_from_slice(&0u32.to_le_bytes());
+    tagged_hash("TapSighash", &message)
+}
+
+// Check a base64 BIP-322 simple signature (the to_sign witness) of `message` by `address`
+pub fn verify_signature(address: &Address, message: &str, signature: &str) -> Result<(), String> {
+    let witness = base64::engine::general_purpose::STANDARD
+        .decode(signature)
+        .map_err(|e| format!("Signature is not base64: {}", e))?;
+    let mut data = witness.as_slice();
+    let (&count, rest) = data.split_first().ok_or("Empty signature")?;
+    data = rest;
+    let items = (0..count).map(|_| read_var_bytes(&mut data)).collect::<Result<Vec<_>, _>>()?;
+    if !data.is_empty() {
+        return Err("Trailing bytes after the signature witness".to_string());
+    }
+
+    let txid = to_spend_txid(address, message);
+    let invalid = || "Invalid signature".to_string();
+    match (address, items.as_slice()) {
+        (Address::P2wpkh(hash), [signature, pubkey]) => {
+            if hash160(pubkey) != *hash {
+                return Err("Public key does not match the address".to_string());
+            }
+            let (&hash_type, der) = signature.split_last().ok_or_else(invalid)?;
+            if hash_type != 0x01 {
+                return Err("Only SIGHASH_ALL signatures are supported".to_string());
+            }
+            let key = k256::ecdsa::VerifyingKey::from_sec1_bytes(pubkey).map_err(|_| invalid())?;
+            let signature = k256::ecdsa::Signature::from_der(der).map_err(|_| invalid())?;
+            let signature = signature.normalize_s().unwrap_or(signature);
+            key.verify_prehash(&segwit_v0_sighash(&txid, hash), &signature).map_err(|_| invalid())
+        }
+        (Address::P2tr(output_key), [signature]) => {
+            let (signature, hash_type) = match signature.len() {
+                64 => (&signature[..], 0x00),
+                65 if signature[64] == 0x01 => (&signature[..64], 0x01),
+                _ => return Err("Only SIGHASH_DEFAULT and SIGHASH_ALL signatures are supported".to_string()),
+            };
+            let key = k256::schnorr::VerifyingKey::from_bytes(output_key).map_err(|_| invalid())?;
+            let signature = k256::schnorr::Signature::try_from(signature).map_err(|_| invalid())?;
+            let sighash = taproot_sighash(&txid, &address.script_pubkey(), hash_type);
+            key.verify_prehash(&sighash, &signature).map_err(|_| invalid())
+        }
+        _ => Err("Unexpected witness for the address type".to_string()),
+    }
+}
+
+// Confirmed balance of `address` and the tip it was read at
+pub async fn confirmed_balance(address: &str, config: &BtcConfig) -> Result<(u64, GetUtxosResponse), String> {
+    let mut request = GetUtxosRequest {
+        address: address.to_string(),
+        network: config.network.api(),
+        filter: Some(UtxoFilter::MinConfirmations(config.min_confirmations)),
+    };
+    let mut first: Option<GetUtxosResponse> = None;
+    let mut balance: u64 = 0;
+    loop {
+        let (page,) = bitcoin_get_utxos(request.clone())
+            .await
+            .map_err(|(_, msg)| format!("bitcoin_get_utxos failed: {}", msg))?;
+        for utxo in &page.utxos {
+            balance = balance.checked_add(utxo.value).ok_or("Balance overflows 64 bits")?;
+        }
+        let next_page = page.next_page.clone();
+        first.get_or_insert(page);
+        match next_page {
+            Some(next_page) => request.filter = Some(UtxoFilter::Page(next_page)),
+            None => break,
+        }
+    }
+    Ok((balance, first.expect("at least one page was read")))
+}
+
+// Attest that `owner` controls `address` and that it holds at least `min_satoshis`
+// in UTXOs with the configured number of confirmations
+pub async fn attest(owner: Principal, address: &str, signature: &str, min_satoshis: u64, now: u64) -> Result<BtcAttestation, String> {
+    if owner == Principal::anonymous() {
+        return Err("Anonymous callers cannot attest Bitcoin balances".to_string());
+    }
+    let config = config();
+    let parsed = Address::parse(address, config.network)?;
+    verify_signature(&parsed, &attestation_message(&owner, address), signature)?;
+
+    let (balance, tip) = confirmed_balance(address, &config).await?;
+    if balance < min_satoshis {
+        return Err(format!("Confirmed balance is below {} satoshis", min_satoshis));
+    }
+
+    let attestation = BtcAttestation {
+        id: Uuid::new_v4().to_string(),
+        owner,
+        network: config.network,
+        min_satoshis,
+        min_confirmations: config.min_confirmations,
+        tip_height: tip.tip_height,
+        tip_block_hash: tip.tip_block_hash,
+        attested_at: now,
+    };
+    BTC_ATTESTATIONS.with(|attestations| {
+        attestations.borrow_mut().insert(StorableString(attestation.id.clone()), attestation.clone());
+    });
+    Ok(attestation)
+}
+
+pub fn attestation(id: &str) -> Option<BtcAttestation> {
+    BTC_ATTESTATIONS.with(|attestations| attestations.borrow().get(&StorableString(id.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from BIP-322
+    const P2WPKH: &str = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+    const P2TR: &str = "bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3";
+
+    #[test]
+    fn message_hashes() {
+        assert_eq!(
+            hex::encode(tagged_hash("BIP0322-signed-message", b"")),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            hex::encode(tagged_hash("BIP0322-signed-message", b"Hello World")),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+    }
+
+    #[test]
+    fn verifies_p2wpkh_signatures() {
+        let address = Address::parse(P2WPKH, BtcNetwork::Mainnet).unwrap();
+        let empty = "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        let hello = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        assert_eq!(verify_signature(&address, "", empty), Ok(()));
+        assert_eq!(verify_signature(&address, "Hello World", hello), Ok(()));
+        assert!(verify_signature(&address, "Hello World", empty).is_err());
+    }
+
+    #[test]
+    fn verifies_p2tr_signatures() {
+        let address = Address::parse(P2TR, BtcNetwork::Mainnet).unwrap();
+        let hello = "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+        assert_eq!(verify_signature(&address, "Hello World", hello), Ok(()));
+        assert!(verify_signature(&address, "Hello", hello).is_err());
+    }
+
+    #[test]
+    fn addresses_must_match_the_network_and_type() {
+        assert!(Address::parse(P2WPKH, BtcNetwork::Regtest).is_err());
+        // P2PKH
+        assert!(Address::parse("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", BtcNetwork::Mainnet).is_err());
+        // P2WSH
+        assert!(Address::parse(
+            "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3",
+            BtcNetwork::Mainnet
+        ).is_err());
+    }
+}
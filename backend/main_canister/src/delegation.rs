@@ -0,0 +1,180 @@
+use crate::memory::{self, Memory};
+use candid::{CandidType, Deserialize};
+use ic_cdk::export::Principal;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Who may request proofs about whose balances. A caller may always prove its own
+// balances; proving for another principal takes a delegation that the owner
+// registered from its own identity, and that it can revoke at any time.
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Delegation {
+    pub owner: Principal,
+    pub delegate: Principal,
+    pub granted_at: u64,
+    // Never expires when absent
+    pub expires_at: Option<u64>,
+}
+
+impl Delegation {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.map_or(true, |expires_at| now < expires_at)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DelegationKey {
+    owner: Principal,
+    delegate: Principal,
+}
+
+impl Storable for DelegationKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::new();
+        for principal in [&self.owner, &self.delegate] {
+            bytes.push(principal.as_slice().len() as u8);
+            bytes.extend_from_slice(principal.as_slice());
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let owner_len = bytes[0] as usize;
+        let delegate_len = bytes[1 + owner_len] as usize;
+        DelegationKey {
+            owner: Principal::from_slice(&bytes[1..1 + owner_len]),
+            delegate: Principal::from_slice(&bytes[2 + owner_len..2 + owner_len + delegate_len]),
+        }
+    }
+}
+
+impl BoundedStorable for DelegationKey {
+    const MAX_SIZE: u32 = 2 * (1 + 29);
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static DELEGATIONS: RefCell<StableBTreeMap<DelegationKey, Delegation, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::DELEGATIONS)));
+}
+
+fn require_authenticated(caller: Principal) -> Result<(), String> {
+    if caller == Principal::anonymous() {
+        return Err("Anonymous callers cannot request proofs".to_string());
+    }
+    Ok(())
+}
+
+// Let `delegate` request proofs about the balances of `owner` until `expires_at`.
+// Granting again replaces the earlier delegation.
+pub fn grant(owner: Principal, delegate: Principal, expires_at: Option<u64>, now: u64) -> Result<Delegation, String> {
+    require_authenticated(owner)?;
+    if delegate == Principal::anonymous() {
+        return Err("Cannot delegate to the anonymous principal".to_string());
+    }
+    if delegate == owner {
+        return Err("Cannot delegate to yourself".to_string());
+    }
+    if expires_at.map_or(false, |expires_at| expires_at <= now) {
+        return Err("Delegation would already be expired".to_string());
+    }
+
+    let delegation = Delegation { owner, delegate, granted_at: now, expires_at };
+    DELEGATIONS.with(|delegations| {
+        delegations.borrow_mut().insert(DelegationKey { owner, delegate }, delegation.clone());
+    });
+    Ok(delegation)
+}
+
+pub fn revoke(owner: Principal, delegate: Principal) -> Result<(), String> {
+    DELEGATIONS.with(|delegations| delegations.borrow_mut().remove(&DelegationKey { owner, delegate }))
+        .map(|_| ())
+        .ok_or_else(|| "No such delegation".to_string())
+}
+
+// Delegations granted by or to `principal`, expired ones included
+pub fn list(principal: Principal) -> Vec<Delegation> {
+    DELEGATIONS.with(|delegations| {
+        delegations.borrow()
+            .iter()
+            .map(|(_, delegation)| delegation)
+            .filter(|delegation| delegation.owner == principal || delegation.delegate == principal)
+            .collect()
+    })
+}
+
+// Whether `caller` may request proofs about the balances of `wallet`: its own, or
+// those of a principal with an active delegation to it
+pub fn authorize(caller: Principal, wallet: Principal, now: u64) -> Result<(), String> {
+    require_authenticated(caller)?;
+    if wallet == caller {
+        return Ok(());
+    }
+
+    let delegation = DELEGATIONS.with(|delegations| {
+        delegations.borrow().get(&DelegationKey { owner: wallet, delegate: caller })
+    });
+    match delegation {
+        Some(delegation) if delegation.is_active(now) => Ok(()),
+        Some(_) => Err(format!("Delegation from {} has expired", wallet)),
+        None => Err(format!("{} has not delegated proof requests to the caller", wallet)),
+    }
+}
+
+// The principal whose balances `caller` asks about: itself when `wallet` is absent
+pub fn resolve_wallet(caller: Principal, wallet: Option<&str>, now: u64) -> Result<Principal, String> {
+    let wallet = match wallet {
+        None => caller,
+        Some(text) => Principal::from_text(text).map_err(|e| format!("Invalid wallet address: {}", e))?,
+    };
+    authorize(caller, wallet, now)?;
+    Ok(wallet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    #[test]
+    fn callers_prove_for_themselves() {
+        let alice = principal(1);
+        assert_eq!(resolve_wallet(alice, None, 0), Ok(alice));
+        assert_eq!(resolve_wallet(alice, Some(&alice.to_text()), 0), Ok(alice));
+        assert!(resolve_wallet(Principal::anonymous(), None, 0).is_err());
+        assert!(resolve_wallet(alice, Some("not a principal"), 0).is_err());
+    }
+
+    #[test]
+    fn delegates_prove_until_revoked_or_expired() {
+        let (alice, bob, carol) = (principal(1), principal(2), principal(3));
+        assert!(resolve_wallet(bob, Some(&alice.to_text()), 0).is_err());
+
+        grant(alice, bob, Some(100), 10).unwrap();
+        assert_eq!(resolve_wallet(bob, Some(&alice.to_text()), 50), Ok(alice));
+        assert!(resolve_wallet(bob, Some(&alice.to_text()), 100).is_err());
+        // Delegation is one way and per delegate
+        assert!(resolve_wallet(alice, Some(&bob.to_text()), 50).is_err());
+        assert!(resolve_wallet(carol, Some(&alice.to_text()), 50).is_err());
+        assert_eq!(list(bob).len(), 1);
+
+        revoke(alice, bob).unwrap();
+        assert!(resolve_wallet(bob, Some(&alice.to_text()), 50).is_err());
+        assert!(revoke(alice, bob).is_err());
+    }
+
+    #[test]
+    fn rejects_pointless_delegations() {
+        let alice = principal(1);
+        assert!(grant(Principal::anonymous(), alice, None, 0).is_err());
+        assert!(grant(alice, Principal::anonymous(), None, 0).is_err());
+        assert!(grant(alice, alice, None, 0).is_err());
+        assert!(grant(alice, principal(2), Some(5), 5).is_err());
+    }
+}
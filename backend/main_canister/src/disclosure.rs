@@ -0,0 +1,261 @@
+use crate::memory::{self, Memory};
+use crate::merkle::{AccountScope, BalanceTree};
+use crate::roots;
+use candid::{CandidType, Deserialize, Encode};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument, SignWithEcdsaArgument,
+};
+use ic_cdk::export::Principal;
+use ic_stable_structures::StableCell;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// What a task reveals about the balance claim it proves. Every level evaluates the
+// claim against the balance tree and publishes the root it was read from:
+//   Anonymous - whether the claim holds, and the root
+//   Redacted  - also the token and the threshold, but not whose balance it is
+//   Full      - also the owner and the exact balance, signed by the canister's
+//               threshold ECDSA key so the statement can be shown to third parties
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum DisclosureLevel {
+    Anonymous,
+    Redacted,
+    Full,
+}
+
+impl DisclosureLevel {
+    // Levels were free-form text before; anything unrecognised was disclosed in full
+    pub fn from_legacy(level: &str) -> Self {
+        match level {
+            "anonymous" => DisclosureLevel::Anonymous,
+            "redacted" => DisclosureLevel::Redacted,
+            _ => DisclosureLevel::Full,
+        }
+    }
+}
+
+// `owner` holds at least `min_balance` of `token_id` in the balance tree
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BalanceClaim {
+    pub owner: Principal,
+    pub token_id: u64,
+    // The default account when absent
+    pub subaccount: Option<Vec<u8>>,
+    pub min_balance: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct AnonymousStatement {
+    pub holds: bool,
+    pub merkle_root: Vec<u8>,
+    pub epoch: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RedactedStatement {
+    pub holds: bool,
+    pub merkle_root: Vec<u8>,
+    pub epoch: u64,
+    pub token_id: u64,
+    pub min_balance: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FullStatement {
+    pub holds: bool,
+    pub merkle_root: Vec<u8>,
+    pub epoch: u64,
+    pub token_id: u64,
+    pub min_balance: u64,
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+    pub balance: u64,
+    pub issued_at: u64,
+}
+
+// `signature` is a 64-byte secp256k1 ECDSA signature over the SHA-256 of the
+// Candid encoding of `statement`; get_disclosure_public_key returns the key
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SignedStatement {
+    pub statement: FullStatement,
+    pub signature: Vec<u8>,
+    pub key_name: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum Disclosure {
+    Anonymous(AnonymousStatement),
+    Redacted(RedactedStatement),
+    Full(SignedStatement),
+}
+
+// The disclosure a task produced, kept on its Reference
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Artifact {
+    pub task_id: String,
+    pub created_at: u64,
+    pub disclosure: Disclosure,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct DisclosureConfig {
+    // "dfx_test_key" on a local replica, "test_key_1" or "key_1" on mainnet
+    pub ecdsa_key_name: String,
+}
+
+impl Default for DisclosureConfig {
+    fn default() -> Self {
+        DisclosureConfig { ecdsa_key_name: "key_1".to_string() }
+    }
+}
+
+thread_local! {
+    static DISCLOSURE_CONFIG: RefCell<StableCell<DisclosureConfig, Memory>> =
+        RefCell::new(StableCell::init(memory::get(memory::DISCLOSURE_CONFIG), DisclosureConfig::default())
+            .expect("failed to initialize the disclosure config"));
+}
+
+pub fn config() -> DisclosureConfig {
+    DISCLOSURE_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+pub fn set_config(config: DisclosureConfig) {
+    DISCLOSURE_CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .expect("failed to write the disclosure config");
+    });
+}
+
+fn key_id(config: &DisclosureConfig) -> EcdsaKeyId {
+    EcdsaKeyId { curve: EcdsaCurve::Secp256k1, name: config.ecdsa_key_name.clone() }
+}
+
+// Evaluate `claim` against `tree`, giving the full statement every level is cut from
+pub fn evaluate(tree: &BalanceTree, claim: &BalanceClaim, now: u64) -> Result<FullStatement, String> {
+    let subaccount = claim.subaccount.as_deref()
+        .map(|bytes| <[u8; 32]>::try_from(bytes).map_err(|_| "Subaccounts must be 32 bytes".to_string()))
+        .transpose()?;
+    let balance = tree.balance(claim.owner, claim.token_id, &AccountScope::of(subaccount)).unwrap_or(0);
+    Ok(FullStatement {
+        holds: balance >= claim.min_balance,
+        merkle_root: tree.root_hash().to_vec(),
+        epoch: 0,
+        token_id: claim.token_id,
+        min_balance: claim.min_balance,
+        owner: claim.owner,
+        subaccount: claim.subaccount.clone(),
+        balance,
+        issued_at: now,
+    })
+}
+
+pub fn anonymous(statement: &FullStatement) -> AnonymousStatement {
+    AnonymousStatement {
+        holds: statement.holds,
+        merkle_root: statement.merkle_root.clone(),
+        epoch: statement.epoch,
+    }
+}
+
+pub fn redacted(statement: &FullStatement) -> RedactedStatement {
+    RedactedStatement {
+        holds: statement.holds,
+        merkle_root: statement.merkle_root.clone(),
+        epoch: statement.epoch,
+        token_id: statement.token_id,
+        min_balance: statement.min_balance,
+    }
+}
+
+pub fn statement_hash(statement: &FullStatement) -> Vec<u8> {
+    let bytes = Encode!(statement).expect("failed to encode statement");
+    Sha256::digest(bytes).to_vec()
+}
+
+pub async fn full(statement: FullStatement) -> Result<SignedStatement, String> {
+    let config = config();
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: statement_hash(&statement),
+        derivation_path: vec![],
+        key_id: key_id(&config),
+    })
+    .await
+    .map_err(|(_, msg)| format!("sign_with_ecdsa failed: {}", msg))?;
+    Ok(SignedStatement { statement, signature: response.signature, key_name: config.ecdsa_key_name })
+}
+
+// Cut the artifact of `level` from an evaluated statement, publishing its root so
+// that the epoch it names can be checked against the root history
+pub async fn disclose(level: DisclosureLevel, mut statement: FullStatement) -> Result<Disclosure, String> {
+    statement.epoch = roots::publish(&statement.merkle_root, statement.issued_at).epoch;
+    Ok(match level {
+        DisclosureLevel::Anonymous => Disclosure::Anonymous(anonymous(&statement)),
+        DisclosureLevel::Redacted => Disclosure::Redacted(redacted(&statement)),
+        DisclosureLevel::Full => Disclosure::Full(full(statement).await?),
+    })
+}
+
+// SEC1-compressed public key that signs full disclosures
+pub async fn public_key() -> Result<Vec<u8>, String> {
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: key_id(&config()),
+    })
+    .await
+    .map_err(|(_, msg)| format!("ecdsa_public_key failed: {}", msg))?;
+    Ok(response.public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    #[test]
+    fn legacy_levels() {
+        assert_eq!(DisclosureLevel::from_legacy("anonymous"), DisclosureLevel::Anonymous);
+        assert_eq!(DisclosureLevel::from_legacy("redacted"), DisclosureLevel::Redacted);
+        assert_eq!(DisclosureLevel::from_legacy("full"), DisclosureLevel::Full);
+        assert_eq!(DisclosureLevel::from_legacy(""), DisclosureLevel::Full);
+    }
+
+    #[test]
+    fn levels_reveal_progressively_more() {
+        let mut tree = BalanceTree::init();
+        let owner = principal(7);
+        tree.set_balance(owner, 1, AccountScope::Default, 500).unwrap();
+        let claim = BalanceClaim { owner, token_id: 1, subaccount: None, min_balance: 100 };
+
+        let statement = evaluate(&tree, &claim, 42).unwrap();
+        assert!(statement.holds);
+        assert_eq!(statement.balance, 500);
+        assert_eq!(statement.merkle_root, tree.root_hash().to_vec());
+
+        let anonymous = anonymous(&statement);
+        assert_eq!(anonymous, AnonymousStatement { holds: true, merkle_root: statement.merkle_root.clone(), epoch: 0 });
+        let redacted = redacted(&statement);
+        assert_eq!((redacted.token_id, redacted.min_balance), (1, 100));
+
+        // Nothing about the owner leaks into the redacted encoding
+        let encoded = Encode!(&redacted).unwrap();
+        assert!(!encoded.windows(29).any(|window| window == owner.as_slice()));
+    }
+
+    #[test]
+    fn unmet_and_unknown_claims_do_not_hold() {
+        let mut tree = BalanceTree::init();
+        tree.set_balance(principal(1), 1, AccountScope::Default, 50).unwrap();
+        let claim = |owner, subaccount| BalanceClaim { owner, token_id: 1, subaccount, min_balance: 100 };
+
+        assert!(!evaluate(&tree, &claim(principal(1), None), 0).unwrap().holds);
+        assert_eq!(evaluate(&tree, &claim(principal(2), None), 0).unwrap().balance, 0);
+        assert!(evaluate(&tree, &claim(principal(1), Some(vec![1; 31])), 0).is_err());
+    }
+}
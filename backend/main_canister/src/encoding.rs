@@ -0,0 +1,826 @@
+use crate::btc::{BtcAttestation, BtcConfig};
+use crate::delegation::Delegation;
+use crate::disclosure::{Artifact, BalanceClaim, DisclosureConfig, DisclosureLevel};
+use crate::evm::EvmConfig;
+use crate::merkle::{AccountScope, BalanceLeaf};
+use crate::roots::{RootRecord, RootWindow};
+use crate::snapshot::SnapshotState;
+use crate::tasks::TaskStatus;
+use crate::{Reference, Task, TaskConfig, TokenProofResult, VerificationResult};
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk::export::Principal;
+use ic_stable_structures::{BoundedStorable, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+
+// Stored records are framed as one version byte followed by the Candid encoding of
+// that version's shape. To change a stored type, freeze its current shape below as
+// `<Type>V<n>`, bump the version constant and add a decode arm that upgrades it.
+// Changes that cannot be decoded in place belong in a migration (migrations.rs).
+pub const REFERENCE_VERSION: u8 = 4;
+pub const TOKEN_PROOF_RESULT_VERSION: u8 = 3;
+pub const VERIFICATION_RESULT_VERSION: u8 = 1;
+pub const BALANCE_LEAF_VERSION: u8 = 2;
+pub const ROOT_RECORD_VERSION: u8 = 1;
+pub const ROOT_WINDOW_VERSION: u8 = 1;
+pub const SNAPSHOT_STATE_VERSION: u8 = 1;
+pub const DELEGATION_VERSION: u8 = 1;
+pub const EVM_CONFIG_VERSION: u8 = 1;
+pub const BTC_CONFIG_VERSION: u8 = 1;
+pub const BTC_ATTESTATION_VERSION: u8 = 1;
+pub const DISCLOSURE_CONFIG_VERSION: u8 = 1;
+
+pub fn encode<T: CandidType>(version: u8, value: &T) -> Vec<u8> {
+    let mut bytes = vec![version];
+    bytes.extend(Encode!(value).expect("failed to encode stored record"));
+    bytes
+}
+
+// Split a stored record into its version and payload
+pub fn split(bytes: &[u8]) -> Result<(u8, &[u8]), String> {
+    bytes.split_first()
+        .map(|(version, payload)| (*version, payload))
+        .ok_or_else(|| "stored record is empty".to_string())
+}
+
+fn decode<T: CandidType + for<'de> Deserialize<'de>>(payload: &[u8]) -> Result<T, String> {
+    Decode!(payload, T).map_err(|e| e.to_string())
+}
+
+// Reference v1 was untagged serde_json; migrations.rs rewrites it as v2
+pub fn decode_reference(bytes: &[u8]) -> Result<Reference, String> {
+    match split(bytes)? {
+        (2, payload) => decode::<ReferenceV2>(payload).map(|v2| ReferenceV3::from(v2).into()),
+        (3, payload) => decode::<ReferenceV3>(payload).map(Into::into),
+        (4, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported Reference version {}", version)),
+    }
+}
+
+// Versioned TokenProofResults start at 2; the records written before versioning
+// are read by decode_legacy_token_proof_result
+pub fn decode_token_proof_result(bytes: &[u8]) -> Result<TokenProofResult, String> {
+    match split(bytes)? {
+        (2, payload) => decode::<TokenProofResultV2>(payload).map(Into::into),
+        (3, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported TokenProofResult version {}", version)),
+    }
+}
+
+pub fn decode_verification_result(bytes: &[u8]) -> Result<VerificationResult, String> {
+    match split(bytes)? {
+        (1, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported VerificationResult version {}", version)),
+    }
+}
+
+// Before versioning, TokenProofResult and VerificationResult were stored as their
+// fields concatenated, with no version byte and no lengths for the text fields:
+//
+//   TokenProofResult:   proof_id | token_id | merkle_root | u32 LE len | proof_data (len)
+//                       | anonymous_reference | u64 LE timestamp | u8 is_valid
+//   VerificationResult: proof_id | u8 is_verified | u64 LE timestamp
+//
+// proof_id and anonymous_reference were always hyphenated UUIDs and merkle_root the
+// hex of a 32-byte hash, which is enough to split the text apart again. Records in
+// this layout start with a UUID character, never with a version byte. migrations.rs
+// rewrites them as versioned Candid.
+const UUID_LEN: usize = 36;
+const HEX_ROOT_LEN: usize = 64;
+
+pub fn is_legacy_layout(bytes: &[u8]) -> bool {
+    bytes.first().map_or(false, u8::is_ascii_hexdigit)
+}
+
+fn legacy_text(bytes: &[u8], field: &str) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| format!("legacy {} is not UTF-8: {}", field, e))
+}
+
+pub fn decode_legacy_token_proof_result(bytes: &[u8]) -> Result<TokenProofResult, String> {
+    let trailer_len = UUID_LEN + 8 + 1;
+    if bytes.len() < UUID_LEN + 4 + trailer_len {
+        return Err(format!("legacy TokenProofResult of {} bytes is too short", bytes.len()));
+    }
+    let (head, trailer) = bytes.split_at(bytes.len() - trailer_len);
+    let (proof_id, body) = head.split_at(UUID_LEN);
+
+    // The text before the length prefix is printable, so read as a u32 it is far
+    // larger than any record; the first offset whose prefix covers exactly the rest
+    // of the body is where the text ends
+    let text_len = (0..=body.len() - 4)
+        .find(|&at| {
+            let len = u32::from_le_bytes(body[at..at + 4].try_into().unwrap()) as usize;
+            at + 4 + len == body.len()
+        })
+        .ok_or("legacy TokenProofResult has no proof data length")?;
+    let text = legacy_text(&body[..text_len], "token_id and merkle_root")?;
+    let root_at = text.len().saturating_sub(HEX_ROOT_LEN);
+    let (token_id, merkle_root) = match text.get(root_at..) {
+        Some(root) if root.len() == HEX_ROOT_LEN && root.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            (text[..root_at].to_string(), root.to_string())
+        }
+        _ => (text, String::new()),
+    };
+
+    Ok(TokenProofResult {
+        proof_id: legacy_text(proof_id, "proof_id")?,
+        token_id,
+        token_symbol: None,
+        merkle_root,
+        proof_data: body[text_len + 4..].to_vec(),
+        anonymous_reference: legacy_text(&trailer[..UUID_LEN], "anonymous_reference")?,
+        timestamp: u64::from_le_bytes(trailer[UUID_LEN..UUID_LEN + 8].try_into().unwrap()),
+        is_valid: trailer[UUID_LEN + 8] != 0,
+    })
+}
+
+// The anonymous reference was not stored, so it comes back empty
+pub fn decode_legacy_verification_result(bytes: &[u8]) -> Result<VerificationResult, String> {
+    if bytes.len() < 1 + 1 + 8 {
+        return Err(format!("legacy VerificationResult of {} bytes is too short", bytes.len()));
+    }
+    let (proof_id, trailer) = bytes.split_at(bytes.len() - 9);
+    Ok(VerificationResult {
+        is_verified: trailer[0] != 0,
+        proof_id: legacy_text(proof_id, "proof_id")?,
+        timestamp: u64::from_le_bytes(trailer[1..].try_into().unwrap()),
+        anonymous_reference: String::new(),
+    })
+}
+
+// v1 leaves were kept in maps bounded for the smaller shape; migrations.rs moves
+// them to the current maps
+pub fn decode_balance_leaf(bytes: &[u8]) -> Result<BalanceLeaf, String> {
+    match split(bytes)? {
+        (1, payload) => decode::<BalanceLeafV1>(payload).map(Into::into),
+        (2, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported BalanceLeaf version {}", version)),
+    }
+}
+
+pub fn decode_root_record(bytes: &[u8]) -> Result<RootRecord, String> {
+    match split(bytes)? {
+        (1, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported RootRecord version {}", version)),
+    }
+}
+
+pub fn decode_root_window(bytes: &[u8]) -> Result<RootWindow, String> {
+    match split(bytes)? {
+        (1, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported RootWindow version {}", version)),
+    }
+}
+
+pub fn decode_snapshot_state(bytes: &[u8]) -> Result<SnapshotState, String> {
+    match split(bytes)? {
+        (1, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported SnapshotState version {}", version)),
+    }
+}
+
+pub fn decode_delegation(bytes: &[u8]) -> Result<Delegation, String> {
+    match split(bytes)? {
+        (1, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported Delegation version {}", version)),
+    }
+}
+
+pub fn decode_evm_config(bytes: &[u8]) -> Result<EvmConfig, String> {
+    match split(bytes)? {
+        (1, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported EvmConfig version {}", version)),
+    }
+}
+
+pub fn decode_btc_config(bytes: &[u8]) -> Result<BtcConfig, String> {
+    match split(bytes)? {
+        (1, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported BtcConfig version {}", version)),
+    }
+}
+
+pub fn decode_btc_attestation(bytes: &[u8]) -> Result<BtcAttestation, String> {
+    match split(bytes)? {
+        (1, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported BtcAttestation version {}", version)),
+    }
+}
+
+pub fn decode_disclosure_config(bytes: &[u8]) -> Result<DisclosureConfig, String> {
+    match split(bytes)? {
+        (1, payload) => decode(payload),
+        (version, _) => Err(format!("unsupported DisclosureConfig version {}", version)),
+    }
+}
+
+// Reference before disclosure artifacts, with the disclosure level kept as text
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ReferenceV2 {
+    pub id: String,
+    pub tasks: Vec<TaskV2>,
+    pub zk_proof: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TaskV2 {
+    pub id: String,
+    pub description: String,
+    pub status: String,
+    pub timestamp: u64,
+    pub config: Option<TaskConfigV2>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TaskConfigV2 {
+    pub priority: String,
+    pub execution_delay: u64,
+    pub retry_attempts: u8,
+    pub disclosure_level: String,
+    pub storage_type: String,
+}
+
+impl From<ReferenceV2> for ReferenceV3 {
+    fn from(v2: ReferenceV2) -> Self {
+        ReferenceV3 {
+            id: v2.id,
+            tasks: v2.tasks.into_iter().map(Into::into).collect(),
+            zk_proof: v2.zk_proof,
+            artifacts: vec![],
+        }
+    }
+}
+
+impl From<TaskV2> for TaskV3 {
+    fn from(v2: TaskV2) -> Self {
+        TaskV3 {
+            id: v2.id,
+            description: v2.description,
+            status: v2.status,
+            timestamp: v2.timestamp,
+            config: v2.config.map(|config| TaskConfig {
+                priority: config.priority,
+                execution_delay: config.execution_delay,
+                retry_attempts: config.retry_attempts,
+                disclosure_level: DisclosureLevel::from_legacy(&config.disclosure_level),
+                storage_type: config.storage_type,
+            }),
+            claim: None,
+        }
+    }
+}
+
+// Reference before task statuses were typed and attempts recorded
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ReferenceV3 {
+    pub id: String,
+    pub tasks: Vec<TaskV3>,
+    pub zk_proof: Option<String>,
+    pub artifacts: Vec<Artifact>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TaskV3 {
+    pub id: String,
+    pub description: String,
+    pub status: String,
+    pub timestamp: u64,
+    pub config: Option<TaskConfig>,
+    pub claim: Option<BalanceClaim>,
+}
+
+impl From<ReferenceV3> for Reference {
+    fn from(v3: ReferenceV3) -> Self {
+        Reference {
+            id: v3.id,
+            tasks: v3.tasks.into_iter().map(Into::into).collect(),
+            zk_proof: v3.zk_proof,
+            artifacts: v3.artifacts,
+        }
+    }
+}
+
+impl From<TaskV3> for Task {
+    fn from(v3: TaskV3) -> Self {
+        Task {
+            id: v3.id,
+            description: v3.description,
+            status: TaskStatus::from_legacy(&v3.status),
+            timestamp: v3.timestamp,
+            config: v3.config,
+            claim: v3.claim,
+            attempts: vec![],
+        }
+    }
+}
+
+// TokenProofResult before the token symbol was recorded
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TokenProofResultV2 {
+    pub proof_id: String,
+    pub token_id: String,
+    pub merkle_root: String,
+    pub proof_data: Vec<u8>,
+    pub anonymous_reference: String,
+    pub timestamp: u64,
+    pub is_valid: bool,
+}
+
+impl From<TokenProofResultV2> for TokenProofResult {
+    fn from(v2: TokenProofResultV2) -> Self {
+        TokenProofResult {
+            proof_id: v2.proof_id,
+            token_id: v2.token_id,
+            token_symbol: None,
+            merkle_root: v2.merkle_root,
+            proof_data: v2.proof_data,
+            anonymous_reference: v2.anonymous_reference,
+            timestamp: v2.timestamp,
+            is_valid: v2.is_valid,
+        }
+    }
+}
+
+// BalanceLeaf before subaccounts: always the default account
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BalanceLeafV1 {
+    pub principal: Principal,
+    pub token_id: u64,
+    pub balance: u64,
+}
+
+impl From<BalanceLeafV1> for BalanceLeaf {
+    fn from(v1: BalanceLeafV1) -> Self {
+        BalanceLeaf {
+            principal: v1.principal,
+            token_id: v1.token_id,
+            scope: AccountScope::Default,
+            balance: v1.balance,
+        }
+    }
+}
+
+impl Storable for Reference {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(REFERENCE_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_reference(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+// tasks.rs bounds what a reference can grow to so that it stays under this
+impl BoundedStorable for Reference {
+    const MAX_SIZE: u32 = 32 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for TokenProofResult {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(TOKEN_PROOF_RESULT_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_token_proof_result(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl BoundedStorable for TokenProofResult {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for VerificationResult {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(VERIFICATION_RESULT_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_verification_result(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl BoundedStorable for VerificationResult {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for BalanceLeaf {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(BALANCE_LEAF_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_balance_leaf(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl BoundedStorable for BalanceLeaf {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RootRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(ROOT_RECORD_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_root_record(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl BoundedStorable for RootRecord {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RootWindow {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(ROOT_WINDOW_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_root_window(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl Storable for SnapshotState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(SNAPSHOT_STATE_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_snapshot_state(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl Storable for Delegation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(DELEGATION_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_delegation(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl BoundedStorable for Delegation {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for EvmConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(EVM_CONFIG_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_evm_config(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl Storable for BtcConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(BTC_CONFIG_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_btc_config(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl Storable for BtcAttestation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(BTC_ATTESTATION_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_btc_attestation(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl BoundedStorable for BtcAttestation {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for DisclosureConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode(DISCLOSURE_CONFIG_VERSION, self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_disclosure_config(&bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disclosure::{Disclosure, FullStatement, SignedStatement};
+    use crate::tasks;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    // Arbitrary unicode, capped so that every record stays under MAX_SIZE
+    fn text() -> impl Strategy<Value = String> {
+        "\\PC{0,64}"
+    }
+
+    fn token_proof_result() -> impl Strategy<Value = TokenProofResult> {
+        (
+            (text(), text(), proptest::option::of(text()), text()),
+            (vec(any::<u8>(), 0..1024), text(), any::<u64>(), any::<bool>()),
+        ).prop_map(
+            |((proof_id, token_id, token_symbol, merkle_root), (proof_data, anonymous_reference, timestamp, is_valid))| {
+                TokenProofResult {
+                    proof_id,
+                    token_id,
+                    token_symbol,
+                    merkle_root,
+                    proof_data,
+                    anonymous_reference,
+                    timestamp,
+                    is_valid,
+                }
+            },
+        )
+    }
+
+    fn verification_result() -> impl Strategy<Value = VerificationResult> {
+        (any::<bool>(), text(), any::<u64>(), text()).prop_map(
+            |(is_verified, proof_id, timestamp, anonymous_reference)| {
+                VerificationResult { is_verified, proof_id, timestamp, anonymous_reference }
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn token_proof_result_round_trips(result in token_proof_result()) {
+            let bytes = result.to_bytes();
+            prop_assert!(bytes.len() <= TokenProofResult::MAX_SIZE as usize);
+            prop_assert_eq!(TokenProofResult::from_bytes(bytes), result);
+        }
+
+        #[test]
+        fn verification_result_round_trips(result in verification_result()) {
+            let bytes = result.to_bytes();
+            prop_assert!(bytes.len() <= VerificationResult::MAX_SIZE as usize);
+            prop_assert_eq!(VerificationResult::from_bytes(bytes), result);
+        }
+    }
+
+    #[test]
+    fn keeps_identifiers_longer_than_32_bytes() {
+        let uuid = "4f9c2d1e-7a3b-4c5d-9e8f-0a1b2c3d4e5f".to_string();
+        let result = TokenProofResult {
+            proof_id: uuid.clone(),
+            token_id: "ryjl3-tyaaa-aaaaa-aaaba-cai".to_string(),
+            token_symbol: Some("ICP".to_string()),
+            merkle_root: "ab".repeat(32),
+            proof_data: vec![1, 2, 3],
+            anonymous_reference: uuid.clone(),
+            timestamp: 1_700_000_000_000_000_000,
+            is_valid: true,
+        };
+        assert_eq!(TokenProofResult::from_bytes(result.to_bytes()), result);
+    }
+
+    #[test]
+    fn decodes_v2_token_proof_result() {
+        let v2 = TokenProofResultV2 {
+            proof_id: "proof".to_string(),
+            token_id: "ryjl3-tyaaa-aaaaa-aaaba-cai".to_string(),
+            merkle_root: "ab".repeat(32),
+            proof_data: vec![1, 2, 3],
+            anonymous_reference: "reference".to_string(),
+            timestamp: 5,
+            is_valid: true,
+        };
+        let decoded = TokenProofResult::from_bytes(Cow::Owned(encode(2, &v2)));
+        assert_eq!(decoded.token_symbol, None);
+        assert_eq!(decoded.token_id, v2.token_id);
+        assert_eq!(decoded.proof_data, v2.proof_data);
+    }
+
+    // TokenProofResult::to_bytes before versioning, field for field
+    fn baseline_token_proof_bytes(result: &TokenProofResult) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(result.proof_id.as_bytes());
+        bytes.extend_from_slice(result.token_id.as_bytes());
+        bytes.extend_from_slice(result.merkle_root.as_bytes());
+        bytes.extend_from_slice(&(result.proof_data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&result.proof_data);
+        bytes.extend_from_slice(result.anonymous_reference.as_bytes());
+        bytes.extend_from_slice(&result.timestamp.to_le_bytes());
+        bytes.push(result.is_valid as u8);
+        bytes
+    }
+
+    #[test]
+    fn decodes_baseline_token_proof_results() {
+        let result = TokenProofResult {
+            proof_id: "4f9c2d1e-7a3b-4c5d-9e8f-0a1b2c3d4e5f".to_string(),
+            token_id: "ryjl3-tyaaa-aaaaa-aaaba-cai".to_string(),
+            token_symbol: None,
+            merkle_root: "0123456789abcdef".repeat(4),
+            // Proof bytes that look like a length prefix must not confuse the split
+            proof_data: vec![3, 0, 0, 0, 0xff, 0, 1, 2],
+            anonymous_reference: "9e8f0a1b-2c3d-4e5f-4f9c-2d1e7a3b4c5d".to_string(),
+            timestamp: 1_700_000_000_000_000_000,
+            is_valid: true,
+        };
+        let bytes = baseline_token_proof_bytes(&result);
+        assert!(is_legacy_layout(&bytes));
+        assert_eq!(decode_legacy_token_proof_result(&bytes), Ok(result.clone()));
+
+        // A record stored before any merkle root was known
+        let rootless = TokenProofResult { merkle_root: String::new(), proof_data: vec![], is_valid: false, ..result };
+        assert_eq!(decode_legacy_token_proof_result(&baseline_token_proof_bytes(&rootless)), Ok(rootless));
+
+        assert!(decode_legacy_token_proof_result(b"4f9c2d1e").is_err());
+        assert!(!is_legacy_layout(&TokenProofResult::to_bytes(&decode_legacy_token_proof_result(&bytes).unwrap())));
+    }
+
+    #[test]
+    fn decodes_baseline_verification_results() {
+        // proof_id, then the verified flag and the timestamp, as stored before versioning
+        let mut bytes = b"0a1b2c3d-4e5f-4f9c-2d1e-7a3b4c5d9e8f".to_vec();
+        bytes.push(1);
+        bytes.extend_from_slice(&1_700_000_000_000_000_000u64.to_le_bytes());
+
+        assert!(is_legacy_layout(&bytes));
+        assert_eq!(decode_legacy_verification_result(&bytes), Ok(VerificationResult {
+            is_verified: true,
+            proof_id: "0a1b2c3d-4e5f-4f9c-2d1e-7a3b4c5d9e8f".to_string(),
+            timestamp: 1_700_000_000_000_000_000,
+            anonymous_reference: String::new(),
+        }));
+        assert!(decode_legacy_verification_result(&bytes[..5]).is_err());
+    }
+
+    #[test]
+    fn decodes_v1_balance_leaf_as_default_account() {
+        let v1 = BalanceLeafV1 { principal: Principal::from_slice(&[4; 29]), token_id: 1, balance: 50 };
+        let leaf = decode_balance_leaf(&encode(1, &v1)).unwrap();
+        assert_eq!(leaf, BalanceLeaf {
+            principal: v1.principal,
+            token_id: 1,
+            scope: AccountScope::Default,
+            balance: 50,
+        });
+
+        let aggregate = BalanceLeaf { scope: AccountScope::Aggregate([0xff; 32]), ..leaf };
+        let bytes = aggregate.to_bytes();
+        assert!(bytes.len() <= BalanceLeaf::MAX_SIZE as usize);
+        assert_eq!(BalanceLeaf::from_bytes(bytes), aggregate);
+    }
+
+    #[test]
+    fn decodes_v2_reference_with_typed_disclosure_levels() {
+        let task = |id: &str, level: &str| TaskV2 {
+            id: id.to_string(),
+            description: "prove balance".to_string(),
+            status: "pending".to_string(),
+            timestamp: 5,
+            config: Some(TaskConfigV2 {
+                priority: "high".to_string(),
+                execution_delay: 0,
+                retry_attempts: 3,
+                disclosure_level: level.to_string(),
+                storage_type: "chain".to_string(),
+            }),
+        };
+        let v2 = ReferenceV2 {
+            id: "ref".to_string(),
+            tasks: vec![task("a", "anonymous"), task("r", "redacted"), task("f", "whatever")],
+            zk_proof: None,
+        };
+        let reference = Reference::from_bytes(Cow::Owned(encode(2, &v2)));
+        let levels: Vec<DisclosureLevel> = reference.tasks.iter()
+            .map(|task| task.config.as_ref().unwrap().disclosure_level)
+            .collect();
+        assert_eq!(levels, [DisclosureLevel::Anonymous, DisclosureLevel::Redacted, DisclosureLevel::Full]);
+        assert!(reference.artifacts.is_empty());
+        assert_eq!(reference.tasks[0].claim, None);
+        assert_eq!(Reference::from_bytes(reference.to_bytes()), reference);
+    }
+
+    #[test]
+    fn decodes_v3_reference_with_typed_statuses() {
+        let task = |id: &str, status: &str| TaskV3 {
+            id: id.to_string(),
+            description: String::new(),
+            status: status.to_string(),
+            timestamp: 5,
+            config: None,
+            claim: None,
+        };
+        let v3 = ReferenceV3 {
+            id: "ref".to_string(),
+            tasks: vec![task("p", "pending"), task("c", "completed")],
+            zk_proof: None,
+            artifacts: vec![],
+        };
+        let reference = Reference::from_bytes(Cow::Owned(encode(3, &v3)));
+        assert_eq!(reference.tasks[0].status, TaskStatus::Pending);
+        assert_eq!(reference.tasks[1].status, TaskStatus::Succeeded);
+        assert!(reference.tasks.iter().all(|task| task.attempts.is_empty()));
+        assert_eq!(Reference::from_bytes(reference.to_bytes()), reference);
+    }
+
+    // A task at MAX_TASK_SIZE that has used every attempt with the longest errors
+    fn exhausted_task(n: u8) -> Task {
+        let mut task = Task {
+            id: format!("{:0>36}", n),
+            description: String::new(),
+            status: TaskStatus::Pending,
+            timestamp: u64::MAX,
+            config: Some(TaskConfig {
+                priority: "medium".to_string(),
+                execution_delay: u64::MAX,
+                retry_attempts: tasks::MAX_RETRY_ATTEMPTS,
+                disclosure_level: DisclosureLevel::Full,
+                storage_type: "encrypted".to_string(),
+            }),
+            claim: Some(BalanceClaim {
+                owner: Principal::from_slice(&[0xff; 29]),
+                token_id: u64::MAX,
+                subaccount: Some(vec![0xff; 32]),
+                min_balance: u64::MAX,
+            }),
+            attempts: vec![],
+        };
+        while tasks::encoded_len(&task) < tasks::MAX_TASK_SIZE {
+            task.description.push('x');
+        }
+        if tasks::encoded_len(&task) > tasks::MAX_TASK_SIZE {
+            task.description.pop();
+        }
+
+        let runs = tasks::max_attempts(&task);
+        for run in 1..=runs {
+            tasks::transition(&mut task, TaskStatus::Running, u64::MAX).unwrap();
+            if run < runs {
+                tasks::retry(&mut task, "e".repeat(1000), u64::MAX).unwrap();
+            } else {
+                tasks::transition(&mut task, TaskStatus::Failed { reason: "f".repeat(1000) }, u64::MAX).unwrap();
+            }
+        }
+        task
+    }
+
+    // A full disclosure of `task_id` padded to MAX_ARTIFACT_SIZE
+    fn largest_artifact(task_id: &str) -> Artifact {
+        let statement = FullStatement {
+            holds: true,
+            merkle_root: vec![0xff; 32],
+            epoch: u64::MAX,
+            token_id: u64::MAX,
+            min_balance: u64::MAX,
+            owner: Principal::from_slice(&[0xff; 29]),
+            subaccount: Some(vec![0xff; 32]),
+            balance: u64::MAX,
+            issued_at: u64::MAX,
+        };
+        let mut signed = SignedStatement { statement, signature: vec![], key_name: "key_1".to_string() };
+        let artifact = |signed: &SignedStatement| Artifact {
+            task_id: task_id.to_string(),
+            created_at: u64::MAX,
+            disclosure: Disclosure::Full(signed.clone()),
+        };
+        while tasks::encoded_len(&artifact(&signed)) < tasks::MAX_ARTIFACT_SIZE {
+            signed.signature.push(0xff);
+        }
+        if tasks::encoded_len(&artifact(&signed)) > tasks::MAX_ARTIFACT_SIZE {
+            signed.signature.pop();
+        }
+        artifact(&signed)
+    }
+
+    #[test]
+    fn a_reference_filled_to_the_task_bounds_fits() {
+        let filled: Vec<Task> = (0..tasks::MAX_TASKS_PER_REFERENCE as u8).map(exhausted_task).collect();
+        assert!(filled.iter().all(|task| task.attempts.len() == tasks::max_attempts(task)));
+        let reference = Reference {
+            id: "4f9c2d1e-7a3b-4c5d-9e8f-0a1b2c3d4e5f".to_string(),
+            artifacts: filled.iter().map(|task| largest_artifact(&task.id)).collect(),
+            tasks: filled,
+            zk_proof: Some("z".repeat(128)),
+        };
+
+        let bytes = reference.to_bytes();
+        assert!(bytes.len() <= Reference::MAX_SIZE as usize, "{} bytes", bytes.len());
+        assert_eq!(Reference::from_bytes(bytes), reference);
+    }
+
+    #[test]
+    #[should_panic(expected = "unsupported TokenProofResult version 9")]
+    fn rejects_unknown_versions() {
+        let bytes = encode(9, &"not a record".to_string());
+        TokenProofResult::from_bytes(Cow::Owned(bytes));
+    }
+}
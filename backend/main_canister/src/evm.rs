@@ -0,0 +1,335 @@
+use crate::memory::{self, Memory};
+use candid::{CandidType, Deserialize, Nat, Reserved};
+use ic_cdk::api::call::call_with_payment128;
+use ic_cdk::export::Principal;
+use ic_stable_structures::StableCell;
+use num_bigint::BigUint;
+use serde::Serialize;
+use std::cell::RefCell;
+
+// Reads of EVM contract state through the EVM RPC canister's eth_call. Every call
+// goes to the chain's default providers, and a result is used once at least
+// `min_agreeing` of them returned it.
+// EVM state is public: any address can be read, and a read says nothing about who
+// controls the address.
+
+pub const EVM_RPC_CANISTER_ID: &str = "7hfb6-caaaa-aaaar-qadga-cai";
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct EvmConfig {
+    pub rpc_canister: Principal,
+    pub min_agreeing: u8,
+    // Attached to every eth_call; what the RPC canister does not use is refunded
+    pub cycles_per_call: u64,
+}
+
+impl Default for EvmConfig {
+    fn default() -> Self {
+        EvmConfig {
+            rpc_canister: Principal::from_text(EVM_RPC_CANISTER_ID).expect("valid canister id"),
+            min_agreeing: 2,
+            cycles_per_call: 10_000_000_000,
+        }
+    }
+}
+
+thread_local! {
+    static EVM_CONFIG: RefCell<StableCell<EvmConfig, Memory>> =
+        RefCell::new(StableCell::init(memory::get(memory::EVM_CONFIG), EvmConfig::default())
+            .expect("failed to initialize the EVM config"));
+}
+
+pub fn config() -> EvmConfig {
+    EVM_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+pub fn set_config(config: EvmConfig) -> Result<(), String> {
+    if config.min_agreeing == 0 {
+        return Err("min_agreeing must be at least 1".to_string());
+    }
+    EVM_CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .expect("failed to write the EVM config");
+    });
+    Ok(())
+}
+
+// Types of the EVM RPC canister interface, limited to what eth_call needs.
+// Chains are always read through the canister's default providers.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum RpcServices {
+    EthMainnet(Option<Vec<Reserved>>),
+    EthSepolia(Option<Vec<Reserved>>),
+    ArbitrumOne(Option<Vec<Reserved>>),
+    BaseMainnet(Option<Vec<Reserved>>),
+    OptimismMainnet(Option<Vec<Reserved>>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct RpcConfig {
+    #[serde(rename = "responseSizeEstimate")]
+    response_size_estimate: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum BlockTag {
+    Finalized,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransactionRequest {
+    to: Option<String>,
+    input: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CallArgs {
+    transaction: TransactionRequest,
+    block: Option<BlockTag>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum RpcError {
+    JsonRpcError { code: i64, message: String },
+    ProviderError(Reserved),
+    ValidationError(Reserved),
+    HttpOutcallError(Reserved),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum CallResult {
+    Ok(String),
+    Err(RpcError),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum MultiCallResult {
+    Consistent(CallResult),
+    Inconsistent(Vec<(Reserved, CallResult)>),
+}
+
+fn rpc_services(chain_id: u64) -> Result<RpcServices, String> {
+    match chain_id {
+        1 => Ok(RpcServices::EthMainnet(None)),
+        11155111 => Ok(RpcServices::EthSepolia(None)),
+        42161 => Ok(RpcServices::ArbitrumOne(None)),
+        8453 => Ok(RpcServices::BaseMainnet(None)),
+        10 => Ok(RpcServices::OptimismMainnet(None)),
+        other => Err(format!("EVM chain {} is not served by the EVM RPC canister", other)),
+    }
+}
+
+pub fn is_supported_chain(chain_id: u64) -> bool {
+    rpc_services(chain_id).is_ok()
+}
+
+// What a provider answered: the returned data, or None when the call reverted
+type Outcome = Option<String>;
+
+fn outcome(result: &CallResult) -> Result<Outcome, String> {
+    match result {
+        CallResult::Ok(data) => Ok(Some(data.to_ascii_lowercase())),
+        CallResult::Err(RpcError::JsonRpcError { message, .. }) if message.contains("revert") => Ok(None),
+        CallResult::Err(RpcError::JsonRpcError { code, message }) => Err(format!("JSON-RPC error {}: {}", code, message)),
+        CallResult::Err(other) => Err(format!("{:?}", other)),
+    }
+}
+
+// The outcome returned by at least `min_agreeing` providers
+fn agree(results: &[CallResult], min_agreeing: u8) -> Result<Outcome, String> {
+    let mut counts: Vec<(Outcome, usize)> = Vec::new();
+    let mut errors = Vec::new();
+    for result in results {
+        match outcome(result) {
+            Ok(outcome) => match counts.iter_mut().find(|(seen, _)| *seen == outcome) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((outcome, 1)),
+            },
+            Err(err) => errors.push(err),
+        }
+    }
+
+    let best = counts.into_iter().max_by_key(|(_, count)| *count);
+    match best {
+        Some((outcome, count)) if count >= min_agreeing as usize => Ok(outcome),
+        Some((_, count)) => Err(format!(
+            "only {} of {} providers agree, {} required{}",
+            count,
+            results.len(),
+            min_agreeing,
+            if errors.is_empty() { String::new() } else { format!("; errors: {}", errors.join(", ")) }
+        )),
+        None => Err(format!("every provider failed: {}", errors.join(", "))),
+    }
+}
+
+async fn eth_call(chain_id: u64, contract: &[u8; 20], input: Vec<u8>) -> Result<Outcome, String> {
+    let config = config();
+    let args = CallArgs {
+        transaction: TransactionRequest {
+            to: Some(format!("0x{}", hex::encode(contract))),
+            input: Some(format!("0x{}", hex::encode(input))),
+        },
+        block: Some(BlockTag::Finalized),
+    };
+    let rpc_config = RpcConfig { response_size_estimate: None };
+    let (result,): (MultiCallResult,) = call_with_payment128(
+        config.rpc_canister,
+        "eth_call",
+        (rpc_services(chain_id)?, Some(rpc_config), args),
+        config.cycles_per_call as u128,
+    )
+    .await
+    .map_err(|(_, msg)| format!("eth_call failed: {}", msg))?;
+
+    // A consistent result came back identical from every provider
+    let results = match result {
+        MultiCallResult::Consistent(result) => vec![result; config.min_agreeing as usize],
+        MultiCallResult::Inconsistent(results) => results.into_iter().map(|(_, result)| result).collect(),
+    };
+    agree(&results, config.min_agreeing)
+}
+
+async fn eth_call_returning(chain_id: u64, contract: &[u8; 20], input: Vec<u8>) -> Result<Vec<u8>, String> {
+    let data = eth_call(chain_id, contract, input).await?
+        .ok_or_else(|| "contract call reverted".to_string())?;
+    decode_hex(&data)
+}
+
+// ABI encoding: 4-byte selector, then one 32-byte word per argument
+const BALANCE_OF: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+const OWNER_OF: [u8; 4] = [0x63, 0x52, 0x21, 0x1e];
+const ERC1155_BALANCE_OF: [u8; 4] = [0x00, 0xfd, 0xd5, 0x8e];
+const SUPPORTS_INTERFACE: [u8; 4] = [0x01, 0xff, 0xc9, 0xa7];
+pub const ERC721_INTERFACE: [u8; 4] = [0x80, 0xac, 0x58, 0xcd];
+pub const ERC1155_INTERFACE: [u8; 4] = [0xd9, 0xb6, 0x7a, 0x26];
+
+fn decode_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    hex::decode(digits).map_err(|e| format!("invalid hex {}: {}", text, e))
+}
+
+pub fn parse_address(text: &str) -> Result<[u8; 20], String> {
+    decode_hex(text)?
+        .try_into()
+        .map_err(|_| format!("{} is not a 20-byte EVM address", text))
+}
+
+fn calldata(selector: [u8; 4], words: &[[u8; 32]]) -> Vec<u8> {
+    let mut data = selector.to_vec();
+    for word in words {
+        data.extend_from_slice(word);
+    }
+    data
+}
+
+fn address_word(address: &[u8; 20]) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(address);
+    word
+}
+
+fn uint_word(value: &Nat) -> Result<[u8; 32], String> {
+    let bytes = value.0.to_bytes_be();
+    if bytes.len() > 32 {
+        return Err(format!("{} does not fit in 256 bits", value));
+    }
+    let mut word = [0u8; 32];
+    word[32 - bytes.len()..].copy_from_slice(&bytes);
+    Ok(word)
+}
+
+fn first_word(data: &[u8]) -> Result<&[u8], String> {
+    data.get(..32).ok_or_else(|| "contract returned less than one word".to_string())
+}
+
+fn decode_uint(data: &[u8]) -> Result<Nat, String> {
+    Ok(Nat(BigUint::from_bytes_be(first_word(data)?)))
+}
+
+fn decode_address(data: &[u8]) -> Result<[u8; 20], String> {
+    let word = first_word(data)?;
+    if word[..12].iter().any(|byte| *byte != 0) {
+        return Err("contract returned a malformed address".to_string());
+    }
+    Ok(word[12..].try_into().unwrap())
+}
+
+pub async fn erc20_balance(chain_id: u64, contract: &[u8; 20], owner: &[u8; 20]) -> Result<Nat, String> {
+    let data = eth_call_returning(chain_id, contract, calldata(BALANCE_OF, &[address_word(owner)])).await?;
+    decode_uint(&data)
+}
+
+// ERC-721 balanceOf shares the ERC-20 selector and counts the tokens held
+pub async fn erc721_balance(chain_id: u64, contract: &[u8; 20], owner: &[u8; 20]) -> Result<Nat, String> {
+    erc20_balance(chain_id, contract, owner).await
+}
+
+// None when the token does not exist; ownerOf reverts for those
+pub async fn erc721_owner(chain_id: u64, contract: &[u8; 20], token_id: &Nat) -> Result<Option<[u8; 20]>, String> {
+    match eth_call(chain_id, contract, calldata(OWNER_OF, &[uint_word(token_id)?])).await? {
+        Some(data) => decode_address(&decode_hex(&data)?).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub async fn erc1155_balance(chain_id: u64, contract: &[u8; 20], owner: &[u8; 20], token_id: &Nat) -> Result<Nat, String> {
+    let input = calldata(ERC1155_BALANCE_OF, &[address_word(owner), uint_word(token_id)?]);
+    let data = eth_call_returning(chain_id, contract, input).await?;
+    decode_uint(&data)
+}
+
+// ERC-165; contracts without it revert or return nothing
+pub async fn supports_interface(chain_id: u64, contract: &[u8; 20], interface: [u8; 4]) -> Result<bool, String> {
+    let mut word = [0u8; 32];
+    word[..4].copy_from_slice(&interface);
+    match eth_call(chain_id, contract, calldata(SUPPORTS_INTERFACE, &[word])).await? {
+        Some(data) => Ok(decode_uint(&decode_hex(&data)?).map_or(false, |value| value == Nat::from(1u64))),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(data: &str) -> CallResult {
+        CallResult::Ok(data.to_string())
+    }
+
+    fn reverted() -> CallResult {
+        CallResult::Err(RpcError::JsonRpcError { code: 3, message: "execution reverted".to_string() })
+    }
+
+    #[test]
+    fn majority_of_providers_decides() {
+        assert_eq!(agree(&[ok("0x01"), ok("0x01"), ok("0x02")], 2), Ok(Some("0x01".to_string())));
+        assert_eq!(agree(&[reverted(), reverted(), ok("0x02")], 2), Ok(None));
+        assert!(agree(&[ok("0x01"), ok("0x02"), ok("0x03")], 2).is_err());
+        let failed = CallResult::Err(RpcError::HttpOutcallError(Reserved));
+        assert!(agree(&[ok("0x01"), failed.clone()], 2).is_err());
+        assert!(agree(&[failed.clone(), failed], 1).is_err());
+    }
+
+    #[test]
+    fn hex_case_does_not_split_the_vote() {
+        assert_eq!(agree(&[ok("0xAB"), ok("0xab")], 2), Ok(Some("0xab".to_string())));
+    }
+
+    #[test]
+    fn abi_words() {
+        let owner = parse_address("0x00000000000000000000000000000000000000ff").unwrap();
+        let data = calldata(ERC1155_BALANCE_OF, &[address_word(&owner), uint_word(&Nat::from(7u64)).unwrap()]);
+        assert_eq!(data.len(), 4 + 64);
+        assert_eq!(&data[..4], &ERC1155_BALANCE_OF);
+        assert_eq!(data[35], 0xff);
+        assert_eq!(data[67], 7);
+
+        assert_eq!(decode_address(&address_word(&owner)), Ok(owner));
+        assert!(decode_address(&[0xff; 32]).is_err());
+        assert_eq!(decode_uint(&uint_word(&Nat::from(u64::MAX)).unwrap()), Ok(Nat::from(u64::MAX)));
+        assert!(decode_uint(&[1; 31]).is_err());
+        assert!(parse_address("0x1234").is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use candid::{CandidType, Deserialize, Func, Int, Nat};
+use ic_cdk::export::Principal;
+
+// Client side of the ICRC-3 block log (icrc3_get_blocks) and the ICRC-1 block
+// schema: https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-3
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+// Blocks moved to an archive canister, fetched by calling `callback` with `args`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: Func,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LedgerAccount {
+    pub owner: Principal,
+    pub subaccount: Option<[u8; 32]>,
+}
+
+// The balance effect of one ICRC-1/ICRC-2 block
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    Mint { to: LedgerAccount, amount: u64 },
+    Burn { from: LedgerAccount, amount: u64, fee: u64 },
+    Transfer { from: LedgerAccount, to: LedgerAccount, amount: u64, fee: u64 },
+    // Approvals only move the fee
+    Approve { from: LedgerAccount, fee: u64 },
+}
+
+pub fn nat_to_u64(value: &Nat) -> Result<u64, String> {
+    u64::try_from(&value.0).map_err(|_| format!("{} does not fit in 64 bits", value))
+}
+
+fn field<'a>(map: &'a [(String, Value)], name: &str) -> Option<&'a Value> {
+    map.iter().find(|(key, _)| key == name).map(|(_, value)| value)
+}
+
+fn as_map<'a>(value: &'a Value, what: &str) -> Result<&'a [(String, Value)], String> {
+    match value {
+        Value::Map(map) => Ok(map),
+        _ => Err(format!("{} is not a map", what)),
+    }
+}
+
+fn as_u64(value: Option<&Value>, what: &str) -> Result<Option<u64>, String> {
+    match value {
+        None => Ok(None),
+        Some(Value::Nat(nat)) => nat_to_u64(nat).map(Some),
+        Some(_) => Err(format!("{} is not a nat", what)),
+    }
+}
+
+fn account(value: Option<&Value>, what: &str) -> Result<LedgerAccount, String> {
+    let parts = match value {
+        Some(Value::Array(parts)) => parts,
+        _ => return Err(format!("block has no {} account", what)),
+    };
+    match parts.as_slice() {
+        [Value::Blob(owner)] => Ok(LedgerAccount {
+            owner: Principal::try_from_slice(owner).map_err(|e| e.to_string())?,
+            subaccount: None,
+        }),
+        [Value::Blob(owner), Value::Blob(subaccount)] => Ok(LedgerAccount {
+            owner: Principal::try_from_slice(owner).map_err(|e| e.to_string())?,
+            subaccount: Some(subaccount.as_slice().try_into()
+                .map_err(|_| format!("{} subaccount is not 32 bytes", what))?),
+        }),
+        _ => Err(format!("malformed {} account", what)),
+    }
+}
+
+pub fn parse_block(block: &Value) -> Result<Operation, String> {
+    let block = as_map(block, "block")?;
+    let tx = as_map(field(block, "tx").ok_or("block has no tx")?, "tx")?;
+
+    // Blocks carry either a top-level btype or the older tx.op
+    let kind = match (field(block, "btype"), field(tx, "op")) {
+        (Some(Value::Text(btype)), _) => btype.as_str(),
+        (None, Some(Value::Text(op))) => op.as_str(),
+        _ => return Err("block has no type".to_string()),
+    };
+    let amount = || as_u64(field(tx, "amt"), "amt")?.ok_or_else(|| "block has no amount".to_string());
+    // The fee in tx was requested by the caller; the top-level one was charged by the ledger
+    let fee = as_u64(field(tx, "fee"), "fee")?
+        .or(as_u64(field(block, "fee"), "fee")?)
+        .unwrap_or(0);
+
+    match kind {
+        "1mint" | "mint" => Ok(Operation::Mint { to: account(field(tx, "to"), "to")?, amount: amount()? }),
+        "1burn" | "burn" => Ok(Operation::Burn { from: account(field(tx, "from"), "from")?, amount: amount()?, fee }),
+        "1xfer" | "2xfer" | "xfer" => Ok(Operation::Transfer {
+            from: account(field(tx, "from"), "from")?,
+            to: account(field(tx, "to"), "to")?,
+            amount: amount()?,
+            fee,
+        }),
+        "2approve" | "approve" => Ok(Operation::Approve { from: account(field(tx, "from"), "from")?, fee }),
+        other => Err(format!("unsupported block type {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(value: &str) -> Value {
+        Value::Text(value.to_string())
+    }
+
+    fn nat(value: u64) -> Value {
+        Value::Nat(Nat::from(value))
+    }
+
+    fn owner(n: u8) -> Value {
+        Value::Array(vec![Value::Blob(vec![n; 29])])
+    }
+
+    fn map(fields: Vec<(&str, Value)>) -> Value {
+        Value::Map(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    fn principal(n: u8) -> LedgerAccount {
+        LedgerAccount { owner: Principal::from_slice(&[n; 29]), subaccount: None }
+    }
+
+    #[test]
+    fn parses_btype_and_op_blocks() {
+        let mint = map(vec![
+            ("btype", text("1mint")),
+            ("tx", map(vec![("to", owner(1)), ("amt", nat(500))])),
+        ]);
+        assert_eq!(parse_block(&mint), Ok(Operation::Mint { to: principal(1), amount: 500 }));
+
+        // Effective fee at the top level, transaction type in tx.op
+        let transfer = map(vec![
+            ("fee", nat(10)),
+            ("tx", map(vec![("op", text("xfer")), ("from", owner(1)), ("to", owner(2)), ("amt", nat(100))])),
+        ]);
+        assert_eq!(
+            parse_block(&transfer),
+            Ok(Operation::Transfer { from: principal(1), to: principal(2), amount: 100, fee: 10 })
+        );
+    }
+
+    #[test]
+    fn reads_subaccounts() {
+        let mut subaccount = [0u8; 32];
+        subaccount[31] = 7;
+        let block = map(vec![
+            ("btype", text("2approve")),
+            ("tx", map(vec![
+                ("from", Value::Array(vec![Value::Blob(vec![3; 29]), Value::Blob(subaccount.to_vec())])),
+                ("fee", nat(1)),
+            ])),
+        ]);
+        let Ok(Operation::Approve { from, fee }) = parse_block(&block) else { panic!("not an approval") };
+        assert_eq!(from.subaccount, Some(subaccount));
+        assert_eq!(fee, 1);
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        assert!(parse_block(&text("block")).is_err());
+        assert!(parse_block(&map(vec![("btype", text("1mint")), ("tx", map(vec![("to", owner(1))]))])).is_err());
+        assert!(parse_block(&map(vec![("btype", text("9xyz")), ("tx", map(vec![]))])).is_err());
+        let huge = Value::Nat(Nat::from(u64::MAX) + Nat::from(1u64));
+        assert!(parse_block(&map(vec![
+            ("btype", text("1mint")),
+            ("tx", map(vec![("to", owner(1)), ("amt", huge)])),
+        ])).is_err());
+    }
+}
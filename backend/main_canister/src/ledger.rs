@@ -0,0 +1,163 @@
+use crate::icrc3;
+use crate::ownership::{TokenMetadata, TokenStandard};
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::api::call::call;
+use ic_cdk::export::Principal;
+use sha2::{Digest, Sha256};
+
+// Balance lookups on token ledgers through icrc1_balance_of, which the ICP ledger
+// implements as well. Its legacy account_balance takes a 32-byte account
+// identifier instead of an Account, so it is not used.
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<[u8; 32]>,
+}
+
+pub const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+// Token id of ICP balances in the balance tree
+pub const ICP_TOKEN_ID: u64 = 1;
+
+// A balance and what the circuit is told about its token
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenBalance {
+    pub token_id: u64,
+    pub balance: u64,
+    pub metadata: TokenMetadata,
+    pub symbol: String,
+}
+
+pub async fn get_icp_balance(account: Account) -> Result<u64, String> {
+    let ledger_id = Principal::from_text(ICP_LEDGER_CANISTER_ID)
+        .map_err(|e| format!("Invalid ledger ID: {}", e))?;
+    icrc1_balance_of(ledger_id, account).await
+}
+
+pub async fn icrc1_balance_of(ledger: Principal, account: Account) -> Result<u64, String> {
+    let (balance,): (Nat,) = call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(_, msg)| format!("Failed to get balance: {}", msg))?;
+    icrc3::nat_to_u64(&balance)
+}
+
+pub async fn icrc1_decimals(ledger: Principal) -> Result<u8, String> {
+    let (decimals,): (u8,) = call(ledger, "icrc1_decimals", ())
+        .await
+        .map_err(|(_, msg)| format!("Failed to get decimals: {}", msg))?;
+    Ok(decimals)
+}
+
+pub async fn icrc1_symbol(ledger: Principal) -> Result<String, String> {
+    let (symbol,): (String,) = call(ledger, "icrc1_symbol", ())
+        .await
+        .map_err(|(_, msg)| format!("Failed to get symbol: {}", msg))?;
+    Ok(symbol)
+}
+
+// Token id of an ICRC-1 ledger's balances in the live tree. ICP keeps ICP_TOKEN_ID
+// whichever interface it is read through; other ledgers get an id derived from
+// their principal, with the top bit set so it cannot clash with small fixed ids.
+pub fn ledger_token_id(ledger: Principal) -> u64 {
+    if ledger.to_text() == ICP_LEDGER_CANISTER_ID {
+        return ICP_TOKEN_ID;
+    }
+    let digest = Sha256::new()
+        .chain_update(b"icrc1-ledger")
+        .chain_update(ledger.as_slice())
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap()) | 1 << 63
+}
+
+fn add_balance(total: u64, balance: u64) -> Result<u64, String> {
+    total.checked_add(balance).ok_or_else(|| "Combined balance does not fit in 64 bits".to_string())
+}
+
+// The combined balance of `accounts` for a token of `standard`. For ICP `token` is
+// ignored; for ICRC-1/ICRC-2 it is the ledger canister id.
+pub async fn token_balance(standard: TokenStandard, token: &str, accounts: Vec<Account>) -> Result<TokenBalance, String> {
+    match standard {
+        TokenStandard::ICP => {
+            let mut balance = 0;
+            for account in accounts {
+                balance = add_balance(balance, get_icp_balance(account).await?)?;
+            }
+            Ok(TokenBalance {
+                token_id: ICP_TOKEN_ID,
+                balance,
+                metadata: TokenMetadata {
+                    canister_id: ICP_LEDGER_CANISTER_ID.to_string(),
+                    token_standard: TokenStandard::ICP,
+                    decimals: Some(8),  // ICP uses 8 decimal places
+                },
+                symbol: "ICP".to_string(),
+            })
+        }
+        TokenStandard::ICRC1 | TokenStandard::ICRC2 => {
+            let ledger = Principal::from_text(token)
+                .map_err(|e| format!("Invalid ledger canister id: {}", e))?;
+            let decimals = icrc1_decimals(ledger).await?;
+            let symbol = icrc1_symbol(ledger).await?;
+            let mut balance = 0;
+            for account in accounts {
+                balance = add_balance(balance, icrc1_balance_of(ledger, account).await?)?;
+            }
+            Ok(TokenBalance {
+                token_id: ledger_token_id(ledger),
+                balance,
+                metadata: TokenMetadata {
+                    canister_id: ledger.to_text(),
+                    token_standard: standard,
+                    decimals: Some(decimals),
+                },
+                symbol,
+            })
+        }
+        other => Err(format!("{:?} balances cannot be read from the IC", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{Decode, Encode};
+
+    // icrc1_balance_of's argument as the ledger's interface declares it
+    #[derive(CandidType, Deserialize, Debug, PartialEq)]
+    struct LedgerAccount {
+        owner: Principal,
+        subaccount: Option<Vec<u8>>,
+    }
+
+    // account_balance's argument, which an Account must not be mistaken for
+    #[derive(CandidType, Deserialize, Debug)]
+    struct AccountBalanceArgs {
+        account: Vec<u8>,
+    }
+
+    #[test]
+    fn balance_requests_carry_icrc1_accounts() {
+        let owner = Principal::from_slice(&[1; 29]);
+        for subaccount in [None, Some([3; 32])] {
+            let bytes = Encode!(&Account { owner, subaccount }).unwrap();
+            assert_eq!(
+                Decode!(&bytes, LedgerAccount).unwrap(),
+                LedgerAccount { owner, subaccount: subaccount.map(|subaccount| subaccount.to_vec()) }
+            );
+            assert!(Decode!(&bytes, AccountBalanceArgs).is_err());
+        }
+    }
+
+    #[test]
+    fn ledger_token_ids() {
+        let icp = Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap();
+        assert_eq!(ledger_token_id(icp), ICP_TOKEN_ID);
+
+        let ckbtc = Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap();
+        let cketh = Principal::from_text("ss2fx-dyaaa-aaaar-qacoq-cai").unwrap();
+        assert_eq!(ledger_token_id(ckbtc), ledger_token_id(ckbtc));
+        assert_ne!(ledger_token_id(ckbtc), ledger_token_id(cketh));
+        assert!(ledger_token_id(ckbtc) >= 1 << 63);
+    }
+}
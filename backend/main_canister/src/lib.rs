@@ -1,51 +1,99 @@
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Nat};
 use serde::{Serialize};
 use ic_cdk_macros::{query, update};
 use ic_cdk::api::call::call;
 use ic_cdk::api::time;
 use ic_cdk::export::Principal;
-use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable, memory_manager::VirtualMemory, DefaultMemoryImpl, Memory};
+use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable};
 use serde_json::json;
-use serde_json::to_vec;
 use uuid::Uuid;
-use sha2;
-use sha2::{Sha256, Digest};
 use hex;
-use ic_stable_structures::storable::{Bound, BoundedStorable};
-
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+use ic_cdk_macros::{init, post_upgrade};
+
+mod btc;
+mod delegation;
+mod disclosure;
+mod encoding;
+mod evm;
+mod icrc3;
+mod ledger;
+mod memory;
+mod merkle;
+mod migrations;
+mod nft;
+mod ownership;
+mod poseidon;
+mod references;
+mod roots;
+mod scheduler;
+mod snapshot;
+mod tasks;
+
+use btc::{BtcAttestation, BtcConfig};
+use delegation::Delegation;
+use disclosure::{Artifact, BalanceClaim, DisclosureConfig, DisclosureLevel};
+use evm::EvmConfig;
+use ledger::Account;
+use merkle::{AccountScope, BalanceTree, MerkleProof};
+use migrations::MigrationReport;
+use nft::{Chain, NftError};
+use ownership::{OwnershipProof, ProofError, TokenMetadata, TokenOwnershipInput, TokenStandard, VerificationError, VerificationOutcome};
+use roots::{RootRecord, RootWindow};
+use snapshot::{SnapshotState, SnapshotStatus};
+use tasks::{TaskAttempt, TaskStatus, TaskStatusKind};
+
+use memory::Memory;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
 struct TaskConfig {
+    // "high", "medium" or "low"; due tasks start highest priority first
     priority: String,
+    // Seconds from execute_tasks until the task starts
     execution_delay: u64,
+    // Retries after a failed first run, each after a backoff; at most
+    // tasks::MAX_RETRY_ATTEMPTS
     retry_attempts: u8,
-    disclosure_level: String,
+    disclosure_level: DisclosureLevel,
     storage_type: String,
 }
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
 struct Task {
     id: String,
     description: String,
-    status: String, // pending, completed
+    // Changed only through tasks::transition
+    status: TaskStatus,
     timestamp: u64,
     config: Option<TaskConfig>,
+    // What the task proves; tasks without a claim produce no artifact
+    claim: Option<BalanceClaim>,
+    // One entry per time the task ran, oldest first
+    attempts: Vec<TaskAttempt>,
 }
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+// Stable encoding in encoding.rs
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
 struct Reference {
     id: String,
     tasks: Vec<Task>,
     zk_proof: Option<String>,
+    artifacts: Vec<Artifact>,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 struct WalletVerificationRequest {
     wallet_address: String,
+    // Collection canister on the IC, or contract address on an EVM chain
     nft_contract_address: Option<String>,
+    // "icp", or an EVM chain: "eth", "sepolia" or an EIP-155 chain id
     chain_id: String,
+    // A single token to check; any token of the collection when absent (ICRC-7 and
+    // ERC-721 only)
+    token_id: Option<String>,
 }
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+// Stable encoding in encoding.rs
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
 struct VerificationResult {
     is_verified: bool,
     proof_id: String,
@@ -53,213 +101,113 @@ struct VerificationResult {
     anonymous_reference: String,
 }
 
-impl Storable for VerificationResult {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(self.proof_id.as_bytes());
-        bytes.extend_from_slice(&[self.is_valid as u8]);
-        bytes.extend_from_slice(&(self.timestamp as u64).to_le_bytes());
-        std::borrow::Cow::Owned(bytes)
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        let bytes = bytes.as_ref();
-        let proof_id = String::from_utf8_lossy(&bytes[0..bytes.len().min(32)]).to_string();
-        let is_valid = if bytes.len() > 32 { bytes[32] != 0 } else { false };
-        let timestamp = if bytes.len() >= 41 {
-            u64::from_le_bytes(bytes[33..41].try_into().unwrap_or_default()) as u128
-        } else {
-            0
-        };
-        
-        Self {
-            proof_id,
-            is_valid,
-            timestamp,
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+struct TokenProofRequest {
+    token_id: String,
+    min_balance: u64,
+    // The caller when absent; another principal only if it delegated to the caller
+    wallet_address: Option<String>,
+    // ICP when absent; for ICRC1/ICRC2 `token_id` is the ledger canister id
+    token_standard: Option<TokenStandard>,
+    // Prove the balance of one subaccount, or the sum over several; the default
+    // account when both are absent
+    subaccount: Option<Vec<u8>>,
+    aggregate_subaccounts: Option<Vec<Vec<u8>>>,
+}
+
+// Most subaccounts one aggregate proof reads
+const MAX_AGGREGATE_SUBACCOUNTS: usize = 16;
+
+fn parse_subaccount(bytes: &[u8]) -> Result<[u8; 32], String> {
+    bytes.try_into().map_err(|_| "Subaccounts must be 32 bytes".to_string())
+}
+
+impl TokenProofRequest {
+    // The ledger accounts to read and the leaf scope their balance is recorded under
+    fn accounts(&self, owner: Principal) -> Result<(Vec<Account>, AccountScope), String> {
+        match (&self.subaccount, &self.aggregate_subaccounts) {
+            (Some(_), Some(_)) => Err("Set either subaccount or aggregate_subaccounts, not both".to_string()),
+            (None, Some(subaccounts)) => {
+                if subaccounts.is_empty() || subaccounts.len() > MAX_AGGREGATE_SUBACCOUNTS {
+                    return Err(format!("Aggregate proofs take 1 to {} subaccounts", MAX_AGGREGATE_SUBACCOUNTS));
+                }
+                let mut parsed = subaccounts.iter()
+                    .map(|bytes| parse_subaccount(bytes))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let scope = AccountScope::aggregate(&parsed);
+                // Reading a subaccount twice would count its balance twice
+                parsed.sort();
+                parsed.dedup();
+                let accounts = parsed.into_iter()
+                    .map(|subaccount| Account { owner, subaccount: Some(subaccount) })
+                    .collect();
+                Ok((accounts, scope))
+            }
+            (subaccount, None) => {
+                let subaccount = subaccount.as_deref().map(parse_subaccount).transpose()?;
+                Ok((vec![Account { owner, subaccount }], AccountScope::of(subaccount)))
+            }
         }
     }
 }
 
-impl BoundedStorable for VerificationResult {
-    const MAX_SIZE: u32 = 256; // Set an appropriate maximum size
-    const IS_FIXED_SIZE: bool = false;
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+struct BtcAttestationRequest {
+    // P2WPKH or P2TR address on the configured network
+    address: String,
+    // BIP-322 simple signature of get_btc_attestation_message(address)
+    signature: String,
+    min_satoshis: u64,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-struct TokenProofRequest {
-    token_id: String,
-    min_balance: u64,
+struct EvmBalanceRequest {
+    // EIP-155 chain id
+    chain_id: u64,
+    contract_address: String,
     wallet_address: String,
+    // ERC20, ERC721 or ERC1155
+    token_standard: TokenStandard,
+    // Required for ERC1155; for ERC721 asks whether that one token is held
+    token_id: Option<Nat>,
 }
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+// Stable encoding in encoding.rs
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
 struct TokenProofResult {
     proof_id: String,
     token_id: String,
+    token_symbol: Option<String>,
     merkle_root: String,
     proof_data: Vec<u8>,
     anonymous_reference: String,
-    timestamp: u128,
+    timestamp: u64,
     is_valid: bool,
 }
 
-impl Storable for TokenProofResult {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(self.proof_id.as_bytes());
-        bytes.extend_from_slice(self.token_id.as_bytes());
-        bytes.extend_from_slice(self.merkle_root.as_bytes());
-        
-        // Store proof data length and content
-        let data_len = (self.proof_data.len() as u32).to_le_bytes();
-        bytes.extend_from_slice(&data_len);
-        bytes.extend_from_slice(&self.proof_data);
-        
-        bytes.extend_from_slice(self.anonymous_reference.as_bytes());
-        
-        // Store timestamp and valid flag
-        let timestamp_bytes = (self.timestamp as u64).to_le_bytes();
-        bytes.extend_from_slice(&timestamp_bytes);
-        bytes.push(self.is_valid as u8);
-        
-        std::borrow::Cow::Owned(bytes)
-    }
+// Limits on the caller- and ledger-supplied fields of a TokenProofResult, so a
+// stored result stays well inside its bound. Token ids longer than this are
+// rejected; longer symbols are cut.
+const MAX_TOKEN_ID_LEN: usize = 128;
+const MAX_TOKEN_SYMBOL_LEN: usize = 32;
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        let bytes = bytes.as_ref();
-        let mut offset = 0;
-        
-        // Read proof_id (first 32 bytes or less)
-        let proof_id_len = bytes.len().min(32);
-        let proof_id = String::from_utf8_lossy(&bytes[0..proof_id_len]).to_string();
-        offset += proof_id_len;
-        
-        // Read token_id (next 32 bytes or less)
-        let token_id_len = bytes.len().saturating_sub(offset).min(32);
-        let token_id = if token_id_len > 0 {
-            String::from_utf8_lossy(&bytes[offset..offset+token_id_len]).to_string()
-        } else {
-            String::new()
-        };
-        offset += token_id_len;
-        
-        // Read merkle_root (next 32 bytes or less)
-        let merkle_root_len = bytes.len().saturating_sub(offset).min(32);
-        let merkle_root = if merkle_root_len > 0 {
-            String::from_utf8_lossy(&bytes[offset..offset+merkle_root_len]).to_string()
-        } else {
-            String::new()
-        };
-        offset += merkle_root_len;
-        
-        // Read proof data length (4 bytes) and content
-        let mut proof_data = Vec::new();
-        if bytes.len() >= offset + 4 {
-            let data_len_bytes = &bytes[offset..offset+4];
-            let data_len = u32::from_le_bytes([
-                data_len_bytes[0], 
-                data_len_bytes[1], 
-                data_len_bytes[2], 
-                data_len_bytes[3]
-            ]) as usize;
-            offset += 4;
-            
-            if bytes.len() >= offset + data_len {
-                proof_data = bytes[offset..offset+data_len].to_vec();
-                offset += data_len;
-            }
-        }
-        
-        // Read anonymous_reference (next 32 bytes or less)
-        let anon_ref_len = bytes.len().saturating_sub(offset).min(32);
-        let anonymous_reference = if anon_ref_len > 0 {
-            String::from_utf8_lossy(&bytes[offset..offset+anon_ref_len]).to_string()
-        } else {
-            String::new()
-        };
-        offset += anon_ref_len;
-        
-        // Read timestamp (8 bytes) and valid flag (1 byte)
-        let timestamp = if bytes.len() >= offset + 8 {
-            let timestamp_bytes = &bytes[offset..offset+8];
-            u64::from_le_bytes([
-                timestamp_bytes[0], 
-                timestamp_bytes[1], 
-                timestamp_bytes[2], 
-                timestamp_bytes[3],
-                timestamp_bytes[4], 
-                timestamp_bytes[5], 
-                timestamp_bytes[6], 
-                timestamp_bytes[7]
-            ]) as u128
-        } else {
-            0
-        };
-        offset += 8;
-        
-        let is_valid = bytes.len() > offset && bytes[offset] != 0;
-        
-        Self {
-            proof_id,
-            token_id,
-            merkle_root,
-            proof_data,
-            anonymous_reference,
-            timestamp,
-            is_valid,
-        }
-    }
-}
-
-impl BoundedStorable for TokenProofResult {
-    const MAX_SIZE: u32 = 1024; // Set an appropriate maximum size
-    const IS_FIXED_SIZE: bool = false;
-}
-
-// Add ICP ledger interface
-
-#[derive(CandidType, Deserialize)]
-struct Account {
-    owner: Principal,
-    subaccount: Option<[u8; 32]>,
-}
-
-#[derive(CandidType, Deserialize)]
-struct ICPBalance {
-    e8s: u64,  // ICP balance in e8s (10^-8 ICP)
-}
-
-const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
-
-async fn get_icp_balance(account: Account) -> Result<u64, String> {
-    let ledger_id = Principal::from_text(ICP_LEDGER_CANISTER_ID)
-        .map_err(|e| format!("Invalid ledger ID: {}", e))?;
-    
-    let balance_result: Result<(ICPBalance,), _> = call(
-        ledger_id,
-        "account_balance",
-        (account,)
-    ).await;
-    
-    match balance_result {
-        Ok((balance,)) => Ok(balance.e8s),
-        Err((_, msg)) => Err(format!("Failed to get balance: {}", msg))
+fn check_token_id(token_id: &str) -> Result<(), String> {
+    if token_id.is_empty() || token_id.len() > MAX_TOKEN_ID_LEN {
+        return Err(format!("Token ids must be 1 to {} bytes", MAX_TOKEN_ID_LEN));
     }
+    Ok(())
 }
 
-impl Storable for Reference {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        std::borrow::Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
+// `symbol` cut to MAX_TOKEN_SYMBOL_LEN bytes, on a character boundary
+fn bounded_symbol(mut symbol: String) -> String {
+    if symbol.len() > MAX_TOKEN_SYMBOL_LEN {
+        let mut end = MAX_TOKEN_SYMBOL_LEN;
+        while !symbol.is_char_boundary(end) {
+            end -= 1;
+        }
+        symbol.truncate(end);
     }
-}
-
-impl BoundedStorable for Reference {
-    const MAX_SIZE: u32 = 8192; // Increased to 8KB to handle more tasks
-    const IS_FIXED_SIZE: bool = false;
+    symbol
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -280,184 +228,58 @@ impl BoundedStorable for StorableString {
     const IS_FIXED_SIZE: bool = false;
 }
 
+// Stable maps; see memory.rs for the MemoryId of each one
 thread_local! {
-    static REFERENCES: StableBTreeMap<StorableString, Reference, VirtualMemory<DefaultMemoryImpl>> = 
-        StableBTreeMap::new(VirtualMemory::new(DefaultMemoryImpl::default()));
+    static REFERENCES: std::cell::RefCell<StableBTreeMap<StorableString, Reference, Memory>> =
+        std::cell::RefCell::new(StableBTreeMap::init(memory::get(memory::REFERENCES)));
 }
 
 // Storage for verification results
 thread_local! {
-    static VERIFICATION_RESULTS: std::cell::RefCell<StableBTreeMap<StorableString, VerificationResult, VirtualMemory<DefaultMemoryImpl>>> = 
-        std::cell::RefCell::new(StableBTreeMap::init(
-            ic_stable_structures::memory_manager::MemoryManager::init(DefaultMemoryImpl::default())
-                .get(Memory::from(2))
-        ));
-}
-
-// Storage for merkle root
-thread_local! {
-    static MERKLE_ROOT: std::cell::RefCell<String> = std::cell::RefCell::new(String::new());
+    static VERIFICATION_RESULTS: std::cell::RefCell<StableBTreeMap<StorableString, VerificationResult, Memory>> =
+        std::cell::RefCell::new(StableBTreeMap::init(memory::get(memory::VERIFICATION_RESULTS)));
 }
 
 // Storage for token proofs
 thread_local! {
-    static TOKEN_PROOFS: std::cell::RefCell<StableBTreeMap<StorableString, TokenProofResult, VirtualMemory<DefaultMemoryImpl>>> = 
-        std::cell::RefCell::new(StableBTreeMap::init(
-            ic_stable_structures::memory_manager::MemoryManager::init(DefaultMemoryImpl::default())
-                .get(Memory::from(3))
-        ));
+    static TOKEN_PROOFS: std::cell::RefCell<StableBTreeMap<StorableString, TokenProofResult, Memory>> =
+        std::cell::RefCell::new(StableBTreeMap::init(memory::get(memory::TOKEN_PROOFS)));
 }
 
-// Merkle tree node structure - essential for building the tree hierarchy
-#[derive(Clone, Debug)]
-struct MerkleNode {
-    hash: Vec<u8>,
-    left: Option<Box<MerkleNode>>,
-    right: Option<Box<MerkleNode>>,
+// Thread-local storage for the Merkle tree - maintains global tree state
+thread_local! {
+    static MERKLE_TREE: std::cell::RefCell<BalanceTree> = std::cell::RefCell::new(BalanceTree::init());
 }
 
-// Balance leaf structure - represents individual token balance entries
-#[derive(Clone, Debug)]
-struct BalanceLeaf {
-    principal: Principal,
-    token_id: u64,
-    balance: u64,
+#[init]
+fn init() {
+    memory::set_schema_version(memory::CURRENT_SCHEMA_VERSION);
 }
 
-impl BalanceLeaf {
-    // Hash function for leaf nodes - ensures data integrity
-    fn hash(&self) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(self.principal.as_slice());
-        hasher.update(&self.token_id.to_be_bytes());
-        hasher.update(&self.balance.to_be_bytes());
-        hasher.finalize().to_vec()
+// A failed migration is logged and kept in the report rather than trapping, which
+// would roll back the upgrade and leave the old wasm running against the same data
+#[post_upgrade]
+fn post_upgrade() {
+    let report = migrations::run();
+    if let Some(failure) = &report.failure {
+        ic_cdk::println!("Schema migration {} failed: {}", failure.migration, failure.reason);
     }
+    // Timers do not survive upgrades; the task queue does
+    scheduler::rearm(time());
 }
 
-// Merkle tree implementation - manages the entire tree structure
-struct MerkleTree {
-    root: Option<MerkleNode>,
-    leaves: Vec<BalanceLeaf>,
+#[query]
+fn get_migration_report() -> Option<MigrationReport> {
+    migrations::last_report()
 }
 
-impl MerkleTree {
-    // Create new empty tree - initialization point for the tree
-    fn new() -> Self {
-        MerkleTree {
-            root: None,
-            leaves: Vec::new(),
-        }
-    }
-    
-    // Add a new balance entry - updates tree with new token balance
-    fn add_balance(&mut self, principal: Principal, token_id: u64, balance: u64) {
-        let leaf = BalanceLeaf {
-            principal,
-            token_id,
-            balance,
-        };
-        self.leaves.push(leaf);
-        self.rebuild_tree();
-    }
-    
-    // Update existing balance - crucial for maintaining current state
-    fn update_balance(&mut self, principal: Principal, token_id: u64, new_balance: u64) -> bool {
-        if let Some(leaf) = self.leaves.iter_mut().find(|l| l.principal == principal && l.token_id == token_id) {
-            leaf.balance = new_balance;
-            self.rebuild_tree();
-            true
-        } else {
-            false
-        }
-    }
-    
-    // Rebuild entire tree - ensures tree consistency after updates
-    fn rebuild_tree(&mut self) {
-        if self.leaves.is_empty() {
-            self.root = None;
-            return;
-        }
-        
-        let mut current_level: Vec<MerkleNode> = self.leaves.iter()
-            .map(|leaf| MerkleNode {
-                hash: leaf.hash(),
-                left: None,
-                right: None,
-            })
-            .collect();
-        
-        while current_level.len() > 1 {
-            let mut next_level = Vec::new();
-            for chunk in current_level.chunks(2) {
-                match chunk {
-                    [left] => next_level.push(left.clone()),
-                    [left, right] => {
-                        let mut hasher = Sha256::new();
-                        hasher.update(&left.hash);
-                        hasher.update(&right.hash);
-                        next_level.push(MerkleNode {
-                            hash: hasher.finalize().to_vec(),
-                            left: Some(Box::new(left.clone())),
-                            right: Some(Box::new(right.clone())),
-                        });
-                    }
-                    _ => unreachable!(),
-                }
-            }
-            current_level = next_level;
-        }
-        
-        self.root = Some(current_level.remove(0));
-    }
-    
-    // Generate proof path - essential for ZK proof verification
-    fn generate_proof(&self, principal: Principal, token_id: u64) -> Option<(Vec<Vec<u8>>, Vec<bool>)> {
-        let target_leaf = self.leaves.iter()
-            .position(|l| l.principal == principal && l.token_id == token_id)?;
-        
-        let mut proof = Vec::new();
-        let mut path_indices = Vec::new();
-        let mut current_pos = target_leaf;
-        let mut current_level: Vec<_> = self.leaves.iter().map(|l| l.hash()).collect();
-        
-        while current_level.len() > 1 {
-            let sibling_pos = if current_pos % 2 == 0 {
-                current_pos + 1
-            } else {
-                current_pos - 1
-            };
-            
-            if sibling_pos < current_level.len() {
-                proof.push(current_level[sibling_pos].clone());
-                path_indices.push(current_pos % 2 == 0);
-            }
-            
-            current_pos /= 2;
-            current_level = current_level.chunks(2)
-                .map(|chunk| {
-                    let mut hasher = Sha256::new();
-                    hasher.update(&chunk[0]);
-                    if chunk.len() > 1 {
-                        hasher.update(&chunk[1]);
-                    }
-                    hasher.finalize().to_vec()
-                })
-                .collect();
-        }
-        
-        Some((proof, path_indices))
-    }
-    
-    // Get current root hash - needed for proof verification
-    fn root_hash(&self) -> Option<Vec<u8>> {
-        self.root.as_ref().map(|node| node.hash.clone())
+// Retry pending migrations after fixing the cause of a failure
+#[update]
+fn run_migrations() -> Result<MigrationReport, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can run migrations".to_string());
     }
-}
-
-// Thread-local storage for the Merkle tree - maintains global tree state
-thread_local! {
-    static MERKLE_TREE: std::cell::RefCell<MerkleTree> = std::cell::RefCell::new(MerkleTree::new());
+    Ok(migrations::run())
 }
 
 #[update]
@@ -467,29 +289,47 @@ fn generate_reference() -> String {
         id: id.clone(),
         tasks: vec![],
         zk_proof: None,
+        artifacts: vec![],
     };
     
     REFERENCES.with(|store| {
-        store.insert(StorableString(id.clone()), new_reference);
+        store.borrow_mut().insert(StorableString(id.clone()), new_reference);
     });
     id
 }
 
+// A claim about another principal's balance needs its delegation; see delegation.rs.
+// None as well when the task or the reference would exceed the bounds in tasks.rs.
 #[update]
-fn assign_task(reference_id: String, description: String, config: Option<TaskConfig>) -> Option<String> {
+fn assign_task(reference_id: String, description: String, config: Option<TaskConfig>, claim: Option<BalanceClaim>) -> Option<String> {
+    if let Some(claim) = &claim {
+        delegation::authorize(ic_cdk::caller(), claim.owner, time()).ok()?;
+    }
+    if config.as_ref().map_or(false, |config| config.retry_attempts > tasks::MAX_RETRY_ATTEMPTS) {
+        return None;
+    }
     let task_id = Uuid::new_v4().to_string();
     let task = Task {
         id: task_id.clone(),
         description,
-        status: "pending".to_string(),
+        status: TaskStatus::Pending,
         timestamp: time(),
         config,
+        claim,
+        attempts: vec![],
     };
+    if tasks::encoded_len(&task) > tasks::MAX_TASK_SIZE {
+        return None;
+    }
     
     REFERENCES.with(|store| {
+        let mut store = store.borrow_mut();
         let reference_option = store.get(&StorableString(reference_id.clone()));
         if let Some(reference) = reference_option {
             let mut reference = reference.clone();
+            if reference.tasks.len() >= tasks::MAX_TASKS_PER_REFERENCE {
+                return None;
+            }
             reference.tasks.push(task.clone());
             store.insert(StorableString(reference_id.clone()), reference);
             Some(task_id)
@@ -502,112 +342,165 @@ fn assign_task(reference_id: String, description: String, config: Option<TaskCon
 #[query]
 fn get_tasks(reference_id: String) -> Option<Vec<Task>> {
     REFERENCES.with(|store| {
-        store.get(&StorableString(reference_id)).map(|r| r.tasks.clone())
+        store.borrow().get(&StorableString(reference_id)).map(|r| r.tasks.clone())
     })
 }
 
-async fn execute_single_task(task: &mut Task) {
-    if let Some(config) = &task.config {
-        // Handle task based on disclosure level
-        match config.disclosure_level.as_str() {
-            "anonymous" => generate_anonymous_proof(task),
-            "redacted" => generate_redacted_proof(task),
-            _ => generate_full_proof(task),
+// Run a scheduled task once, writing each status change back to its reference.
+// A failed run is queued again after a backoff until the task has used its
+// retry_attempts. Tasks cancelled or deleted since they were queued are skipped.
+async fn execute_single_task(reference_id: String, task_id: String) {
+    let task = match references::update_task(&reference_id, &task_id, |task| tasks::transition(task, TaskStatus::Running, time())) {
+        Ok(task) => task,
+        Err(e) => {
+            ic_cdk::println!("Skipping task {}: {}", task_id, e);
+            return;
         }
-
-        // Store proof based on storage type
-        match config.storage_type.as_str() {
-            "chain" => store_proof_on_chain(task).await,
-            "ipfs" => store_proof_on_ipfs(task).await,
-            "encrypted" => store_encrypted_proof(task).await,
-            _ => store_proof_on_chain(task).await,
+    };
+    let outcome = run_task(&reference_id, &task).await;
+
+    let now = time();
+    let recorded = references::update_task(&reference_id, &task_id, |task| tasks::finish(task, outcome, now));
+    match recorded {
+        Ok(task) if task.status == TaskStatus::Scheduled => {
+            let retry = task.attempts.len() as u32;
+            let seed = [task.id.as_bytes(), &now.to_be_bytes()].concat();
+            let due_at = now.saturating_add(scheduler::backoff(retry, &seed));
+            scheduler::enqueue(&reference_id, &task_id, due_at, scheduler::task_priority(&task));
+            scheduler::rearm(now);
         }
+        Ok(_) => {}
+        Err(e) => ic_cdk::println!("Task {} finished but could not be recorded: {}", task_id, e),
+    }
+}
 
-        // Implement retry logic
-        let mut attempts = 0;
-        while attempts < config.retry_attempts as u64 {
-            match execute_task_operation(task).await {
-                Ok(_) => break,
-                Err(_) => {
-                    attempts += 1;
-                    if attempts == config.retry_attempts as u64 {
-                        break;
-                    }
-                    // Wait before retry
-                    let current_time = time();
-                    while time() < current_time + 1_000_000_000 { } // 1 second delay
-                }
-            }
-        }
-    } else {
+// The work of one task; the error of its last try when every try failed
+async fn run_task(reference_id: &str, task: &Task) -> Result<(), String> {
+    let Some(config) = &task.config else {
         // Execute with default settings if no config
-        let _ = execute_task_operation(task).await;
+        return execute_task_operation(task).await.map_err(str::to_string);
+    };
+
+    // Disclose the claim at the configured level
+    if let Some(claim) = &task.claim {
+        let artifact = generate_artifact(&task.id, config.disclosure_level, claim).await?;
+        references::store_artifact(reference_id, artifact)?;
     }
-    
-    task.status = "completed".to_string();
-}
 
-fn generate_anonymous_proof(task: &Task) {
-    // Implementation for anonymous proof generation
-}
+    // Store proof based on storage type
+    match config.storage_type.as_str() {
+        "chain" => store_proof_on_chain(task).await,
+        "ipfs" => store_proof_on_ipfs(task).await,
+        "encrypted" => store_encrypted_proof(task).await,
+        _ => store_proof_on_chain(task).await,
+    }
 
-fn generate_redacted_proof(task: &Task) {
-    // Implementation for redacted proof generation
+    // Retries are scheduled by execute_single_task
+    execute_task_operation(task).await.map_err(str::to_string)
 }
 
-fn generate_full_proof(task: &Task) {
-    // Implementation for full proof generation
+// Evaluate `claim` against the balance tree and cut the artifact for `level`
+async fn generate_artifact(task_id: &str, level: DisclosureLevel, claim: &BalanceClaim) -> Result<Artifact, String> {
+    let now = time();
+    let statement = MERKLE_TREE.with(|tree| disclosure::evaluate(&tree.borrow(), claim, now))?;
+    Ok(Artifact {
+        task_id: task_id.to_string(),
+        created_at: now,
+        disclosure: disclosure::disclose(level, statement).await?,
+    })
 }
 
-async fn store_proof_on_chain(task: &Task) {
+async fn store_proof_on_chain(_task: &Task) {
     // Implementation for on-chain storage
 }
 
-async fn store_proof_on_ipfs(task: &Task) {
+async fn store_proof_on_ipfs(_task: &Task) {
     // Implementation for IPFS storage
 }
 
-async fn store_encrypted_proof(task: &Task) {
+async fn store_encrypted_proof(_task: &Task) {
     // Implementation for encrypted storage
 }
 
-async fn execute_task_operation(task: &Task) -> Result<(), &'static str> {
+async fn execute_task_operation(_task: &Task) -> Result<(), &'static str> {
     // Here you would implement the actual task execution logic
     // For now, we just simulate success
     Ok(())
 }
 
 #[update]
-async fn execute_tasks(reference_id: String) -> Option<Vec<Task>> {
+fn execute_tasks(reference_id: String) -> Option<Vec<Task>> {
+    let now = time();
+    let key = StorableString(reference_id.clone());
+    let mut reference = REFERENCES.with(|store| store.borrow().get(&key))?;
+    reference.zk_proof = Some(generate_zk_proof(&reference.id));
+
+    // Pending tasks are queued to start execution_delay seconds from now
+    let mut queued = Vec::new();
+    for task in reference.tasks.iter_mut().filter(|task| task.status == TaskStatus::Pending) {
+        tasks::transition(task, TaskStatus::Scheduled, now).expect("pending tasks can be scheduled");
+        let delay = task.config.as_ref().map_or(0, |config| config.execution_delay);
+        let due_at = now.saturating_add(delay.saturating_mul(1_000_000_000));
+        queued.push((task.id.clone(), due_at, scheduler::task_priority(task)));
+    }
+    REFERENCES.with(|store| store.borrow_mut().insert(key, reference.clone()));
+
+    for (task_id, due_at, priority) in queued {
+        scheduler::enqueue(&reference_id, &task_id, due_at, priority);
+    }
+    scheduler::rearm(now);
+    Some(reference.tasks)
+}
+
+// Tasks of the reference whose status is of the given kind
+#[query]
+fn get_tasks_by_status(reference_id: String, status: TaskStatusKind) -> Option<Vec<Task>> {
     REFERENCES.with(|store| {
-        let reference_option = store.get(&StorableString(reference_id.clone()));
-        if let Some(reference) = reference_option {
-            let mut reference = reference.clone();
-            
-            // Execute each task
-            for task in &mut reference.tasks {
-                if task.status == "pending" {
-                    // Note: In a real implementation, you would want to handle
-                    // task execution asynchronously and possibly in parallel
-                    ic_cdk::spawn(execute_single_task(task));
-                }
-            }
+        store.borrow().get(&StorableString(reference_id)).map(|r| {
+            r.tasks.into_iter().filter(|task| task.status.kind() == status).collect()
+        })
+    })
+}
 
-            let zk_proof = generate_zk_proof(&reference.id);
-            reference.zk_proof = Some(zk_proof.clone());
-            execute_on_eth_btc(&reference.id);
-            store.insert(StorableString(reference_id.clone()), reference);
-            Some(reference.tasks.clone())
-        } else {
-            None
-        }
+// Stop a task that has not started running
+#[update]
+fn cancel_task(reference_id: String, task_id: String) -> Result<Task, String> {
+    // A cancelled task stays queued and is skipped when it comes due
+    references::update_task(&reference_id, &task_id, |task| tasks::transition(task, TaskStatus::Cancelled, time()))
+}
+
+// Disclosure artifacts produced by the reference's tasks
+#[query]
+fn get_artifacts(reference_id: String) -> Option<Vec<Artifact>> {
+    REFERENCES.with(|store| {
+        store.borrow().get(&StorableString(reference_id)).map(|r| r.artifacts)
     })
 }
 
+// Key that verifies the signatures of full disclosures
+#[update]
+async fn get_disclosure_public_key() -> Result<Vec<u8>, String> {
+    disclosure::public_key().await
+}
+
+#[query]
+fn get_disclosure_config() -> DisclosureConfig {
+    disclosure::config()
+}
+
+#[update]
+fn set_disclosure_config(config: DisclosureConfig) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can change the disclosure config".to_string());
+    }
+    disclosure::set_config(config);
+    Ok(())
+}
+
 #[update]
 fn delete_reference(reference_id: String) -> bool {
     REFERENCES.with(|store| {
-        store.remove(&StorableString(reference_id)).is_some()
+        store.borrow_mut().remove(&StorableString(reference_id)).is_some()
     })
 }
 
@@ -620,51 +513,28 @@ fn generate_zk_proof(reference_id: &str) -> String {
 }
 
 #[update]
-async fn execute_on_eth_btc(reference_id: &str) {
-    let chain_fusion_principal = Principal::management_canister();
-    
-    let eth_tx = json!({
-        "chain": "Ethereum",
-        "action": "execute_task",
-        "reference_id": reference_id,
-        "timestamp": time(),
-    });
-
-    let btc_tx = json!({
-        "chain": "Bitcoin",
-        "action": "execute_task",
-        "reference_id": reference_id,
-        "timestamp": time(),
-    });
-
-    let eth_payload = to_vec(&eth_tx).unwrap();
-    let btc_payload = to_vec(&btc_tx).unwrap();
-
-    let _eth_result: Result<(), _> = call::<(Vec<u8>,), ()>(
-        chain_fusion_principal, 
-        "execute_transaction", 
-        (eth_payload,)
-    ).await;
-    
-    let _btc_result: Result<(), _> = call::<(Vec<u8>,), ()>(
-        chain_fusion_principal, 
-        "execute_transaction", 
-        (btc_payload,)
-    ).await;
-}
+async fn verify_nft_ownership(request: WalletVerificationRequest) -> Result<VerificationResult, NftError> {
+    let chain = Chain::from_id(&request.chain_id)?;
+    let collection = request.nft_contract_address.as_deref()
+        .ok_or_else(|| NftError::InvalidRequest("nft_contract_address is required".to_string()))?;
+    let token_id = request.token_id.as_deref();
+    let is_verified = match chain {
+        Chain::InternetComputer => {
+            let owner = Principal::from_text(&request.wallet_address)
+                .map_err(|e| NftError::InvalidRequest(format!("Invalid wallet address: {}", e)))?;
+            delegation::authorize(ic_cdk::caller(), owner, time()).map_err(NftError::Unauthorized)?;
+            nft::ic_owns(collection, owner, token_id).await?
+        }
+        // EVM state is public, so any address may be checked
+        Chain::Evm(chain_id) => {
+            let owner = evm::parse_address(&request.wallet_address).map_err(NftError::InvalidRequest)?;
+            nft::evm_owns(chain_id, collection, &owner, token_id).await?
+        }
+    };
 
-#[update]
-fn verify_nft_ownership(request: WalletVerificationRequest) -> VerificationResult {
     let proof_id = Uuid::new_v4().to_string();
     let anonymous_reference = Uuid::new_v4().to_string();
-    
-    // In a real implementation, this would make external calls to verify NFT ownership
-    // For now, we'll simulate verification based on the wallet address
-    // This is a placeholder - in production, you would integrate with actual blockchain APIs
-    
-    // Simple simulation: if wallet address starts with "0x", consider it verified
-    let is_verified = request.wallet_address.starts_with("0x");
-    
+
     let result = VerificationResult {
         is_verified,
         proof_id: proof_id.clone(),
@@ -689,13 +559,13 @@ fn verify_nft_ownership(request: WalletVerificationRequest) -> VerificationResul
         priority: "high".to_string(),
         execution_delay: 0,
         retry_attempts: 3,
-        disclosure_level: "anonymous".to_string(),
+        disclosure_level: DisclosureLevel::Anonymous,
         storage_type: "chain".to_string(),
     };
     
-    assign_task(reference_id, task_description, Some(config));
+    assign_task(reference_id, task_description, Some(config), None);
     
-    result
+    Ok(result)
 }
 
 #[query]
@@ -711,67 +581,93 @@ async fn generate_token_proof(request: TokenProofRequest) -> Result<TokenProofRe
     if request.min_balance == 0 {
         return Err("Minimum balance must be greater than 0".to_string());
     }
+    check_token_id(&request.token_id)?;
 
-    // Get current merkle root
-    let merkle_root = MERKLE_ROOT.with(|root| root.borrow().clone());
-    if merkle_root.is_empty() {
-        return Err("Merkle root not initialized".to_string());
-    }
+    let wallet_principal = delegation::resolve_wallet(ic_cdk::caller(), request.wallet_address.as_deref(), time())?;
 
-    // Convert wallet address to Principal
-    let wallet_principal = Principal::from_text(&request.wallet_address)
-        .map_err(|e| format!("Invalid wallet address: {}", e))?;
+    // Get actual token balance from the token's ledger
+    let (accounts, scope) = request.accounts(wallet_principal)?;
+    let standard = request.token_standard.clone().unwrap_or(TokenStandard::ICP);
+    let token = ledger::token_balance(standard, &request.token_id, accounts).await?;
 
-    // Get actual token balance from ICP ledger
-    let account = Account {
-        owner: wallet_principal,
-        subaccount: None,
-    };
-    
-    let actual_balance = get_icp_balance(account).await?;
-    
-    if actual_balance < request.min_balance {
+    if token.balance < request.min_balance {
         return Err("Insufficient balance".to_string());
     }
 
-    // Prepare input for ZK circuit
+    // Record the balance, then prove against the root that includes it
+    let circuit_input = MERKLE_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.set_balance(wallet_principal, token.token_id, scope.clone(), token.balance)?;
+        ownership::ownership_input(&tree, wallet_principal, token.token_id, &scope, request.min_balance, token.metadata)
+    })?;
+    roots::publish(&circuit_input.merkle_root, time());
+
+    prove_and_store(request.token_id, Some(token.symbol), circuit_input).await
+}
+
+// Prove ownership of the leaf in the frozen ledger snapshot; `token_id` is the
+// ledger canister id
+#[update]
+async fn generate_snapshot_proof(request: TokenProofRequest) -> Result<TokenProofResult, String> {
+    if request.min_balance == 0 {
+        return Err("Minimum balance must be greater than 0".to_string());
+    }
+    check_token_id(&request.token_id)?;
+
+    let wallet_principal = delegation::resolve_wallet(ic_cdk::caller(), request.wallet_address.as_deref(), time())?;
+    let ledger = Principal::from_text(&request.token_id)
+        .map_err(|e| format!("Invalid ledger canister id: {}", e))?;
+    let token_id = snapshot::frozen_token_id(ledger)?;
+    // Snapshots hold one leaf per ledger account, so there is nothing to aggregate
+    if request.aggregate_subaccounts.is_some() {
+        return Err("Snapshot proofs cover a single account".to_string());
+    }
+    let (_, scope) = request.accounts(wallet_principal)?;
+
     let token_metadata = TokenMetadata {
-        canister_id: Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap(),
-        token_standard: TokenStandard::ICP,
-        decimals: 8,  // ICP uses 8 decimal places
+        canister_id: request.token_id.clone(),
+        token_standard: TokenStandard::ICRC1,
+        decimals: Some(ledger::icrc1_decimals(ledger).await?),
     };
+    let symbol = ledger::icrc1_symbol(ledger).await?;
 
-    let circuit_input = TokenOwnershipInput {
-        token_metadata,
-        token_id: 1,  // ICP token ID is always 1
-        balance: actual_balance,
-        owner_hash: hash_principal(&wallet_principal),
-        merkle_path: generate_merkle_path(wallet_principal, actual_balance),
-        path_indices: generate_path_indices(),
-        token_specific_data: None,
-    };
+    let circuit_input = snapshot::SNAPSHOT_TREE.with(|tree| {
+        let tree = tree.borrow();
+        let balance = tree.balance(wallet_principal, token_id, &scope).unwrap_or(0);
+        if balance < request.min_balance {
+            return Err("Insufficient balance".to_string());
+        }
+        ownership::ownership_input(&tree, wallet_principal, token_id, &scope, request.min_balance, token_metadata)
+    })?;
+
+    prove_and_store(request.token_id, Some(symbol), circuit_input).await
+}
+
+// Have the zk_canister prove `circuit_input` and store the result
+async fn prove_and_store(token_id: String, token_symbol: Option<String>, circuit_input: TokenOwnershipInput) -> Result<TokenProofResult, String> {
+    // Bound what gets stored before paying for the proof
+    check_token_id(&token_id)?;
+    let token_symbol = token_symbol.map(bounded_symbol);
+    let merkle_root = hex::encode(&circuit_input.merkle_root);
 
     // Generate ZK proof using the circuit
-    let zk_canister = Principal::from_text("hi7bu-myaaa-aaaad-aaloa-cai")
-        .map_err(|e| format!("Invalid ZK canister ID: {}", e))?;
+    let zk_canister = zk_canister_id()?;
     
-    let proof_result: Result<Vec<u8>, String> = call(
+    let (proof_result,): (Result<Vec<u8>, ProofError>,) = call(
         zk_canister,
         "prove_ownership",
-        (CIRCUIT_PARAMS, circuit_input)
+        (OWNERSHIP_PARAM_ID.to_string(), circuit_input)
     ).await.map_err(|(_, msg)| msg)?;
 
-    let proof_data = proof_result.iter()
-        .enumerate()
-        .map(|(i, &b)| (i as u8, vec![b]))
-        .collect();
+    let proof_data = proof_result.map_err(|e| format!("ZK canister failed to prove: {:?}", e))?;
 
     let proof_id = Uuid::new_v4().to_string();
     let anonymous_reference = Uuid::new_v4().to_string();
     
     let result = TokenProofResult {
         proof_id: proof_id.clone(),
-        token_id: request.token_id.clone(),
+        token_id,
+        token_symbol,
         merkle_root,
         proof_data,
         anonymous_reference: anonymous_reference.clone(),
@@ -787,149 +683,203 @@ async fn generate_token_proof(request: TokenProofRequest) -> Result<TokenProofRe
     Ok(result)
 }
 
-// Helper function to hash a Principal for the circuit
-fn hash_principal(principal: &Principal) -> Vec<u8> {
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(principal.as_slice());
-    hasher.finalize().to_vec()
-}
-
-// Helper function to generate Merkle path - now uses actual Merkle tree
-fn generate_merkle_path(principal: Principal, balance: u64) -> Vec<u64> {
+#[query]
+fn get_merkle_root() -> Result<String, String> {
     MERKLE_TREE.with(|tree| {
-        let mut tree = tree.borrow_mut();
-        // Update or add the balance to the tree
-        if !tree.update_balance(principal, 1, balance) {
-            tree.add_balance(principal, 1, balance);
-        }
-        // Convert proof format for ZK circuit
-        if let Some((proof, _)) = tree.generate_proof(principal, 1) {
-            proof.iter()
-                .map(|hash| u64::from_be_bytes(hash[0..8].try_into().unwrap()))
-                .collect()
-        } else {
-            vec![] // Empty proof as fallback
+        let tree = tree.borrow();
+        if tree.is_empty() {
+            return Err("Merkle root not initialized".to_string());
         }
+        Ok(hex::encode(tree.root_hash()))
     })
 }
 
-// Helper function to generate path indices - now uses actual Merkle tree
-fn generate_path_indices() -> Vec<u8> {
-    MERKLE_TREE.with(|tree| {
-        // Get proof for any leaf (we only need the indices)
-        if let Some(leaf) = tree.borrow().leaves.first() {
-            if let Some((_, indices)) = tree.borrow().generate_proof(leaf.principal, leaf.token_id) {
-                indices.iter().map(|&b| b as u8).collect()
-            } else {
-                vec![]
-            }
-        } else {
-            vec![]
-        }
-    })
+#[update]
+fn update_merkle_root(_root: String) -> Result<(), String> {
+    // This function is now deprecated since the root is managed by the tree
+    Err("Merkle root is now managed automatically by the tree".to_string())
 }
 
+// Add new function to get balance proof - useful for frontend verification.
+// None as well when the caller may not request proofs for `principal`.
 #[query]
-fn get_merkle_root() -> Result<String, String> {
+fn get_balance_proof(principal: Principal, token_id: u64, subaccount: Option<Vec<u8>>) -> Option<MerkleProof> {
+    delegation::authorize(ic_cdk::caller(), principal, time()).ok()?;
+    let subaccount = subaccount.as_deref().map(parse_subaccount).transpose().ok()?;
     MERKLE_TREE.with(|tree| {
-        tree.borrow()
-            .root_hash()
-            .map(|hash| hex::encode(hash))
-            .ok_or_else(|| "Merkle root not initialized".to_string())
+        tree.borrow().generate_proof(principal, token_id, &AccountScope::of(subaccount))
     })
 }
 
+// Allow `delegate` to request proofs about the caller's balances
 #[update]
-fn update_merkle_root(root: String) -> Result<(), String> {
-    // This function is now deprecated since the root is managed by the tree
-    Err("Merkle root is now managed automatically by the tree".to_string())
+fn grant_delegation(delegate: Principal, expires_at: Option<u64>) -> Result<Delegation, String> {
+    delegation::grant(ic_cdk::caller(), delegate, expires_at, time())
+}
+
+#[update]
+fn revoke_delegation(delegate: Principal) -> Result<(), String> {
+    delegation::revoke(ic_cdk::caller(), delegate)
 }
 
-// Add new function to get balance proof - useful for frontend verification
+// Delegations granted by or to the caller
 #[query]
-fn get_balance_proof(principal: Principal, token_id: u64) -> Option<(Vec<Vec<u8>>, Vec<bool>)> {
-    MERKLE_TREE.with(|tree| {
-        tree.borrow().generate_proof(principal, token_id)
-    })
+fn get_delegations() -> Vec<Delegation> {
+    delegation::list(ic_cdk::caller())
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-enum TokenStandard {
-    ICP,
-    ERC20,
-    ICRC1,
+// The zk_canister that proves and verifies ownership statements
+const ZK_CANISTER_ID: &str = "hi7bu-myaaa-aaaad-aaloa-cai";
+
+fn zk_canister_id() -> Result<Principal, String> {
+    Principal::from_text(ZK_CANISTER_ID).map_err(|e| format!("Invalid ZK canister ID: {}", e))
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-struct TokenMetadata {
-    canister_id: Principal,
-    token_standard: TokenStandard,
-    decimals: u8,
+// Parameter set in the zk_canister key registry. Keys are installed there through the
+// chunked begin_upload/append_upload/commit_upload flow and then activated.
+const OWNERSHIP_PARAM_ID: &str = "ownership-v1";
+
+// Only proofs issued under this canister's parameter set, against a root it
+// published and still accepts, are sent on for verification
+fn check_envelope(proof: &[u8], now: u64) -> Result<OwnershipProof, String> {
+    let envelope = candid::decode_one::<OwnershipProof>(proof)
+        .map_err(|e| format!("Malformed proof envelope: {}", e))?;
+    if envelope.param_id != OWNERSHIP_PARAM_ID {
+        return Err(format!(
+            "Proof was made with parameter set {}, expected {}",
+            envelope.param_id, OWNERSHIP_PARAM_ID
+        ));
+    }
+    roots::check_accepted(&envelope.public_inputs.merkle_root, now)?;
+    Ok(envelope)
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-struct TokenOwnershipInput {
-    token_metadata: TokenMetadata,
-    token_id: u64,
-    balance: u64,
-    owner_hash: Vec<u8>,
-    merkle_path: Vec<u64>,
-    path_indices: Vec<u8>,
-    token_specific_data: Option<Vec<u8>>,
+#[update]
+async fn verify_token_proof(proof: Vec<u8>) -> Result<bool, String> {
+    ic_cdk::println!("Verifying token proof with ZK canister");
+    let envelope = check_envelope(&proof, time())?;
+
+    // Verify under OWNERSHIP_PARAM_ID's key, and check the zk_canister answered for
+    // that key and the envelope's statement
+    let zk_canister = zk_canister_id()?;
+    let (result,): (Result<VerificationOutcome, VerificationError>,) = call(
+        zk_canister,
+        "verify_statement",
+        (OWNERSHIP_PARAM_ID.to_string(), envelope.public_inputs.clone(), envelope.proof)
+    ).await.map_err(|(code, msg)| format!("Failed to call ZK canister: {} (code: {:?})", msg, code))?;
+
+    let outcome = result.map_err(|e| format!("ZK canister returned an error: {:?}", e))?;
+    if outcome.vk_id != OWNERSHIP_PARAM_ID || outcome.public_inputs != envelope.public_inputs {
+        return Err("ZK canister verified a different statement".to_string());
+    }
+    Ok(outcome.is_valid)
+}
+
+// Published balance tree roots, newest first
+#[query]
+fn get_merkle_roots() -> Vec<RootRecord> {
+    roots::list()
 }
 
-// Circuit parameters (in production, these would be loaded from a file)
-const CIRCUIT_PARAMS: &[u8] = &[0u8; 32];  // Placeholder
+#[query]
+fn get_root_window() -> RootWindow {
+    roots::window()
+}
 
 #[update]
-async fn verify_token_proof(zk_canister: Principal, proof: Vec<u8>) -> Result<bool, String> {
-    ic_cdk::println!("Verifying token proof with ZK canister");
-    
-    // Call the ZK canister to verify the proof
-    match ic_cdk::call(zk_canister, "verify_proof", (proof,)).await {
-        Ok(result) => {
-            let is_valid: Result<bool, String> = result;
-            match is_valid {
-                Ok(valid) => Ok(valid),
-                Err(e) => Err(format!("ZK canister returned an error: {}", e))
-            }
-        },
-        Err((code, msg)) => {
-            Err(format!("Failed to call ZK canister: {} (code: {:?})", msg, code))
+fn set_root_window(window: RootWindow) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can change the root window".to_string());
+    }
+    roots::set_window(window)
+}
+
+// Add an ICRC-3 ledger to the balance snapshot, its balances kept under `token_id`
+#[update]
+fn register_snapshot_ledger(ledger: Principal, token_id: u64) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can register snapshot ledgers".to_string());
+    }
+    snapshot::register_ledger(ledger, token_id)
+}
+
+// Catch the snapshot up with every registered ledger, then freeze it and publish its root
+#[update]
+async fn build_balance_snapshot() -> Result<SnapshotStatus, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can build balance snapshots".to_string());
+    }
+    snapshot::build().await
+}
+
+// Read a balance from an EVM chain: the ERC-20 balance, the number of ERC-721 tokens
+// held (0 or 1 for a single token_id), or the ERC-1155 balance of token_id
+#[update]
+async fn get_evm_balance(request: EvmBalanceRequest) -> Result<Nat, String> {
+    let contract = evm::parse_address(&request.contract_address)?;
+    let owner = evm::parse_address(&request.wallet_address)?;
+    let chain_id = request.chain_id;
+    match (request.token_standard, request.token_id) {
+        (TokenStandard::ERC20, None) => evm::erc20_balance(chain_id, &contract, &owner).await,
+        (TokenStandard::ERC721, None) => evm::erc721_balance(chain_id, &contract, &owner).await,
+        (TokenStandard::ERC721, Some(token_id)) => {
+            let holder = evm::erc721_owner(chain_id, &contract, &token_id).await?;
+            Ok(Nat::from(u64::from(holder == Some(owner))))
         }
+        (TokenStandard::ERC1155, Some(token_id)) => evm::erc1155_balance(chain_id, &contract, &owner, &token_id).await,
+        (TokenStandard::ERC1155, None) => Err("ERC1155 balances need a token_id".to_string()),
+        (TokenStandard::ERC20, Some(_)) => Err("ERC20 balances take no token_id".to_string()),
+        (other, _) => Err(format!("{:?} is not an EVM token standard", other)),
     }
 }
 
-// Function to store verification results
-fn store_verification_result(proof_id: String, is_valid: bool) {
-    let result = VerificationResult {
-        proof_id: proof_id.clone(),
-        is_valid,
-        timestamp: ic_cdk::api::time(),
-    };
+#[query]
+fn get_evm_config() -> EvmConfig {
+    evm::config()
+}
 
-    VERIFICATION_RESULTS.with(|results| {
-        results.borrow_mut().insert(StorableString(proof_id.clone()), result);
-    });
+#[update]
+fn set_evm_config(config: EvmConfig) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can change the EVM config".to_string());
+    }
+    evm::set_config(config)
 }
 
-// Function to store token proofs
-fn store_token_proof(proof_id: String, token_id: String, merkle_root: String, proof_data: Vec<u8>, anonymous_reference: String) -> TokenProofResult {
-    let result = TokenProofResult {
-        proof_id: proof_id.clone(),
-        token_id,
-        merkle_root,
-        proof_data,
-        anonymous_reference,
-        timestamp: ic_cdk::api::time(),
-        is_valid: true,
-    };
+// What the owner of `address` has to sign before the caller can attest its balance
+#[query]
+fn get_btc_attestation_message(address: String) -> String {
+    btc::attestation_message(&ic_cdk::caller(), &address)
+}
 
-    TOKEN_PROOFS.with(|proofs| {
-        proofs.borrow_mut().insert(StorableString(proof_id.clone()), result.clone());
-    });
+// Attest that the caller controls a Bitcoin address holding at least min_satoshis
+#[update]
+async fn attest_btc_balance(request: BtcAttestationRequest) -> Result<BtcAttestation, String> {
+    btc::attest(ic_cdk::caller(), &request.address, &request.signature, request.min_satoshis, time()).await
+}
+
+#[query]
+fn get_btc_attestation(id: String) -> Option<BtcAttestation> {
+    btc::attestation(&id)
+}
+
+#[query]
+fn get_btc_config() -> BtcConfig {
+    btc::config()
+}
+
+#[update]
+fn set_btc_config(config: BtcConfig) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can change the Bitcoin config".to_string());
+    }
+    btc::set_config(config);
+    Ok(())
+}
 
-    result
+#[query]
+fn get_snapshot_state() -> SnapshotState {
+    snapshot::state()
 }
 
 #[query]
@@ -941,11 +891,52 @@ fn __get_candid_interface_tmp_hack() -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use candid::{export_service, Principal};
+    use candid::export_service;
 
     #[test]
     fn export_candid() {
         export_service!();
         std::println!("{}", __export_service());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn envelopes_must_name_the_ownership_parameters_and_an_accepted_root() {
+        let envelope = |param_id: &str, root: u8| candid::encode_one(OwnershipProof {
+            param_id: param_id.to_string(),
+            public_inputs: ownership::PublicInputs {
+                token_id: vec![1],
+                min_balance: 10,
+                merkle_root: vec![root; 32],
+                tree_depth: 20,
+            },
+            proof: vec![1, 2, 3],
+        }).unwrap();
+        roots::publish(&[7; 32], 1);
+
+        assert_eq!(check_envelope(&envelope(OWNERSHIP_PARAM_ID, 7), 2).map(|e| e.proof), Ok(vec![1, 2, 3]));
+        assert!(check_envelope(&envelope("ownership-v2", 7), 2).is_err());
+        assert!(check_envelope(&envelope(OWNERSHIP_PARAM_ID, 8), 2).is_err());
+        assert!(check_envelope(&[1, 2, 3], 2).is_err());
+    }
+
+    #[test]
+    fn token_proof_results_within_the_field_limits_fit() {
+        assert!(check_token_id(&"t".repeat(MAX_TOKEN_ID_LEN + 1)).is_err());
+        let symbol = bounded_symbol("é".repeat(MAX_TOKEN_SYMBOL_LEN));
+        assert_eq!(symbol.len(), MAX_TOKEN_SYMBOL_LEN);
+        assert_eq!(bounded_symbol("ckBTC".to_string()), "ckBTC");
+
+        // A Groth16 ownership envelope is a few hundred bytes
+        let result = TokenProofResult {
+            proof_id: Uuid::nil().to_string(),
+            token_id: "t".repeat(MAX_TOKEN_ID_LEN),
+            token_symbol: Some(symbol),
+            merkle_root: "ab".repeat(32),
+            proof_data: vec![0xff; 1024],
+            anonymous_reference: Uuid::nil().to_string(),
+            timestamp: u64::MAX,
+            is_valid: true,
+        };
+        assert!(result.to_bytes().len() <= TokenProofResult::MAX_SIZE as usize);
+    }
+}
@@ -0,0 +1,98 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableCell};
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Stable memory layout of the main_canister. All stable structures draw their
+// virtual memory from the single MemoryManager below, so no two of them can alias.
+// Ids are permanent: never renumber one, and never reuse the id of a removed map.
+//
+//   id | structure                   | contents
+//   ---+-----------------------------+------------------------------------------
+//    0 | SCHEMA_VERSION              | u32 version of the stored data layout
+//    1 | LEGACY_REFERENCES           | reference_id -> Reference as serde_json (schema 1 only)
+//    2 | LEGACY_VERIFICATION_RESULTS | proof_id -> VerificationResult, unversioned or v1 (schema <= 4)
+//    3 | LEGACY_TOKEN_PROOFS         | proof_id -> TokenProofResult, unversioned or v2/v3 (schema <= 4)
+//    4 | REFERENCES                  | reference_id -> Reference
+//    5 | MERKLE_NODES                | (level, index) -> node hash of the balance tree
+//    6 | LEGACY_MERKLE_LEAVES        | leaf index -> BalanceLeaf without scope (schema <= 3)
+//    7 | LEGACY_MERKLE_LEAF_INDEX    | (principal, token_id) -> leaf index (schema <= 3)
+//    8 | ROOT_HISTORY                | epoch -> RootRecord
+//    9 | ROOT_WINDOW                 | RootWindow of accepted roots
+//   10 | SNAPSHOT_NODES              | (level, index) -> node hash of the ledger snapshot tree
+//   11 | LEGACY_SNAPSHOT_LEAVES      | snapshot leaves without scope (schema <= 3)
+//   12 | LEGACY_SNAPSHOT_LEAF_INDEX  | snapshot leaf index without scope (schema <= 3)
+//   13 | SNAPSHOT_STATE              | SnapshotState: ledger cursors and build status
+//   14 | MERKLE_LEAVES               | leaf index -> BalanceLeaf
+//   15 | MERKLE_LEAF_INDEX           | (principal, token_id, scope) -> leaf index
+//   16 | SNAPSHOT_LEAVES             | leaf index -> BalanceLeaf of the snapshot tree
+//   17 | SNAPSHOT_LEAF_INDEX         | (principal, token_id, scope) -> snapshot leaf index
+//   18 | DELEGATIONS                 | (owner, delegate) -> Delegation of proof requests
+//   19 | EVM_CONFIG                  | EvmConfig: EVM RPC canister and provider agreement
+//   20 | BTC_CONFIG                  | BtcConfig: Bitcoin network and confirmations
+//   21 | BTC_ATTESTATIONS            | attestation id -> BtcAttestation
+//   22 | DISCLOSURE_CONFIG           | DisclosureConfig: threshold ECDSA key for full disclosures
+//   23 | TASK_QUEUE                  | (due_at, priority, task_id) -> reference_id of scheduled tasks
+//   24 | VERIFICATION_RESULTS        | proof_id -> VerificationResult
+//   25 | TOKEN_PROOFS                | proof_id -> TokenProofResult
+pub const SCHEMA_VERSION: MemoryId = MemoryId::new(0);
+pub const LEGACY_REFERENCES: MemoryId = MemoryId::new(1);
+pub const LEGACY_VERIFICATION_RESULTS: MemoryId = MemoryId::new(2);
+pub const LEGACY_TOKEN_PROOFS: MemoryId = MemoryId::new(3);
+pub const REFERENCES: MemoryId = MemoryId::new(4);
+pub const MERKLE_NODES: MemoryId = MemoryId::new(5);
+pub const LEGACY_MERKLE_LEAVES: MemoryId = MemoryId::new(6);
+pub const LEGACY_MERKLE_LEAF_INDEX: MemoryId = MemoryId::new(7);
+pub const ROOT_HISTORY: MemoryId = MemoryId::new(8);
+pub const ROOT_WINDOW: MemoryId = MemoryId::new(9);
+pub const SNAPSHOT_NODES: MemoryId = MemoryId::new(10);
+pub const LEGACY_SNAPSHOT_LEAVES: MemoryId = MemoryId::new(11);
+pub const LEGACY_SNAPSHOT_LEAF_INDEX: MemoryId = MemoryId::new(12);
+pub const SNAPSHOT_STATE: MemoryId = MemoryId::new(13);
+pub const MERKLE_LEAVES: MemoryId = MemoryId::new(14);
+pub const MERKLE_LEAF_INDEX: MemoryId = MemoryId::new(15);
+pub const SNAPSHOT_LEAVES: MemoryId = MemoryId::new(16);
+pub const SNAPSHOT_LEAF_INDEX: MemoryId = MemoryId::new(17);
+pub const DELEGATIONS: MemoryId = MemoryId::new(18);
+pub const EVM_CONFIG: MemoryId = MemoryId::new(19);
+pub const BTC_CONFIG: MemoryId = MemoryId::new(20);
+pub const BTC_ATTESTATIONS: MemoryId = MemoryId::new(21);
+pub const DISCLOSURE_CONFIG: MemoryId = MemoryId::new(22);
+pub const TASK_QUEUE: MemoryId = MemoryId::new(23);
+pub const VERIFICATION_RESULTS: MemoryId = MemoryId::new(24);
+pub const TOKEN_PROOFS: MemoryId = MemoryId::new(25);
+
+// Version written by this build. Bump it together with a migration in
+// migrations.rs whenever stored data has to be rewritten.
+pub const CURRENT_SCHEMA_VERSION: u32 = 5;
+
+// Version of the data written before the schema was recorded
+pub const BASELINE_SCHEMA_VERSION: u32 = 1;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // The baseline canister never wrote this cell, so an unwritten one means
+    // schema 1. Fresh installs are set to the current version by init().
+    static SCHEMA_VERSION_CELL: RefCell<StableCell<u32, Memory>> =
+        RefCell::new(StableCell::init(get(SCHEMA_VERSION), BASELINE_SCHEMA_VERSION)
+            .expect("failed to initialize the schema version cell"));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+pub fn schema_version() -> u32 {
+    SCHEMA_VERSION_CELL.with(|cell| *cell.borrow().get())
+}
+
+pub fn set_schema_version(version: u32) {
+    SCHEMA_VERSION_CELL.with(|cell| {
+        cell.borrow_mut()
+            .set(version)
+            .expect("failed to write the schema version");
+    });
+}
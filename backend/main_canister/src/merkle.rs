@@ -0,0 +1,670 @@
+use crate::memory::{self, Memory};
+use crate::poseidon;
+use ark_bn254::Fr;
+use ark_ff::PrimeField;
+use candid::{CandidType, Deserialize};
+use ic_cdk::export::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::marker::PhantomData;
+
+// Fixed depth of the balance tree; matches the circuit's path arrays
+pub const TREE_DEPTH: usize = 32;
+
+pub type Hash = [u8; 32];
+
+// Which of a principal's ledger accounts a leaf holds the balance of
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AccountScope {
+    // The default (all-zero) subaccount
+    Default,
+    Subaccount([u8; 32]),
+    // The sum over a set of subaccounts, named by the digest from AccountScope::aggregate
+    Aggregate([u8; 32]),
+}
+
+impl AccountScope {
+    // ICRC-1 treats a missing and an all-zero subaccount as the same account
+    pub fn of(subaccount: Option<[u8; 32]>) -> Self {
+        match subaccount {
+            Some(subaccount) if subaccount != [0; 32] => AccountScope::Subaccount(subaccount),
+            _ => AccountScope::Default,
+        }
+    }
+
+    // The scope of the combined balance of `subaccounts`, in any order and with
+    // repeats ignored. A single subaccount is just that account.
+    pub fn aggregate(subaccounts: &[[u8; 32]]) -> Self {
+        let mut subaccounts = subaccounts.to_vec();
+        subaccounts.sort();
+        subaccounts.dedup();
+        if let [subaccount] = subaccounts.as_slice() {
+            return AccountScope::of(Some(*subaccount));
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"aggregate");
+        hasher.update((subaccounts.len() as u32).to_be_bytes());
+        for subaccount in &subaccounts {
+            hasher.update(subaccount);
+        }
+        AccountScope::Aggregate(hasher.finalize().into())
+    }
+
+    // Tag byte and bytes, as used in leaf keys and the SHA-256 leaf preimage
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            AccountScope::Default => vec![0],
+            AccountScope::Subaccount(subaccount) => [&[1u8][..], subaccount].concat(),
+            AccountScope::Aggregate(digest) => [&[2u8][..], digest].concat(),
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        match bytes[0] {
+            0 => AccountScope::Default,
+            1 => AccountScope::Subaccount(bytes[1..33].try_into().unwrap()),
+            _ => AccountScope::Aggregate(bytes[1..33].try_into().unwrap()),
+        }
+    }
+}
+
+// Balance leaf structure - represents individual token balance entries
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BalanceLeaf {
+    pub principal: Principal,
+    pub token_id: u64,
+    pub scope: AccountScope,
+    pub balance: u64,
+}
+
+// The circuit's `wallet` input for an account. The default account is the
+// principal itself, so its leaves hash as before subaccounts existed. A subaccount
+// is split into two 128-bit halves so that distinct subaccounts cannot collide
+// modulo the field; an aggregate digest is only reduced, as finding two digests
+// that agree modulo the field is as hard as a SHA-256 collision.
+pub fn account_field(principal: &Principal, scope: &AccountScope) -> Fr {
+    let owner = poseidon::principal_to_field(principal);
+    match scope {
+        AccountScope::Default => owner,
+        AccountScope::Subaccount(subaccount) => poseidon::hash_3([
+            owner,
+            Fr::from_be_bytes_mod_order(&subaccount[..16]),
+            Fr::from_be_bytes_mod_order(&subaccount[16..]),
+        ]),
+        AccountScope::Aggregate(digest) => poseidon::hash_2([owner, Fr::from_be_bytes_mod_order(digest)]),
+    }
+}
+
+// How leaves and internal nodes of a MerkleTree are hashed. Implementations must
+// keep the two domains apart, so that no leaf can be passed off as an internal node
+// or the other way round.
+pub trait MerkleHasher {
+    fn hash_leaf(leaf: &BalanceLeaf) -> Hash;
+    fn hash_nodes(left: &Hash, right: &Hash) -> Hash;
+
+    // Whether `hash` can be fed to hash_nodes; used to reject foreign proofs
+    fn is_valid_hash(_hash: &Hash) -> bool {
+        true
+    }
+}
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+// leaf = SHA-256(0x00 || principal length || principal || token_id || balance),
+// followed by the scope's tag and bytes for anything but the default account;
+// node = SHA-256(0x01 || left || right)
+pub struct Sha256Hasher;
+
+impl MerkleHasher for Sha256Hasher {
+    fn hash_leaf(leaf: &BalanceLeaf) -> Hash {
+        let principal = leaf.principal.as_slice();
+        let mut hasher = Sha256::new();
+        hasher.update([LEAF_PREFIX, principal.len() as u8]);
+        hasher.update(principal);
+        hasher.update(leaf.token_id.to_be_bytes());
+        hasher.update(leaf.balance.to_be_bytes());
+        if leaf.scope != AccountScope::Default {
+            hasher.update(leaf.scope.encode());
+        }
+        hasher.finalize().into()
+    }
+
+    fn hash_nodes(left: &Hash, right: &Hash) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update([NODE_PREFIX]);
+        hasher.update(left);
+        hasher.update(right);
+        hasher.finalize().into()
+    }
+}
+
+// The circuit's hashing: leaf = hash_3([wallet, token, balance]), node =
+// hash_2([left, right]), with hashes stored as big-endian field elements. Poseidon
+// with 3 inputs and with 2 inputs are different permutations (width 4 and 3), which
+// separates leaves from nodes without a prefix the circuit does not have.
+pub struct PoseidonHasher;
+
+impl MerkleHasher for PoseidonHasher {
+    fn is_valid_hash(hash: &Hash) -> bool {
+        poseidon::field_from_bytes(hash).is_some()
+    }
+
+    fn hash_leaf(leaf: &BalanceLeaf) -> Hash {
+        poseidon::field_to_bytes(poseidon::hash_3([
+            account_field(&leaf.principal, &leaf.scope),
+            Fr::from(leaf.token_id),
+            Fr::from(leaf.balance),
+        ]))
+    }
+
+    fn hash_nodes(left: &Hash, right: &Hash) -> Hash {
+        // Every stored hash is a Poseidon output, so always canonical
+        let left = poseidon::field_from_bytes(left).expect("node hash is a field element");
+        let right = poseidon::field_from_bytes(right).expect("node hash is a field element");
+        poseidon::field_to_bytes(poseidon::hash_2([left, right]))
+    }
+}
+
+// Position of a node: level 0 holds the leaves, level TREE_DEPTH the root
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct NodeKey {
+    level: u8,
+    index: u32,
+}
+
+impl Storable for NodeKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![self.level];
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        NodeKey {
+            level: bytes[0],
+            index: u32::from_be_bytes(bytes[1..5].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for NodeKey {
+    const MAX_SIZE: u32 = 5;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+struct StoredHash(Hash);
+
+impl Storable for StoredHash {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredHash(bytes.as_ref().try_into().unwrap())
+    }
+}
+
+impl BoundedStorable for StoredHash {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// (principal, token_id, scope) of a leaf, for the leaf index lookup
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct LeafKey {
+    principal: Principal,
+    token_id: u64,
+    scope: AccountScope,
+}
+
+impl Storable for LeafKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let principal = self.principal.as_slice();
+        let mut bytes = vec![principal.len() as u8];
+        bytes.extend_from_slice(principal);
+        bytes.extend_from_slice(&self.token_id.to_be_bytes());
+        bytes.extend(self.scope.encode());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        LeafKey {
+            principal: Principal::from_slice(&bytes[1..1 + len]),
+            token_id: u64::from_be_bytes(bytes[1 + len..9 + len].try_into().unwrap()),
+            scope: AccountScope::decode(&bytes[9 + len..]),
+        }
+    }
+}
+
+impl BoundedStorable for LeafKey {
+    const MAX_SIZE: u32 = 1 + 29 + 8 + 33;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MerkleProof {
+    pub leaf_index: u32,
+    // Sibling hashes from the leaf level up, always TREE_DEPTH long
+    pub siblings: Vec<Vec<u8>>,
+    // Bits of leaf_index from the leaf level up: true when the node on the path is
+    // the right child, i.e. the sibling goes on the left
+    pub path_indices: Vec<bool>,
+}
+
+// Check that `proof` places `leaf` under `root`. Every level is hashed, so the
+// proof must carry exactly TREE_DEPTH siblings and the bits of its leaf index.
+pub fn verify_merkle_proof<H: MerkleHasher>(leaf: &BalanceLeaf, proof: &MerkleProof, root: &Hash) -> bool {
+    if proof.siblings.len() != TREE_DEPTH || proof.path_indices.len() != TREE_DEPTH {
+        return false;
+    }
+
+    let mut hash = H::hash_leaf(leaf);
+    for (level, (sibling, &is_right)) in proof.siblings.iter().zip(&proof.path_indices).enumerate() {
+        if is_right != ((proof.leaf_index >> level) & 1 == 1) {
+            return false;
+        }
+        let Ok(sibling) = Hash::try_from(sibling.as_slice()) else {
+            return false;
+        };
+        if !H::is_valid_hash(&sibling) {
+            return false;
+        }
+        hash = if is_right {
+            H::hash_nodes(&sibling, &hash)
+        } else {
+            H::hash_nodes(&hash, &sibling)
+        };
+    }
+    &hash == root
+}
+
+// Sparse Merkle tree of fixed depth kept in stable memory. Leaves are appended at
+// the next free index and updated in place; only nodes on the path of a written
+// leaf are stored. Every other subtree hashes to the precomputed empty value for
+// its level (an all-zero leaf at level 0), so an unpaired node is always hashed
+// with an empty sibling and every level of every path is hashed the same way.
+// A change rewrites TREE_DEPTH + 1 nodes.
+pub struct MerkleTree<H: MerkleHasher> {
+    nodes: StableBTreeMap<NodeKey, StoredHash, Memory>,
+    leaves: StableBTreeMap<u32, BalanceLeaf, Memory>,
+    leaf_index: StableBTreeMap<LeafKey, u32, Memory>,
+    // zeros[level] is the root of an empty subtree of height `level`
+    zeros: Vec<Hash>,
+    hasher: PhantomData<H>,
+}
+
+// The tree whose root the ownership circuit checks
+pub type BalanceTree = MerkleTree<PoseidonHasher>;
+
+// The three stable maps backing one tree
+pub struct TreeMemory {
+    pub nodes: MemoryId,
+    pub leaves: MemoryId,
+    pub leaf_index: MemoryId,
+}
+
+pub const LIVE_TREE_MEMORY: TreeMemory = TreeMemory {
+    nodes: memory::MERKLE_NODES,
+    leaves: memory::MERKLE_LEAVES,
+    leaf_index: memory::MERKLE_LEAF_INDEX,
+};
+
+impl<H: MerkleHasher> MerkleTree<H> {
+    pub fn init() -> Self {
+        Self::init_in(&LIVE_TREE_MEMORY)
+    }
+
+    pub fn init_in(tree_memory: &TreeMemory) -> Self {
+        let mut zeros = vec![[0u8; 32]];
+        for level in 0..TREE_DEPTH {
+            zeros.push(H::hash_nodes(&zeros[level], &zeros[level]));
+        }
+
+        MerkleTree {
+            nodes: StableBTreeMap::init(memory::get(tree_memory.nodes)),
+            leaves: StableBTreeMap::init(memory::get(tree_memory.leaves)),
+            leaf_index: StableBTreeMap::init(memory::get(tree_memory.leaf_index)),
+            zeros,
+            hasher: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn leaf_index(&self, principal: Principal, token_id: u64, scope: &AccountScope) -> Option<u32> {
+        self.leaf_index.get(&LeafKey { principal, token_id, scope: scope.clone() })
+    }
+
+    pub fn leaf(&self, index: u32) -> Option<BalanceLeaf> {
+        self.leaves.get(&index)
+    }
+
+    pub fn balance(&self, principal: Principal, token_id: u64, scope: &AccountScope) -> Option<u64> {
+        self.leaf_index(principal, token_id, scope)
+            .and_then(|index| self.leaf(index))
+            .map(|leaf| leaf.balance)
+    }
+
+    fn node(&self, level: usize, index: u32) -> Hash {
+        self.nodes
+            .get(&NodeKey { level: level as u8, index })
+            .map(|hash| hash.0)
+            .unwrap_or(self.zeros[level])
+    }
+
+    // Insert or update the balance of (principal, token_id, scope); returns its leaf index
+    pub fn set_balance(&mut self, principal: Principal, token_id: u64, scope: AccountScope, balance: u64) -> Result<u32, String> {
+        let key = LeafKey { principal, token_id, scope: scope.clone() };
+        let index = match self.leaf_index.get(&key) {
+            Some(index) => index,
+            None => {
+                let next = self.leaves.len();
+                if next > u32::MAX as u64 {
+                    return Err("Merkle tree is full".to_string());
+                }
+                self.leaf_index.insert(key, next as u32);
+                next as u32
+            }
+        };
+
+        let leaf = BalanceLeaf { principal, token_id, scope, balance };
+        self.write_path(index, H::hash_leaf(&leaf));
+        self.leaves.insert(index, leaf);
+
+        Ok(index)
+    }
+
+    // Store a leaf hash and the TREE_DEPTH nodes above it
+    fn write_path(&mut self, index: u32, leaf_hash: Hash) {
+        let mut hash = leaf_hash;
+        let mut position = index;
+        for level in 0..TREE_DEPTH {
+            self.nodes.insert(NodeKey { level: level as u8, index: position }, StoredHash(hash));
+            let sibling = self.node(level, position ^ 1);
+            hash = if position & 1 == 0 {
+                H::hash_nodes(&hash, &sibling)
+            } else {
+                H::hash_nodes(&sibling, &hash)
+            };
+            position >>= 1;
+        }
+        self.nodes.insert(NodeKey { level: TREE_DEPTH as u8, index: 0 }, StoredHash(hash));
+    }
+
+    // Recompute every node from the stored leaves, e.g. after switching hashers
+    pub fn rehash(&mut self) {
+        let leaves: Vec<(u32, BalanceLeaf)> = self.leaves.iter().collect();
+        self.rehash_from(&leaves);
+    }
+
+    // Replace every node with those of `leaves`, which need not be the stored ones
+    pub fn rehash_from(&mut self, leaves: &[(u32, BalanceLeaf)]) {
+        let keys: Vec<NodeKey> = self.nodes.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            self.nodes.remove(&key);
+        }
+        for (index, leaf) in leaves {
+            self.write_path(*index, H::hash_leaf(leaf));
+        }
+    }
+
+    // Store a leaf at `index` and index it, leaving the nodes alone; for moving
+    // leaves between maps without rehashing
+    pub fn restore_leaf(&mut self, index: u32, leaf: BalanceLeaf) {
+        let key = LeafKey { principal: leaf.principal, token_id: leaf.token_id, scope: leaf.scope.clone() };
+        self.leaf_index.insert(key, index);
+        self.leaves.insert(index, leaf);
+    }
+
+    pub fn root_hash(&self) -> Hash {
+        self.node(TREE_DEPTH, 0)
+    }
+
+    // Generate proof path - essential for ZK proof verification
+    pub fn generate_proof(&self, principal: Principal, token_id: u64, scope: &AccountScope) -> Option<MerkleProof> {
+        let leaf_index = self.leaf_index(principal, token_id, scope)?;
+
+        let mut siblings = Vec::with_capacity(TREE_DEPTH);
+        let mut path_indices = Vec::with_capacity(TREE_DEPTH);
+        let mut position = leaf_index;
+        for level in 0..TREE_DEPTH {
+            siblings.push(self.node(level, position ^ 1).to_vec());
+            path_indices.push(position & 1 == 1);
+            position >>= 1;
+        }
+
+        Some(MerkleProof { leaf_index, siblings, path_indices })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    // Dense recomputation over all leaves, padding each level with empty subtrees
+    fn naive_root<H: MerkleHasher>(tree: &MerkleTree<H>, leaves: &[BalanceLeaf]) -> Hash {
+        let mut level: Vec<Hash> = leaves.iter().map(H::hash_leaf).collect();
+        for height in 0..TREE_DEPTH {
+            if level.len() % 2 == 1 {
+                level.push(tree.zeros[height]);
+            }
+            level = level.chunks(2).map(|pair| H::hash_nodes(&pair[0], &pair[1])).collect();
+        }
+        level.first().copied().unwrap_or(tree.zeros[TREE_DEPTH])
+    }
+
+    // One test per hasher: both trees live in the same stable memory
+    #[test]
+    fn sha256_tree_matches_full_recomputation() {
+        check_full_recomputation::<Sha256Hasher>();
+    }
+
+    #[test]
+    fn poseidon_tree_matches_full_recomputation() {
+        check_full_recomputation::<PoseidonHasher>();
+    }
+
+    fn check_full_recomputation<H: MerkleHasher>() {
+        let mut tree = MerkleTree::<H>::init();
+        assert_eq!(tree.root_hash(), tree.zeros[TREE_DEPTH]);
+
+        let mut leaves = Vec::new();
+        for n in 0..7u8 {
+            let leaf = BalanceLeaf { principal: principal(n), token_id: 1, scope: AccountScope::Default, balance: 100 * n as u64 };
+            assert_eq!(tree.set_balance(leaf.principal, leaf.token_id, AccountScope::Default, leaf.balance), Ok(n as u32));
+            leaves.push(leaf);
+            assert_eq!(tree.root_hash(), naive_root(&tree, &leaves));
+        }
+
+        // Updating keeps the leaf where it is
+        assert_eq!(tree.set_balance(principal(3), 1, AccountScope::Default, 42), Ok(3));
+        leaves[3].balance = 42;
+        assert_eq!(tree.len(), 7);
+        assert_eq!(tree.leaf(3), Some(leaves[3].clone()));
+        assert_eq!(tree.root_hash(), naive_root(&tree, &leaves));
+
+        // Same principal under another token is a separate leaf
+        assert_eq!(tree.set_balance(principal(3), 2, AccountScope::Default, 5), Ok(7));
+    }
+
+    #[test]
+    fn sha256_proofs_verify_at_every_position() {
+        check_every_position::<Sha256Hasher>();
+    }
+
+    #[test]
+    fn poseidon_proofs_verify_at_every_position() {
+        check_every_position::<PoseidonHasher>();
+    }
+
+    // Grow the tree one leaf at a time so that every position is checked both as
+    // the last, unpaired leaf of its level and once it has a sibling
+    fn check_every_position<H: MerkleHasher>() {
+        let mut tree = MerkleTree::<H>::init();
+        let mut leaves = Vec::new();
+        for n in 0..9u8 {
+            let leaf = BalanceLeaf { principal: principal(n), token_id: 1, scope: AccountScope::Default, balance: n as u64 };
+            tree.set_balance(leaf.principal, leaf.token_id, AccountScope::Default, leaf.balance).unwrap();
+            leaves.push(leaf);
+
+            let root = tree.root_hash();
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.generate_proof(leaf.principal, leaf.token_id, &leaf.scope).unwrap();
+                assert_eq!(proof.leaf_index, index as u32);
+                assert!(verify_merkle_proof::<H>(leaf, &proof, &root));
+            }
+        }
+        assert_eq!(tree.generate_proof(principal(99), 1, &AccountScope::Default), None);
+    }
+
+    #[test]
+    fn rejects_tampered_proofs() {
+        let mut tree = BalanceTree::init();
+        for n in 0..5u8 {
+            tree.set_balance(principal(n), 1, AccountScope::Default, n as u64).unwrap();
+        }
+        let root = tree.root_hash();
+        let leaf = tree.leaf(4).unwrap();
+        let proof = tree.generate_proof(leaf.principal, 1, &leaf.scope).unwrap();
+        assert!(verify_merkle_proof::<PoseidonHasher>(&leaf, &proof, &root));
+
+        let inflated = BalanceLeaf { balance: 1_000, ..leaf.clone() };
+        assert!(!verify_merkle_proof::<PoseidonHasher>(&inflated, &proof, &root));
+
+        let mut flipped = proof.clone();
+        flipped.path_indices[0] = !flipped.path_indices[0];
+        assert!(!verify_merkle_proof::<PoseidonHasher>(&leaf, &flipped, &root));
+
+        let mut truncated = proof.clone();
+        truncated.siblings.pop();
+        truncated.path_indices.pop();
+        assert!(!verify_merkle_proof::<PoseidonHasher>(&leaf, &truncated, &root));
+
+        let mut out_of_field = proof.clone();
+        out_of_field.siblings[3] = vec![0xff; 32];
+        assert!(!verify_merkle_proof::<PoseidonHasher>(&leaf, &out_of_field, &root));
+
+        assert!(!verify_merkle_proof::<PoseidonHasher>(&leaf, &proof, &tree.zeros[TREE_DEPTH]));
+    }
+
+    #[test]
+    fn sha256_prefixes_leaves_and_nodes() {
+        let leaf = BalanceLeaf { principal: principal(1), token_id: 2, scope: AccountScope::Default, balance: 3 };
+        let mut preimage = vec![LEAF_PREFIX, 29];
+        preimage.extend_from_slice(&[1; 29]);
+        preimage.extend_from_slice(&2u64.to_be_bytes());
+        preimage.extend_from_slice(&3u64.to_be_bytes());
+        assert_eq!(Sha256Hasher::hash_leaf(&leaf), <Hash>::from(Sha256::digest(&preimage)));
+
+        // A 64-byte leaf-like preimage cannot pass as a node: nodes start with 0x01
+        let (left, right) = ([7u8; 32], [9u8; 32]);
+        let mut preimage = vec![NODE_PREFIX];
+        preimage.extend_from_slice(&left);
+        preimage.extend_from_slice(&right);
+        assert_eq!(Sha256Hasher::hash_nodes(&left, &right), <Hash>::from(Sha256::digest(&preimage)));
+        assert_ne!(Sha256Hasher::hash_nodes(&left, &right), <Hash>::from(Sha256::digest([left, right].concat())));
+    }
+
+    #[test]
+    fn tree_is_read_back_from_stable_memory() {
+        let mut tree = BalanceTree::init();
+        tree.set_balance(principal(1), 1, AccountScope::Default, 10).unwrap();
+        tree.set_balance(principal(2), 1, AccountScope::Default, 20).unwrap();
+        let root = tree.root_hash();
+
+        let reopened = BalanceTree::init();
+        assert_eq!(reopened.root_hash(), root);
+        assert_eq!(reopened.leaf_index(principal(2), 1, &AccountScope::Default), Some(1));
+    }
+
+    #[test]
+    fn rehash_switches_hasher_in_place() {
+        let mut sha = MerkleTree::<Sha256Hasher>::init();
+        sha.set_balance(principal(1), 1, AccountScope::Default, 10).unwrap();
+        sha.set_balance(principal(2), 1, AccountScope::Default, 20).unwrap();
+        let sha_root = sha.root_hash();
+
+        let mut tree = BalanceTree::init();
+        assert_eq!(tree.root_hash(), sha_root);
+        tree.rehash();
+
+        let leaves = vec![tree.leaf(0).unwrap(), tree.leaf(1).unwrap()];
+        assert_eq!(tree.root_hash(), naive_root(&tree, &leaves));
+        assert_ne!(tree.root_hash(), sha_root);
+    }
+
+    #[test]
+    fn poseidon_leaf_matches_circuit() {
+        let leaf = BalanceLeaf { principal: principal(7), token_id: 1, scope: AccountScope::Default, balance: 200 };
+        let expected = poseidon::hash_3([
+            poseidon::principal_to_field(&leaf.principal),
+            Fr::from(1u64),
+            Fr::from(200u64),
+        ]);
+        assert_eq!(PoseidonHasher::hash_leaf(&leaf), poseidon::field_to_bytes(expected));
+    }
+
+    #[test]
+    fn subaccounts_are_separate_leaves() {
+        let mut tree = BalanceTree::init();
+        let savings = AccountScope::of(Some([5; 32]));
+        assert_eq!(AccountScope::of(Some([0; 32])), AccountScope::Default);
+
+        assert_eq!(tree.set_balance(principal(1), 1, AccountScope::Default, 10), Ok(0));
+        assert_eq!(tree.set_balance(principal(1), 1, savings.clone(), 20), Ok(1));
+        assert_eq!(tree.balance(principal(1), 1, &AccountScope::Default), Some(10));
+        assert_eq!(tree.balance(principal(1), 1, &savings), Some(20));
+
+        let leaf = tree.leaf(1).unwrap();
+        let proof = tree.generate_proof(principal(1), 1, &savings).unwrap();
+        assert!(verify_merkle_proof::<PoseidonHasher>(&leaf, &proof, &tree.root_hash()));
+        // The proof is for the subaccount's leaf only
+        let as_default = BalanceLeaf { scope: AccountScope::Default, ..leaf };
+        assert!(!verify_merkle_proof::<PoseidonHasher>(&as_default, &proof, &tree.root_hash()));
+    }
+
+    #[test]
+    fn aggregates_ignore_order_and_repeats() {
+        let (a, b) = ([1; 32], [2; 32]);
+        let both = AccountScope::aggregate(&[a, b]);
+        assert_eq!(both, AccountScope::aggregate(&[b, a, b]));
+        assert!(matches!(both, AccountScope::Aggregate(_)));
+        assert_ne!(both, AccountScope::aggregate(&[a, [3; 32]]));
+        assert_eq!(AccountScope::aggregate(&[a, a]), AccountScope::Subaccount(a));
+
+        let owner = principal(1);
+        assert_ne!(account_field(&owner, &both), account_field(&owner, &AccountScope::Subaccount(a)));
+        assert_eq!(account_field(&owner, &AccountScope::Default), poseidon::principal_to_field(&owner));
+    }
+
+    #[test]
+    fn subaccounts_do_not_collide_modulo_the_field() {
+        // 2^255 + x and x are the same field element once reduced as a whole
+        let mut high = [0u8; 32];
+        high[0] = 0x80;
+        high[31] = 1;
+        let mut low = [0u8; 32];
+        low[31] = 1;
+        let owner = principal(1);
+        assert_ne!(
+            account_field(&owner, &AccountScope::Subaccount(high)),
+            account_field(&owner, &AccountScope::Subaccount(low))
+        );
+    }
+}
@@ -0,0 +1,642 @@
+use crate::encoding::{self, ReferenceV2, ReferenceV3};
+use crate::memory::{self, Memory};
+use crate::merkle::{BalanceLeaf, BalanceTree};
+use crate::snapshot::SNAPSHOT_TREE;
+use crate::{Reference, StorableString, MERKLE_TREE, REFERENCES, TOKEN_PROOFS, VERIFICATION_RESULTS};
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Memory as _, StableBTreeMap, Storable};
+use std::thread::LocalKey;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Schema migrations, run from post_upgrade to bring stored data from the version
+// recorded in stable memory up to memory::CURRENT_SCHEMA_VERSION. Each migration
+// moves the data up exactly one version. A failing migration stops the run and
+// leaves the recorded version where it was, so it must be safe to run again.
+struct Migration {
+    from: u32,
+    name: &'static str,
+    run: fn() -> Result<(), String>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { from: 1, name: "references_json_to_candid", run: references_json_to_candid },
+    Migration { from: 2, name: "merkle_nodes_to_poseidon", run: merkle_nodes_to_poseidon },
+    Migration { from: 3, name: "leaves_with_account_scope", run: leaves_with_account_scope },
+    Migration { from: 4, name: "proof_results_to_candid", run: proof_results_to_candid },
+];
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MigrationFailure {
+    pub migration: String,
+    pub reason: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub applied: Vec<String>,
+    pub failure: Option<MigrationFailure>,
+}
+
+thread_local! {
+    static LAST_REPORT: RefCell<Option<MigrationReport>> = RefCell::new(None);
+}
+
+pub fn run() -> MigrationReport {
+    let from_version = memory::schema_version();
+    let mut report = MigrationReport {
+        from_version,
+        to_version: from_version,
+        applied: Vec::new(),
+        failure: None,
+    };
+
+    if from_version > memory::CURRENT_SCHEMA_VERSION {
+        report.failure = Some(MigrationFailure {
+            migration: String::new(),
+            reason: format!(
+                "Stored schema version {} is newer than this build ({})",
+                from_version,
+                memory::CURRENT_SCHEMA_VERSION
+            ),
+        });
+    }
+
+    while report.failure.is_none() && report.to_version < memory::CURRENT_SCHEMA_VERSION {
+        let version = report.to_version;
+        let Some(migration) = MIGRATIONS.iter().find(|m| m.from == version) else {
+            report.failure = Some(MigrationFailure {
+                migration: String::new(),
+                reason: format!("No migration registered from schema version {}", version),
+            });
+            break;
+        };
+
+        match (migration.run)() {
+            Ok(()) => {
+                memory::set_schema_version(version + 1);
+                report.to_version = version + 1;
+                report.applied.push(migration.name.to_string());
+            }
+            Err(reason) => {
+                report.failure = Some(MigrationFailure { migration: migration.name.to_string(), reason });
+            }
+        }
+    }
+
+    LAST_REPORT.with(|last| *last.borrow_mut() = Some(report.clone()));
+    report
+}
+
+// Outcome of the last run since this canister instance started
+pub fn last_report() -> Option<MigrationReport> {
+    LAST_REPORT.with(|last| last.borrow().clone())
+}
+
+// A record of a retired map, kept as raw bytes under the map's original bound
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Raw<const MAX_SIZE: u32>(Vec<u8>);
+
+impl<const MAX_SIZE: u32> Storable for Raw<MAX_SIZE> {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Raw(bytes.into_owned())
+    }
+}
+
+impl<const MAX_SIZE: u32> BoundedStorable for Raw<MAX_SIZE> {
+    const MAX_SIZE: u32 = MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A schema 1 reference: untagged serde_json, bounded at the old 8 KiB
+type LegacyReference = Raw<8192>;
+
+fn legacy_references() -> StableBTreeMap<StorableString, LegacyReference, Memory> {
+    StableBTreeMap::init(memory::get(memory::LEGACY_REFERENCES))
+}
+
+// Leaves and leaf keys of a schema 3 tree, bounded at 128 and 38 bytes
+fn legacy_leaves(id: MemoryId) -> StableBTreeMap<u32, Raw<128>, Memory> {
+    StableBTreeMap::init(memory::get(id))
+}
+
+fn legacy_leaf_index(id: MemoryId) -> StableBTreeMap<Raw<38>, u32, Memory> {
+    StableBTreeMap::init(memory::get(id))
+}
+
+// Proof results of schema <= 4, in maps created with the value bounds of the
+// baseline's unversioned layout (256 and 1024 bytes) or, from schema 2 on, of the
+// versioned records (1024 and 4096). A map must be read with the bound it was
+// created with: the B-tree lays out its nodes by the bound it is opened with, so a
+// smaller one misreads every entry but the first.
+fn legacy_proof_results<const MAX_SIZE: u32>(id: MemoryId) -> StableBTreeMap<StorableString, Raw<MAX_SIZE>, Memory> {
+    StableBTreeMap::init(memory::get(id))
+}
+
+// Value bound a map was created with, from its header (ic-stable-structures 0.5:
+// "BTR", layout version, then the key and value bounds as little-endian u32s).
+// None if the map was never created.
+fn created_value_bound(id: MemoryId) -> Option<u32> {
+    let memory = memory::get(id);
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut header = [0u8; 12];
+    memory.read(0, &mut header);
+    (&header[..3] == b"BTR").then(|| u32::from_le_bytes(header[8..12].try_into().unwrap()))
+}
+
+fn read_legacy_proof_results(id: MemoryId) -> Result<Vec<(StorableString, Vec<u8>)>, String> {
+    fn read<const MAX_SIZE: u32>(id: MemoryId) -> Vec<(StorableString, Vec<u8>)> {
+        legacy_proof_results::<MAX_SIZE>(id).iter().map(|(key, raw)| (key, raw.0)).collect()
+    }
+    match created_value_bound(id) {
+        None => Ok(Vec::new()),
+        Some(256) => Ok(read::<256>(id)),
+        Some(1024) => Ok(read::<1024>(id)),
+        Some(4096) => Ok(read::<4096>(id)),
+        Some(bound) => Err(format!("Memory {:?} holds a map bounded at {} bytes", id, bound)),
+    }
+}
+
+fn remove_legacy_proof_results(id: MemoryId, keys: &[StorableString]) {
+    fn remove<const MAX_SIZE: u32>(id: MemoryId, keys: &[StorableString]) {
+        let mut map = legacy_proof_results::<MAX_SIZE>(id);
+        for key in keys {
+            map.remove(key);
+        }
+    }
+    match created_value_bound(id) {
+        Some(256) => remove::<256>(id, keys),
+        Some(1024) => remove::<1024>(id, keys),
+        Some(4096) => remove::<4096>(id, keys),
+        _ => {}
+    }
+}
+
+fn read_legacy_leaves(id: MemoryId) -> Result<Vec<(u32, BalanceLeaf)>, String> {
+    legacy_leaves(id)
+        .iter()
+        .map(|(index, raw)| {
+            encoding::decode_balance_leaf(&raw.0)
+                .map(|leaf| (index, leaf))
+                .map_err(|e| format!("Leaf {}: {}", index, e))
+        })
+        .collect()
+}
+
+// 1 -> 2: move references out of the 8 KiB serde_json map into REFERENCES, which
+// stores versioned Candid (see encoding.rs)
+fn references_json_to_candid() -> Result<(), String> {
+    let mut legacy = legacy_references();
+
+    // Decode everything before writing anything, so a bad record leaves the
+    // schema 1 map untouched for the next attempt
+    let mut migrated = Vec::new();
+    for (id, raw) in legacy.iter() {
+        let reference: ReferenceV2 = serde_json::from_slice(&raw.0)
+            .map_err(|e| format!("Reference {}: {}", id.0, e))?;
+        migrated.push((id, Reference::from(ReferenceV3::from(reference))));
+    }
+
+    REFERENCES.with(|store| {
+        let mut store = store.borrow_mut();
+        for (id, reference) in &migrated {
+            store.insert(id.clone(), reference.clone());
+        }
+    });
+    for (id, _) in migrated {
+        legacy.remove(&id);
+    }
+    Ok(())
+}
+
+// 2 -> 3: the balance tree switched from SHA-256 to the circuit's Poseidon hashing.
+// Leaves are unchanged; every node is recomputed from them.
+fn merkle_nodes_to_poseidon() -> Result<(), String> {
+    let leaves = read_legacy_leaves(memory::LEGACY_MERKLE_LEAVES)?;
+    MERKLE_TREE.with(|tree| tree.borrow_mut().rehash_from(&leaves));
+    Ok(())
+}
+
+// 3 -> 4: leaves gained an account scope, which outgrew the bounds of their maps.
+// Both trees' leaves move to new maps as default-account leaves, whose hashes are
+// the same as before, so the nodes stay where they are.
+fn leaves_with_account_scope() -> Result<(), String> {
+    let trees: [(MemoryId, MemoryId, &'static LocalKey<RefCell<BalanceTree>>); 2] = [
+        (memory::LEGACY_MERKLE_LEAVES, memory::LEGACY_MERKLE_LEAF_INDEX, &MERKLE_TREE),
+        (memory::LEGACY_SNAPSHOT_LEAVES, memory::LEGACY_SNAPSHOT_LEAF_INDEX, &SNAPSHOT_TREE),
+    ];
+
+    // Decode everything before writing anything
+    let mut moved = Vec::new();
+    for (leaves_id, _, _) in &trees {
+        moved.push(read_legacy_leaves(*leaves_id)?);
+    }
+
+    for ((leaves_id, index_id, tree), leaves) in trees.into_iter().zip(moved) {
+        tree.with(|tree| {
+            let mut tree = tree.borrow_mut();
+            for (index, leaf) in leaves {
+                tree.restore_leaf(index, leaf);
+            }
+        });
+
+        let mut legacy = legacy_leaves(leaves_id);
+        let indexes: Vec<u32> = legacy.iter().map(|(index, _)| index).collect();
+        for index in indexes {
+            legacy.remove(&index);
+        }
+        let mut legacy_index = legacy_leaf_index(index_id);
+        let keys: Vec<Raw<38>> = legacy_index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            legacy_index.remove(&key);
+        }
+    }
+    Ok(())
+}
+
+// 4 -> 5: proof results were first stored unversioned (see encoding.rs), in maps
+// whose bounds the versioned records outgrew. Every record, in either layout, moves
+// to new maps as versioned Candid.
+fn proof_results_to_candid() -> Result<(), String> {
+    // Decode everything before writing anything
+    let mut verifications = Vec::new();
+    for (id, raw) in read_legacy_proof_results(memory::LEGACY_VERIFICATION_RESULTS)? {
+        let result = if encoding::is_legacy_layout(&raw) {
+            encoding::decode_legacy_verification_result(&raw)
+        } else {
+            encoding::decode_verification_result(&raw)
+        };
+        verifications.push((id.clone(), result.map_err(|e| format!("VerificationResult {}: {}", id.0, e))?));
+    }
+    let mut proofs = Vec::new();
+    for (id, raw) in read_legacy_proof_results(memory::LEGACY_TOKEN_PROOFS)? {
+        let result = if encoding::is_legacy_layout(&raw) {
+            encoding::decode_legacy_token_proof_result(&raw)
+        } else {
+            encoding::decode_token_proof_result(&raw)
+        };
+        proofs.push((id.clone(), result.map_err(|e| format!("TokenProofResult {}: {}", id.0, e))?));
+    }
+
+    VERIFICATION_RESULTS.with(|results| {
+        let mut results = results.borrow_mut();
+        for (id, result) in &verifications {
+            results.insert(id.clone(), result.clone());
+        }
+    });
+    TOKEN_PROOFS.with(|results| {
+        let mut results = results.borrow_mut();
+        for (id, result) in &proofs {
+            results.insert(id.clone(), result.clone());
+        }
+    });
+    let ids: Vec<StorableString> = verifications.into_iter().map(|(id, _)| id).collect();
+    remove_legacy_proof_results(memory::LEGACY_VERIFICATION_RESULTS, &ids);
+    let ids: Vec<StorableString> = proofs.into_iter().map(|(id, _)| id).collect();
+    remove_legacy_proof_results(memory::LEGACY_TOKEN_PROOFS, &ids);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::BalanceLeafV1;
+    use crate::merkle::{AccountScope, MerkleTree, Sha256Hasher};
+    use crate::disclosure::DisclosureLevel;
+    use crate::tasks::TaskStatus;
+    use crate::{Task, TaskConfig, TokenProofResult, VerificationResult};
+    use ic_cdk::export::Principal;
+    use serde_json::json;
+
+    fn insert_legacy(id: &str, value: serde_json::Value) {
+        let bytes = serde_json::to_vec(&value).unwrap();
+        legacy_references().insert(StorableString(id.to_string()), Raw(bytes));
+    }
+
+    fn stored_reference(id: &str) -> Option<Reference> {
+        REFERENCES.with(|store| store.borrow().get(&StorableString(id.to_string())))
+    }
+
+    #[test]
+    fn unrecorded_schema_is_the_baseline() {
+        // As a baseline canister leaves stable memory: data, but no schema version
+        insert_legacy("ref-baseline", json!({ "id": "ref-baseline", "tasks": [], "zk_proof": null }));
+
+        let report = run();
+        assert_eq!(report.from_version, memory::BASELINE_SCHEMA_VERSION);
+        assert_eq!(report.applied[0], "references_json_to_candid");
+        assert_eq!(report.failure, None);
+        assert!(stored_reference("ref-baseline").is_some());
+        assert!(legacy_references().is_empty());
+    }
+
+    #[test]
+    fn migrates_v1_references_and_tasks() {
+        memory::set_schema_version(1);
+        insert_legacy("ref-empty", json!({ "id": "ref-empty", "tasks": [], "zk_proof": null }));
+        insert_legacy("ref-tasks", json!({
+            "id": "ref-tasks",
+            "tasks": [
+                {
+                    "id": "task-1",
+                    "description": "prove balance",
+                    "status": "pending",
+                    "timestamp": 1_700_000_000_000_000_000u64,
+                    "config": {
+                        "priority": "high",
+                        "execution_delay": 30,
+                        "retry_attempts": 3,
+                        "disclosure_level": "anonymous",
+                        "storage_type": "stable"
+                    }
+                },
+                {
+                    "id": "task-2",
+                    "description": "no config",
+                    "status": "completed",
+                    "timestamp": 5,
+                    "config": null
+                }
+            ],
+            "zk_proof": "proof-bytes"
+        }));
+
+        let report = run();
+        assert_eq!(report.from_version, 1);
+        assert_eq!(report.to_version, memory::CURRENT_SCHEMA_VERSION);
+        assert_eq!(report.applied[0], "references_json_to_candid");
+        assert_eq!(report.failure, None);
+        assert_eq!(memory::schema_version(), memory::CURRENT_SCHEMA_VERSION);
+        assert_eq!(last_report(), Some(report));
+
+        assert_eq!(stored_reference("ref-empty"), Some(Reference {
+            id: "ref-empty".to_string(),
+            tasks: vec![],
+            zk_proof: None,
+            artifacts: vec![],
+        }));
+        assert_eq!(stored_reference("ref-tasks"), Some(Reference {
+            id: "ref-tasks".to_string(),
+            tasks: vec![
+                Task {
+                    id: "task-1".to_string(),
+                    description: "prove balance".to_string(),
+                    status: TaskStatus::Pending,
+                    timestamp: 1_700_000_000_000_000_000,
+                    config: Some(TaskConfig {
+                        priority: "high".to_string(),
+                        execution_delay: 30,
+                        retry_attempts: 3,
+                        disclosure_level: DisclosureLevel::Anonymous,
+                        storage_type: "stable".to_string(),
+                    }),
+                    claim: None,
+                    attempts: vec![],
+                },
+                Task {
+                    id: "task-2".to_string(),
+                    description: "no config".to_string(),
+                    status: TaskStatus::Succeeded,
+                    timestamp: 5,
+                    config: None,
+                    claim: None,
+                    attempts: vec![],
+                },
+            ],
+            zk_proof: Some("proof-bytes".to_string()),
+            artifacts: vec![],
+        }));
+        assert!(legacy_references().is_empty());
+
+        // Nothing left to do on the next upgrade
+        assert!(run().applied.is_empty());
+    }
+
+    // A schema 3 leaf and its index entry
+    fn insert_legacy_leaf(leaves_id: MemoryId, index_id: MemoryId, index: u32, owner: u8, balance: u64) -> BalanceLeaf {
+        let v1 = BalanceLeafV1 { principal: Principal::from_slice(&[owner; 29]), token_id: 1, balance };
+        legacy_leaves(leaves_id).insert(index, Raw(encoding::encode(1, &v1)));
+        let mut key = vec![29];
+        key.extend_from_slice(&[owner; 29]);
+        key.extend_from_slice(&1u64.to_be_bytes());
+        legacy_leaf_index(index_id).insert(Raw(key), index);
+        v1.into()
+    }
+
+    #[test]
+    fn rehashes_sha256_balance_tree() {
+        memory::set_schema_version(2);
+        let leaves = vec![
+            (0, insert_legacy_leaf(memory::LEGACY_MERKLE_LEAVES, memory::LEGACY_MERKLE_LEAF_INDEX, 0, 1, 10)),
+            (1, insert_legacy_leaf(memory::LEGACY_MERKLE_LEAVES, memory::LEGACY_MERKLE_LEAF_INDEX, 1, 2, 20)),
+        ];
+        let mut sha = MerkleTree::<Sha256Hasher>::init();
+        sha.rehash_from(&leaves);
+        let sha_root = sha.root_hash();
+
+        let report = run();
+        assert_eq!(report.applied[0], "merkle_nodes_to_poseidon");
+        assert_eq!(report.failure, None);
+
+        let mut expected = crate::merkle::BalanceTree::init();
+        let root = MERKLE_TREE.with(|tree| tree.borrow().root_hash());
+        assert_ne!(root, sha_root);
+        // Re-setting the same balances must not move the root
+        expected.set_balance(Principal::from_slice(&[1; 29]), 1, AccountScope::Default, 10).unwrap();
+        expected.set_balance(Principal::from_slice(&[2; 29]), 1, AccountScope::Default, 20).unwrap();
+        assert_eq!(expected.root_hash(), root);
+    }
+
+    #[test]
+    fn moves_leaves_to_scoped_maps() {
+        memory::set_schema_version(3);
+        let live = vec![
+            (0, insert_legacy_leaf(memory::LEGACY_MERKLE_LEAVES, memory::LEGACY_MERKLE_LEAF_INDEX, 0, 1, 10)),
+            (1, insert_legacy_leaf(memory::LEGACY_MERKLE_LEAVES, memory::LEGACY_MERKLE_LEAF_INDEX, 1, 2, 20)),
+        ];
+        let snapshot = vec![
+            (0, insert_legacy_leaf(memory::LEGACY_SNAPSHOT_LEAVES, memory::LEGACY_SNAPSHOT_LEAF_INDEX, 0, 3, 30)),
+        ];
+        // Schema 3 nodes, as hashed from the leaves without scope
+        MERKLE_TREE.with(|tree| tree.borrow_mut().rehash_from(&live));
+        SNAPSHOT_TREE.with(|tree| tree.borrow_mut().rehash_from(&snapshot));
+        let live_root = MERKLE_TREE.with(|tree| tree.borrow().root_hash());
+        let snapshot_root = SNAPSHOT_TREE.with(|tree| tree.borrow().root_hash());
+
+        let report = run();
+        assert_eq!(report.applied[0], "leaves_with_account_scope");
+        assert_eq!(report.failure, None);
+
+        MERKLE_TREE.with(|tree| {
+            let mut tree = tree.borrow_mut();
+            assert_eq!(tree.root_hash(), live_root);
+            assert_eq!(tree.leaf(1), Some(live[1].1.clone()));
+            let proof = tree.generate_proof(Principal::from_slice(&[2; 29]), 1, &AccountScope::Default);
+            assert_eq!(proof.map(|proof| proof.leaf_index), Some(1));
+            // An unchanged balance lands on the same leaf and leaves the root alone
+            assert_eq!(tree.set_balance(Principal::from_slice(&[1; 29]), 1, AccountScope::Default, 10), Ok(0));
+            assert_eq!(tree.root_hash(), live_root);
+        });
+        SNAPSHOT_TREE.with(|tree| {
+            let tree = tree.borrow();
+            assert_eq!(tree.root_hash(), snapshot_root);
+            assert_eq!(tree.balance(Principal::from_slice(&[3; 29]), 1, &AccountScope::Default), Some(30));
+        });
+
+        assert!(legacy_leaves(memory::LEGACY_MERKLE_LEAVES).is_empty());
+        assert!(legacy_leaf_index(memory::LEGACY_MERKLE_LEAF_INDEX).is_empty());
+        assert!(legacy_leaves(memory::LEGACY_SNAPSHOT_LEAVES).is_empty());
+        assert!(legacy_leaf_index(memory::LEGACY_SNAPSHOT_LEAF_INDEX).is_empty());
+    }
+
+    // Records as the unversioned Storable impls wrote them
+    fn baseline_verification(proof_id: &str, timestamp: u64) -> Vec<u8> {
+        let mut bytes = proof_id.as_bytes().to_vec();
+        bytes.push(1);
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        bytes
+    }
+
+    fn baseline_proof(proof_id: &str, timestamp: u64) -> Vec<u8> {
+        let mut bytes = proof_id.as_bytes().to_vec();
+        bytes.extend_from_slice(b"ryjl3-tyaaa-aaaaa-aaaba-cai");
+        bytes.extend_from_slice("ab".repeat(32).as_bytes());
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 2, 3]);
+        bytes.extend_from_slice(b"9e8f0a1b-2c3d-4e5f-4f9c-2d1e7a3b4c5d");
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        bytes.push(1);
+        bytes
+    }
+
+    fn proof_id(n: u64) -> String {
+        format!("4f9c2d1e-7a3b-4c5d-9e8f-{:012}", n)
+    }
+
+    fn key(id: &str) -> StorableString {
+        StorableString(id.to_string())
+    }
+
+    fn stored_verification(id: &str) -> Option<VerificationResult> {
+        VERIFICATION_RESULTS.with(|results| results.borrow().get(&key(id)))
+    }
+
+    fn stored_proof(id: &str) -> Option<TokenProofResult> {
+        TOKEN_PROOFS.with(|results| results.borrow().get(&key(id)))
+    }
+
+    #[test]
+    fn moves_baseline_proof_results() {
+        memory::set_schema_version(4);
+        // The baseline created both maps with its own bounds
+        let mut verifications = legacy_proof_results::<256>(memory::LEGACY_VERIFICATION_RESULTS);
+        let mut proofs = legacy_proof_results::<1024>(memory::LEGACY_TOKEN_PROOFS);
+        for n in 0..3 {
+            verifications.insert(key(&proof_id(n)), Raw(baseline_verification(&proof_id(n), n)));
+            proofs.insert(key(&proof_id(n)), Raw(baseline_proof(&proof_id(n), n)));
+        }
+
+        let report = run();
+        assert_eq!(report.applied[0], "proof_results_to_candid");
+        assert_eq!(report.failure, None);
+
+        for n in 0..3 {
+            assert_eq!(stored_verification(&proof_id(n)), Some(VerificationResult {
+                is_verified: true,
+                proof_id: proof_id(n),
+                timestamp: n,
+                anonymous_reference: String::new(),
+            }));
+            assert_eq!(stored_proof(&proof_id(n)), Some(TokenProofResult {
+                proof_id: proof_id(n),
+                token_id: "ryjl3-tyaaa-aaaaa-aaaba-cai".to_string(),
+                token_symbol: None,
+                merkle_root: "ab".repeat(32),
+                proof_data: vec![1, 2, 3],
+                anonymous_reference: "9e8f0a1b-2c3d-4e5f-4f9c-2d1e7a3b4c5d".to_string(),
+                timestamp: n,
+                is_valid: true,
+            }));
+        }
+        assert!(legacy_proof_results::<256>(memory::LEGACY_VERIFICATION_RESULTS).is_empty());
+        assert!(legacy_proof_results::<1024>(memory::LEGACY_TOKEN_PROOFS).is_empty());
+    }
+
+    #[test]
+    fn moves_versioned_proof_results() {
+        memory::set_schema_version(4);
+        // Schemas 2 to 4 created both maps with the bounds of the versioned records
+        let mut verifications = legacy_proof_results::<1024>(memory::LEGACY_VERIFICATION_RESULTS);
+        let mut proofs = legacy_proof_results::<4096>(memory::LEGACY_TOKEN_PROOFS);
+        let verification = |n: u64| VerificationResult {
+            is_verified: n % 2 == 0,
+            proof_id: proof_id(n),
+            timestamp: n,
+            anonymous_reference: "reference".to_string(),
+        };
+        let proof = |n: u64| TokenProofResult {
+            proof_id: proof_id(n),
+            token_id: "mxzaz-hqaaa-aaaar-qaada-cai".to_string(),
+            token_symbol: Some("ckBTC".to_string()),
+            merkle_root: "cd".repeat(32),
+            proof_data: vec![n as u8; 1500],
+            anonymous_reference: "reference".to_string(),
+            timestamp: n,
+            is_valid: true,
+        };
+        for n in 0..3 {
+            verifications.insert(key(&proof_id(n)), Raw(verification(n).to_bytes().into_owned()));
+            proofs.insert(key(&proof_id(n)), Raw(proof(n).to_bytes().into_owned()));
+        }
+        // A record left in the unversioned layout by a baseline write
+        proofs.insert(key(&proof_id(3)), Raw(baseline_proof(&proof_id(3), 3)));
+
+        let report = run();
+        assert_eq!(report.applied[0], "proof_results_to_candid");
+        assert_eq!(report.failure, None);
+
+        for n in 0..3 {
+            assert_eq!(stored_verification(&proof_id(n)), Some(verification(n)));
+            assert_eq!(stored_proof(&proof_id(n)), Some(proof(n)));
+        }
+        assert_eq!(stored_proof(&proof_id(3)).map(|result| result.timestamp), Some(3));
+        assert!(legacy_proof_results::<1024>(memory::LEGACY_VERIFICATION_RESULTS).is_empty());
+        assert!(legacy_proof_results::<4096>(memory::LEGACY_TOKEN_PROOFS).is_empty());
+    }
+
+    #[test]
+    fn reports_failures_without_trapping() {
+        memory::set_schema_version(1);
+        insert_legacy("ref-ok", json!({ "id": "ref-ok", "tasks": [], "zk_proof": null }));
+        insert_legacy("ref-bad", json!({ "id": "ref-bad", "tasks": "not a list" }));
+
+        let report = run();
+        assert_eq!(report.to_version, 1);
+        assert!(report.applied.is_empty());
+        let failure = report.failure.unwrap();
+        assert_eq!(failure.migration, "references_json_to_candid");
+        assert!(failure.reason.starts_with("Reference ref-bad"));
+
+        // Schema 1 data is left in place for a retry
+        assert_eq!(memory::schema_version(), 1);
+        assert_eq!(legacy_references().len(), 2);
+        assert_eq!(stored_reference("ref-ok"), None);
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        memory::set_schema_version(memory::CURRENT_SCHEMA_VERSION + 1);
+        let report = run();
+        assert!(report.failure.is_some());
+        assert_eq!(memory::schema_version(), memory::CURRENT_SCHEMA_VERSION + 1);
+    }
+}
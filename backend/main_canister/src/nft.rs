@@ -0,0 +1,265 @@
+use crate::evm;
+use candid::{CandidType, Deserialize, Nat, Reserved};
+use ic_cdk::api::call::call;
+use ic_cdk::export::Principal;
+use serde::Serialize;
+use sha2::{Digest, Sha224};
+
+// NFT ownership checks. On the IC a collection is read through ICRC-7
+// (icrc7_owner_of, icrc7_balance_of) or EXT (bearer), on EVM chains through ERC-721
+// or ERC-1155; which one is discovered from the collection itself.
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum NftError {
+    UnsupportedChain { chain_id: String },
+    UnsupportedCollection { collection: String, reason: String },
+    InvalidRequest(String),
+    Unauthorized(String),
+    CallFailed { method: String, reason: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Chain {
+    InternetComputer,
+    // EIP-155 chain id
+    Evm(u64),
+}
+
+impl Chain {
+    pub fn from_id(chain_id: &str) -> Result<Chain, NftError> {
+        let unsupported = || NftError::UnsupportedChain { chain_id: chain_id.to_string() };
+        match chain_id.to_ascii_lowercase().as_str() {
+            "icp" | "ic" => Ok(Chain::InternetComputer),
+            "eth" | "ethereum" => Ok(Chain::Evm(1)),
+            "sepolia" => Ok(Chain::Evm(11155111)),
+            id => match id.parse() {
+                Ok(id) if evm::is_supported_chain(id) => Ok(Chain::Evm(id)),
+                _ => Err(unsupported()),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NftStandard {
+    ICRC7,
+    EXT,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvmNftStandard {
+    ERC721,
+    ERC1155,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Icrc7Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct SupportedStandard {
+    name: String,
+}
+
+#[derive(CandidType, Deserialize)]
+enum CommonError {
+    InvalidToken(Reserved),
+    Other(String),
+}
+
+#[derive(CandidType, Deserialize)]
+enum BearerResult {
+    #[serde(rename = "ok")]
+    Ok(String),
+    #[serde(rename = "err")]
+    Err(CommonError),
+}
+
+fn call_failed(method: &str, reason: String) -> NftError {
+    NftError::CallFailed { method: method.to_string(), reason }
+}
+
+// ICRC-7 collections list themselves through ICRC-10; EXT ones through their
+// extensions
+pub async fn detect_standard(collection: Principal) -> Result<NftStandard, NftError> {
+    let standards: Result<(Vec<SupportedStandard>,), _> =
+        call(collection, "icrc10_supported_standards", ()).await;
+    if let Ok((standards,)) = standards {
+        if standards.iter().any(|standard| standard.name == "ICRC-7") {
+            return Ok(NftStandard::ICRC7);
+        }
+    }
+
+    let extensions: Result<(Vec<String>,), _> = call(collection, "extensions", ()).await;
+    if let Ok((extensions,)) = extensions {
+        if extensions.iter().any(|extension| extension == "@ext/nonfungible") {
+            return Ok(NftStandard::EXT);
+        }
+    }
+
+    Err(NftError::UnsupportedCollection {
+        collection: collection.to_text(),
+        reason: "implements neither ICRC-7 nor EXT non-fungible tokens".to_string(),
+    })
+}
+
+// ICRC-7 accounts, like ICRC-1 ones, treat an all-zero subaccount as the default
+fn is_default_account_of(account: &Icrc7Account, owner: Principal) -> bool {
+    account.owner == owner
+        && account.subaccount.as_ref().map_or(true, |subaccount| subaccount.iter().all(|byte| *byte == 0))
+}
+
+// Whether the default account of `owner` holds `token_id` of an ICRC-7 collection,
+// or any of its tokens when `token_id` is absent
+pub async fn icrc7_owns(collection: Principal, owner: Principal, token_id: Option<&str>) -> Result<bool, NftError> {
+    match token_id {
+        Some(token_id) => {
+            let token_id = parse_token_id(token_id)?;
+            let (owners,): (Vec<Option<Icrc7Account>>,) = call(collection, "icrc7_owner_of", (vec![token_id],))
+                .await
+                .map_err(|(_, msg)| call_failed("icrc7_owner_of", msg))?;
+            Ok(matches!(owners.first(), Some(Some(holder)) if is_default_account_of(holder, owner)))
+        }
+        None => {
+            let account = Icrc7Account { owner, subaccount: None };
+            let (balances,): (Vec<Nat>,) = call(collection, "icrc7_balance_of", (vec![account],))
+                .await
+                .map_err(|(_, msg)| call_failed("icrc7_balance_of", msg))?;
+            Ok(balances.first().map_or(false, |balance| *balance > Nat::from(0u64)))
+        }
+    }
+}
+
+// Hex account identifier of `principal`'s default account, as EXT reports bearers:
+// CRC-32 of the SHA-224 hash followed by the hash
+pub fn account_identifier(principal: &Principal) -> String {
+    let hash = Sha224::new()
+        .chain_update(b"\x0Aaccount-id")
+        .chain_update(principal.as_slice())
+        .chain_update([0u8; 32])
+        .finalize();
+    let mut bytes = crc32(&hash).to_be_bytes().to_vec();
+    bytes.extend_from_slice(&hash);
+    hex::encode(bytes)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// EXT has no per-owner balance, so only single tokens can be checked
+pub async fn ext_owns(collection: Principal, owner: Principal, token_id: Option<&str>) -> Result<bool, NftError> {
+    let token_id = token_id
+        .ok_or_else(|| NftError::InvalidRequest("EXT collections are checked per token; token_id is required".to_string()))?;
+    let (bearer,): (BearerResult,) = call(collection, "bearer", (token_id.to_string(),))
+        .await
+        .map_err(|(_, msg)| call_failed("bearer", msg))?;
+    match bearer {
+        BearerResult::Ok(account) => Ok(account.eq_ignore_ascii_case(&account_identifier(&owner))),
+        BearerResult::Err(CommonError::InvalidToken(_)) => Ok(false),
+        BearerResult::Err(CommonError::Other(reason)) => Err(call_failed("bearer", reason)),
+    }
+}
+
+fn parse_token_id(token_id: &str) -> Result<Nat, NftError> {
+    token_id.parse()
+        .map_err(|_| NftError::InvalidRequest(format!("Token id {} is not a number", token_id)))
+}
+
+fn evm_failed(method: &str) -> impl Fn(String) -> NftError + '_ {
+    move |reason| call_failed(method, reason)
+}
+
+// ERC-165 tells the two EVM standards apart
+pub async fn detect_evm_standard(chain_id: u64, collection: &[u8; 20]) -> Result<EvmNftStandard, NftError> {
+    if evm::supports_interface(chain_id, collection, evm::ERC721_INTERFACE).await.map_err(evm_failed("supportsInterface"))? {
+        return Ok(EvmNftStandard::ERC721);
+    }
+    if evm::supports_interface(chain_id, collection, evm::ERC1155_INTERFACE).await.map_err(evm_failed("supportsInterface"))? {
+        return Ok(EvmNftStandard::ERC1155);
+    }
+    Err(NftError::UnsupportedCollection {
+        collection: format!("0x{}", hex::encode(collection)),
+        reason: "implements neither ERC-721 nor ERC-1155".to_string(),
+    })
+}
+
+// Whether `owner` holds the NFT (or, without a token id, any NFT) of an IC collection
+pub async fn ic_owns(collection: &str, owner: Principal, token_id: Option<&str>) -> Result<bool, NftError> {
+    let collection = Principal::from_text(collection)
+        .map_err(|e| NftError::InvalidRequest(format!("Invalid collection canister id: {}", e)))?;
+    match detect_standard(collection).await? {
+        NftStandard::ICRC7 => icrc7_owns(collection, owner, token_id).await,
+        NftStandard::EXT => ext_owns(collection, owner, token_id).await,
+    }
+}
+
+// Whether the EVM address `owner` holds the NFT of an ERC-721 or ERC-1155 contract.
+// ERC-721 contracts can also be asked whether it holds any of their tokens.
+pub async fn evm_owns(chain_id: u64, collection: &str, owner: &[u8; 20], token_id: Option<&str>) -> Result<bool, NftError> {
+    let collection = evm::parse_address(collection).map_err(NftError::InvalidRequest)?;
+    let token_id = token_id.map(parse_token_id).transpose()?;
+    let zero = Nat::from(0u64);
+    match (detect_evm_standard(chain_id, &collection).await?, token_id) {
+        (EvmNftStandard::ERC721, Some(token_id)) => {
+            let holder = evm::erc721_owner(chain_id, &collection, &token_id).await.map_err(evm_failed("ownerOf"))?;
+            Ok(holder.as_ref() == Some(owner))
+        }
+        (EvmNftStandard::ERC721, None) => {
+            let balance = evm::erc721_balance(chain_id, &collection, owner).await.map_err(evm_failed("balanceOf"))?;
+            Ok(balance > zero)
+        }
+        (EvmNftStandard::ERC1155, Some(token_id)) => {
+            let balance = evm::erc1155_balance(chain_id, &collection, owner, &token_id).await.map_err(evm_failed("balanceOf"))?;
+            Ok(balance > zero)
+        }
+        (EvmNftStandard::ERC1155, None) => Err(NftError::InvalidRequest(
+            "ERC-1155 balances are per token; token_id is required".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_by_id() {
+        assert_eq!(Chain::from_id("icp"), Ok(Chain::InternetComputer));
+        assert_eq!(Chain::from_id("IC"), Ok(Chain::InternetComputer));
+        assert_eq!(Chain::from_id("eth"), Ok(Chain::Evm(1)));
+        assert_eq!(Chain::from_id("8453"), Ok(Chain::Evm(8453)));
+        assert!(Chain::from_id("56").is_err());
+        assert_eq!(
+            Chain::from_id("solana"),
+            Err(NftError::UnsupportedChain { chain_id: "solana".to_string() })
+        );
+    }
+
+    #[test]
+    fn account_identifiers_match_the_ledger() {
+        assert_eq!(
+            account_identifier(&Principal::anonymous()),
+            "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79"
+        );
+    }
+
+    #[test]
+    fn zero_subaccount_is_the_default_account() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let account = |owner, subaccount| Icrc7Account { owner, subaccount };
+        assert!(is_default_account_of(&account(owner, None), owner));
+        assert!(is_default_account_of(&account(owner, Some(vec![0; 32])), owner));
+        assert!(!is_default_account_of(&account(owner, Some(vec![1; 32])), owner));
+        assert!(!is_default_account_of(&account(Principal::anonymous(), None), owner));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::merkle::{self, AccountScope, BalanceTree, TREE_DEPTH};
+use crate::poseidon;
+use candid::{CandidType, Deserialize};
+use ic_cdk::export::Principal;
+use serde::Serialize;
+
+// Mirrors of the zk_canister's prove_ownership types (zk_canister.did). Field
+// elements are 32-byte big-endian BN254 scalars.
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum TokenStandard {
+    ERC20,
+    ERC721,
+    ERC1155,
+    ICRC1,
+    ICRC2,
+    ICP,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TokenMetadata {
+    pub canister_id: String,
+    pub token_standard: TokenStandard,
+    pub decimals: Option<u8>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TokenOwnershipInput {
+    pub token_metadata: TokenMetadata,
+    pub token_id: Vec<u8>,
+    pub balance: Vec<u8>,
+    pub min_balance: u64,
+    pub merkle_root: Vec<u8>,
+    pub tree_depth: u8,
+    pub owner_hash: Vec<u8>,
+    pub merkle_path: Vec<Vec<u8>>,
+    pub path_indices: Vec<u8>,
+    pub token_specific_data: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum KeyError {
+    NotAuthorized,
+    InvalidCircuitName(String),
+    InvalidKey(String),
+    UnknownKey(String),
+    InactiveKey(String),
+    RetiredKey(String),
+    MissingProvingKey(String),
+    SetupFailed(String),
+    LastAdmin,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ProofError {
+    Key(KeyError),
+    InvalidInput(String),
+    ProvingFailed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PublicInputs {
+    pub token_id: Vec<u8>,
+    pub min_balance: u64,
+    pub merkle_root: Vec<u8>,
+    pub tree_depth: u8,
+}
+
+// The Candid-encoded envelope prove_ownership returns and verify_proof accepts
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct OwnershipProof {
+    pub param_id: String,
+    pub public_inputs: PublicInputs,
+    pub proof: Vec<u8>,
+}
+
+// What verify_statement reports: the key and statement a proof was checked against
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct VerificationOutcome {
+    pub vk_id: String,
+    pub public_inputs: PublicInputs,
+    pub is_valid: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum VerificationError {
+    Key(KeyError),
+    InvalidPublicInputs(String),
+    MalformedProof(String),
+}
+
+// Circuit input for the leaf of (principal, token_id, scope): the leaf's full sibling
+// hashes and direction bits (1 = the path node is the right child) for every one of
+// the TREE_DEPTH levels, checked against the tree's current root.
+pub fn ownership_input(
+    tree: &BalanceTree,
+    principal: Principal,
+    token_id: u64,
+    scope: &AccountScope,
+    min_balance: u64,
+    token_metadata: TokenMetadata,
+) -> Result<TokenOwnershipInput, String> {
+    let index = tree.leaf_index(principal, token_id, scope)
+        .ok_or_else(|| "No balance recorded for this wallet and token".to_string())?;
+    let leaf = tree.leaf(index).expect("indexed leaf exists");
+    let proof = tree.generate_proof(principal, token_id, scope).expect("indexed leaf has a path");
+
+    Ok(TokenOwnershipInput {
+        token_metadata,
+        token_id: token_id.to_be_bytes().to_vec(),
+        balance: leaf.balance.to_be_bytes().to_vec(),
+        min_balance,
+        merkle_root: tree.root_hash().to_vec(),
+        tree_depth: TREE_DEPTH as u8,
+        owner_hash: poseidon::field_to_bytes(merkle::account_field(&principal, scope)).to_vec(),
+        merkle_path: proof.siblings,
+        path_indices: proof.path_indices.iter().map(|&bit| bit as u8).collect(),
+        token_specific_data: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_bn254::Fr;
+    use ark_ff::PrimeField;
+
+    fn metadata() -> TokenMetadata {
+        TokenMetadata {
+            canister_id: "ryjl3-tyaaa-aaaaa-aaaba-cai".to_string(),
+            token_standard: TokenStandard::ICP,
+            decimals: Some(8),
+        }
+    }
+
+    fn field(bytes: &[u8]) -> Fr {
+        poseidon::field_from_bytes(bytes).expect("canonical 32-byte field element")
+    }
+
+    // What circuits/src/main.nr computes from the input
+    fn circuit_root(input: &TokenOwnershipInput) -> Fr {
+        let leaf = poseidon::hash_3([
+            field(&input.owner_hash),
+            Fr::from_be_bytes_mod_order(&input.token_id),
+            Fr::from_be_bytes_mod_order(&input.balance),
+        ]);
+        input.merkle_path.iter()
+            .zip(&input.path_indices)
+            .take(input.tree_depth as usize)
+            .fold(leaf, |current, (sibling, &bit)| match bit {
+                0 => poseidon::hash_2([current, field(sibling)]),
+                1 => poseidon::hash_2([field(sibling), current]),
+                _ => panic!("path index must be 0 or 1"),
+            })
+    }
+
+    #[test]
+    fn canister_path_recomputes_published_root() {
+        let mut tree = BalanceTree::init();
+        let wallets: Vec<Principal> = (1..=6u8).map(|n| Principal::from_slice(&[n; 10])).collect();
+        for (n, wallet) in wallets.iter().enumerate() {
+            tree.set_balance(*wallet, 1, AccountScope::Default, 1_000 * (n as u64 + 1)).unwrap();
+        }
+
+        for (n, wallet) in wallets.iter().enumerate() {
+            let input = ownership_input(&tree, *wallet, 1, &AccountScope::Default, 500, metadata()).unwrap();
+
+            assert_eq!(input.merkle_path.len(), TREE_DEPTH);
+            assert!(input.merkle_path.iter().all(|node| node.len() == 32));
+            assert_eq!(input.path_indices.len(), TREE_DEPTH);
+            // Direction bits are the bits of this wallet's own leaf index
+            let bits: Vec<u8> = (0..TREE_DEPTH).map(|level| ((n >> level) & 1) as u8).collect();
+            assert_eq!(input.path_indices, bits);
+
+            assert_eq!(poseidon::field_to_bytes(circuit_root(&input)), tree.root_hash());
+            assert_eq!(input.merkle_root, tree.root_hash().to_vec());
+        }
+    }
+
+    #[test]
+    fn requires_a_recorded_balance() {
+        let tree = BalanceTree::init();
+        let wallet = Principal::from_slice(&[9; 10]);
+        assert!(ownership_input(&tree, wallet, 1, &AccountScope::Default, 1, metadata()).is_err());
+    }
+
+    #[test]
+    fn subaccount_leaves_recompute_the_root() {
+        let mut tree = BalanceTree::init();
+        let wallet = Principal::from_slice(&[3; 10]);
+        let scopes = [AccountScope::Default, AccountScope::of(Some([8; 32])), AccountScope::aggregate(&[[1; 32], [2; 32]])];
+        for (n, scope) in scopes.iter().enumerate() {
+            tree.set_balance(wallet, 1, scope.clone(), 100 + n as u64).unwrap();
+        }
+
+        for scope in &scopes {
+            let input = ownership_input(&tree, wallet, 1, scope, 50, metadata()).unwrap();
+            assert_eq!(input.owner_hash, poseidon::field_to_bytes(merkle::account_field(&wallet, scope)).to_vec());
+            assert_eq!(poseidon::field_to_bytes(circuit_root(&input)), tree.root_hash());
+        }
+    }
+}
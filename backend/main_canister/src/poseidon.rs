@@ -0,0 +1,122 @@
+use ark_bn254::Fr;
+use ark_ff::{BigInteger, PrimeField};
+use ic_cdk::export::Principal;
+use light_poseidon::{Poseidon, PoseidonHasher};
+
+// Poseidon over BN254 with the circom/Noir parameters, matching
+// `std::hash::poseidon::bn254::hash_2` / `hash_3` in circuits/src/main.nr.
+// Field elements cross the canister boundary as 32-byte big-endian integers.
+
+pub fn hash_2(inputs: [Fr; 2]) -> Fr {
+    hash(&inputs)
+}
+
+pub fn hash_3(inputs: [Fr; 3]) -> Fr {
+    hash(&inputs)
+}
+
+fn hash(inputs: &[Fr]) -> Fr {
+    Poseidon::<Fr>::new_circom(inputs.len())
+        .and_then(|mut poseidon| poseidon.hash(inputs))
+        .expect("supported Poseidon width")
+}
+
+pub fn field_to_bytes(value: Fr) -> [u8; 32] {
+    value.into_bigint().to_bytes_be().try_into().unwrap()
+}
+
+// Rejects encodings that are not the canonical form of a field element
+pub fn field_from_bytes(bytes: &[u8]) -> Option<Fr> {
+    if bytes.len() != 32 {
+        return None;
+    }
+    let value = Fr::from_be_bytes_mod_order(bytes);
+    (field_to_bytes(value).as_slice() == bytes).then_some(value)
+}
+
+// The circuit's `wallet` input. A principal is at most 29 bytes, so its length
+// byte followed by the bytes fits in a field element without reduction, and
+// principals of different lengths cannot collide.
+pub fn principal_to_field(principal: &Principal) -> Fr {
+    let bytes = principal.as_slice();
+    let mut encoded = vec![bytes.len() as u8];
+    encoded.extend_from_slice(bytes);
+    Fr::from_be_bytes_mod_order(&encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::str::FromStr;
+
+    // Shared with the Noir tests in circuits/src/main.nr
+    const VECTORS: &str = include_str!("../../../circuits/test_vectors/poseidon.json");
+
+    #[derive(Deserialize)]
+    struct HashVector {
+        inputs: Vec<String>,
+        output: String,
+    }
+
+    #[derive(Deserialize)]
+    struct MerkleVector {
+        wallet: String,
+        token: String,
+        balance: String,
+        path: Vec<String>,
+        path_indices: Vec<u8>,
+        root: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Vectors {
+        hash_2: Vec<HashVector>,
+        hash_3: Vec<HashVector>,
+        merkle: Vec<MerkleVector>,
+    }
+
+    fn fr(value: &str) -> Fr {
+        Fr::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn matches_circuit_vectors() {
+        let vectors: Vectors = serde_json::from_str(VECTORS).unwrap();
+
+        for vector in &vectors.hash_2 {
+            let inputs = [fr(&vector.inputs[0]), fr(&vector.inputs[1])];
+            assert_eq!(hash_2(inputs), fr(&vector.output));
+        }
+        for vector in &vectors.hash_3 {
+            let inputs = [fr(&vector.inputs[0]), fr(&vector.inputs[1]), fr(&vector.inputs[2])];
+            assert_eq!(hash_3(inputs), fr(&vector.output));
+        }
+        for vector in &vectors.merkle {
+            let leaf = hash_3([fr(&vector.wallet), fr(&vector.token), fr(&vector.balance)]);
+            let root = vector.path.iter().zip(&vector.path_indices).fold(leaf, |current, (sibling, &bit)| {
+                if bit == 0 {
+                    hash_2([current, fr(sibling)])
+                } else {
+                    hash_2([fr(sibling), current])
+                }
+            });
+            assert_eq!(root, fr(&vector.root));
+        }
+    }
+
+    #[test]
+    fn field_bytes_are_canonical() {
+        let value = fr("12274320878848963964457490571575532061525748054681790244418032067398540298439");
+        assert_eq!(field_from_bytes(&field_to_bytes(value)), Some(value));
+        assert_eq!(field_from_bytes(&[0xff; 32]), None);
+        assert_eq!(field_from_bytes(&[1; 31]), None);
+    }
+
+    #[test]
+    fn principals_of_different_lengths_differ() {
+        let short = Principal::from_slice(&[1]);
+        let padded = Principal::from_slice(&[0, 1]);
+        assert_ne!(principal_to_field(&short), principal_to_field(&padded));
+    }
+}
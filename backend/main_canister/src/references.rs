@@ -0,0 +1,110 @@
+use crate::disclosure::Artifact;
+use crate::tasks;
+use crate::{StorableString, Task, REFERENCES};
+
+// Writes to a stored reference while its tasks run. Task execution awaits between
+// reading a task and recording its outcome, so the reference may change meanwhile;
+// every write reads the stored reference again and finds the task by id, rather
+// than writing back a copy taken before the await.
+
+// Apply `change` to a stored task and write the reference back
+pub fn update_task(reference_id: &str, task_id: &str, change: impl FnOnce(&mut Task) -> Result<(), String>) -> Result<Task, String> {
+    REFERENCES.with(|store| {
+        let mut store = store.borrow_mut();
+        let key = StorableString(reference_id.to_string());
+        let mut reference = store.get(&key).ok_or("No such reference")?;
+        let task = reference.tasks.iter_mut()
+            .find(|task| task.id == task_id)
+            .ok_or("No such task")?;
+        change(task)?;
+        let task = task.clone();
+        store.insert(key, reference);
+        Ok(task)
+    })
+}
+
+// Replace the artifact of the task it was cut for
+pub fn store_artifact(reference_id: &str, artifact: Artifact) -> Result<(), String> {
+    if tasks::encoded_len(&artifact) > tasks::MAX_ARTIFACT_SIZE {
+        return Err(format!("Artifact exceeds {} bytes", tasks::MAX_ARTIFACT_SIZE));
+    }
+    REFERENCES.with(|store| {
+        let mut store = store.borrow_mut();
+        let key = StorableString(reference_id.to_string());
+        if let Some(mut reference) = store.get(&key) {
+            reference.artifacts.retain(|existing| existing.task_id != artifact.task_id);
+            reference.artifacts.push(artifact);
+            store.insert(key, reference);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tasks::{TaskAttempt, TaskStatus};
+    use crate::Reference;
+
+    fn task(id: &str) -> Task {
+        Task {
+            id: id.to_string(),
+            description: String::new(),
+            status: TaskStatus::Pending,
+            timestamp: 0,
+            config: None,
+            claim: None,
+            attempts: vec![],
+        }
+    }
+
+    fn stored_tasks(reference_id: &str) -> Option<Vec<Task>> {
+        REFERENCES.with(|store| store.borrow().get(&StorableString(reference_id.to_string())).map(|r| r.tasks))
+    }
+
+    #[test]
+    fn task_changes_are_written_back_to_the_reference() {
+        let reference = Reference { id: "ref".to_string(), tasks: vec![task("a"), task("b")], zk_proof: None, artifacts: vec![] };
+        REFERENCES.with(|store| store.borrow_mut().insert(StorableString("ref".to_string()), reference));
+
+        update_task("ref", "b", |task| tasks::transition(task, TaskStatus::Running, 1)).unwrap();
+        let failed = TaskStatus::Failed { reason: "ledger unavailable".to_string() };
+        update_task("ref", "b", |task| tasks::transition(task, failed.clone(), 2)).unwrap();
+
+        let stored = stored_tasks("ref").unwrap();
+        assert_eq!(stored[0].status, TaskStatus::Pending);
+        assert_eq!(stored[1].status, failed);
+        assert_eq!(stored[1].attempts, [TaskAttempt {
+            started_at: 1,
+            finished_at: Some(2),
+            error: Some("ledger unavailable".to_string()),
+        }]);
+
+        // A rejected change leaves the stored task as it was
+        assert!(update_task("ref", "b", |task| tasks::transition(task, TaskStatus::Running, 3)).is_err());
+        assert_eq!(stored_tasks("ref").unwrap(), stored);
+        assert!(update_task("ref", "c", |_| Ok(())).is_err());
+        assert!(update_task("other", "a", |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn changes_made_while_a_task_runs_are_kept() {
+        let reference = Reference { id: "ref".to_string(), tasks: vec![task("a")], zk_proof: None, artifacts: vec![] };
+        REFERENCES.with(|store| store.borrow_mut().insert(StorableString("ref".to_string()), reference));
+        update_task("ref", "a", |task| tasks::transition(task, TaskStatus::Running, 1)).unwrap();
+
+        // A task is added while "a" is in flight; recording "a" must not drop it
+        REFERENCES.with(|store| {
+            let mut store = store.borrow_mut();
+            let key = StorableString("ref".to_string());
+            let mut reference = store.get(&key).unwrap();
+            reference.tasks.push(task("b"));
+            store.insert(key, reference);
+        });
+        update_task("ref", "a", |task| tasks::transition(task, TaskStatus::Succeeded, 2)).unwrap();
+
+        let stored = stored_tasks("ref").unwrap();
+        assert_eq!(stored.iter().map(|task| task.id.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(stored[0].status, TaskStatus::Succeeded);
+    }
+}
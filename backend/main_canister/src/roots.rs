@@ -0,0 +1,168 @@
+use crate::memory::{self, Memory};
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use serde::Serialize;
+use std::cell::RefCell;
+
+// Published roots of the balance tree. Every root a proof may have been generated
+// against is kept with its epoch, so outstanding proofs stay verifiable after the
+// tree moves on, for as long as their root is inside the accepted window.
+
+// Oldest epochs are dropped beyond this many entries
+pub const ROOT_HISTORY_CAPACITY: u64 = 256;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RootRecord {
+    pub epoch: u64,
+    pub root: Vec<u8>,
+    pub published_at: u64,
+}
+
+// A root is accepted while fewer than `max_epochs` newer roots have been published
+// and it is at most `max_age_ns` old
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct RootWindow {
+    pub max_epochs: u64,
+    pub max_age_ns: u64,
+}
+
+pub const DEFAULT_ROOT_WINDOW: RootWindow = RootWindow {
+    max_epochs: 16,
+    max_age_ns: 24 * 60 * 60 * 1_000_000_000,
+};
+
+thread_local! {
+    static ROOT_HISTORY: RefCell<StableBTreeMap<u64, RootRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::ROOT_HISTORY)));
+
+    static ROOT_WINDOW: RefCell<StableCell<RootWindow, Memory>> =
+        RefCell::new(StableCell::init(memory::get(memory::ROOT_WINDOW), DEFAULT_ROOT_WINDOW)
+            .expect("failed to initialize the root window"));
+}
+
+pub fn latest() -> Option<RootRecord> {
+    // At most ROOT_HISTORY_CAPACITY entries, so a scan is cheap
+    ROOT_HISTORY.with(|history| history.borrow().iter().last().map(|(_, record)| record))
+}
+
+// Record `root` as the next epoch unless it is already the latest one. A root seen
+// in an older epoch is recorded again, so the latest epoch is always the live root.
+pub fn publish(root: &[u8], now: u64) -> RootRecord {
+    if let Some(latest) = latest().filter(|latest| latest.root == root) {
+        return latest;
+    }
+
+    let record = RootRecord {
+        epoch: latest().map(|latest| latest.epoch + 1).unwrap_or(1),
+        root: root.to_vec(),
+        published_at: now,
+    };
+    ROOT_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        history.insert(record.epoch, record.clone());
+        if record.epoch > ROOT_HISTORY_CAPACITY {
+            history.remove(&(record.epoch - ROOT_HISTORY_CAPACITY));
+        }
+    });
+    record
+}
+
+// Newest first
+pub fn list() -> Vec<RootRecord> {
+    let mut records: Vec<RootRecord> =
+        ROOT_HISTORY.with(|history| history.borrow().iter().map(|(_, record)| record).collect());
+    records.reverse();
+    records
+}
+
+pub fn window() -> RootWindow {
+    ROOT_WINDOW.with(|window| *window.borrow().get())
+}
+
+pub fn set_window(window: RootWindow) -> Result<(), String> {
+    if window.max_epochs == 0 || window.max_epochs > ROOT_HISTORY_CAPACITY {
+        return Err(format!("max_epochs must be between 1 and {}", ROOT_HISTORY_CAPACITY));
+    }
+    ROOT_WINDOW.with(|cell| {
+        cell.borrow_mut()
+            .set(window)
+            .expect("failed to write the root window");
+    });
+    Ok(())
+}
+
+// The record of `root` if a proof against it is still acceptable at `now`
+pub fn check_accepted(root: &[u8], now: u64) -> Result<RootRecord, String> {
+    let latest = latest().ok_or_else(|| "No Merkle root has been published".to_string())?;
+    let record = list()
+        .into_iter()
+        .find(|record| record.root == root)
+        .ok_or_else(|| "Unknown Merkle root".to_string())?;
+
+    let window = window();
+    if latest.epoch - record.epoch >= window.max_epochs {
+        return Err(format!(
+            "Merkle root of epoch {} is outside the accepted window of {} epochs",
+            record.epoch, window.max_epochs
+        ));
+    }
+    if now.saturating_sub(record.published_at) > window.max_age_ns {
+        return Err(format!("Merkle root of epoch {} has expired", record.epoch));
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn publishes_epochs_and_skips_repeated_roots() {
+        assert_eq!(latest(), None);
+        assert_eq!(publish(&[1; 32], 10).epoch, 1);
+        assert_eq!(publish(&[1; 32], 20), RootRecord { epoch: 1, root: vec![1; 32], published_at: 10 });
+        assert_eq!(publish(&[2; 32], 30).epoch, 2);
+
+        let epochs: Vec<u64> = list().iter().map(|record| record.epoch).collect();
+        assert_eq!(epochs, vec![2, 1]);
+
+        // A root that recurs after another one is a new epoch, so it becomes the
+        // latest with a fresh publication time
+        assert_eq!(publish(&[1; 32], 40), RootRecord { epoch: 3, root: vec![1; 32], published_at: 40 });
+        assert_eq!(latest().map(|record| record.epoch), Some(3));
+        assert_eq!(check_accepted(&[1; 32], 50).map(|record| record.published_at), Ok(40));
+    }
+
+    #[test]
+    fn accepts_roots_inside_the_window() {
+        set_window(RootWindow { max_epochs: 3, max_age_ns: 100 * SECOND }).unwrap();
+        for n in 1..=4u8 {
+            publish(&[n; 32], n as u64 * SECOND);
+        }
+
+        // Epochs 2..=4 are the three newest
+        assert_eq!(check_accepted(&[4; 32], 5 * SECOND).map(|r| r.epoch), Ok(4));
+        assert_eq!(check_accepted(&[2; 32], 5 * SECOND).map(|r| r.epoch), Ok(2));
+        assert!(check_accepted(&[1; 32], 5 * SECOND).is_err());
+        assert!(check_accepted(&[9; 32], 5 * SECOND).is_err());
+
+        // Age applies on top of the epoch window; a root exactly max_age_ns old is
+        // still accepted
+        assert_eq!(check_accepted(&[2; 32], 102 * SECOND).map(|r| r.epoch), Ok(2));
+        assert!(check_accepted(&[2; 32], 102 * SECOND + 1).is_err());
+        assert!(check_accepted(&[4; 32], 102 * SECOND + 1).is_ok());
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        for n in 0..ROOT_HISTORY_CAPACITY + 10 {
+            publish(&n.to_be_bytes(), n);
+        }
+        let history = list();
+        assert_eq!(history.len() as u64, ROOT_HISTORY_CAPACITY);
+        assert_eq!(history.last().unwrap().epoch, 11);
+        assert!(set_window(RootWindow { max_epochs: ROOT_HISTORY_CAPACITY + 1, max_age_ns: 1 }).is_err());
+    }
+}
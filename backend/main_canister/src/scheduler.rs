@@ -0,0 +1,196 @@
+use crate::memory::{self, Memory};
+use crate::{StorableString, Task};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+// Queue of scheduled tasks, ordered by due time. One timer is armed for the
+// earliest entry; when it fires, the tasks that are due are started highest
+// priority first, at most MAX_TASKS_PER_TICK of them, and the timer is re-armed
+// for whatever is left. The queue is stable; timers are not, so post_upgrade
+// re-arms it.
+
+pub const MAX_TASKS_PER_TICK: usize = 16;
+
+// Failed tasks are retried after a delay that doubles from the base up to the cap
+pub const RETRY_BASE_DELAY_NS: u64 = 1_000_000_000;
+pub const RETRY_MAX_DELAY_NS: u64 = 10 * 60 * 1_000_000_000;
+
+// Delay before retry `retry` (1 for the first). A random part of up to half the
+// delay is taken off, so that tasks which failed together do not retry together.
+// Randomness only spreads retries out, so a hash of `seed` is enough.
+pub fn backoff(retry: u32, seed: &[u8]) -> u64 {
+    let doublings = retry.saturating_sub(1).min(32);
+    let delay = RETRY_BASE_DELAY_NS.saturating_mul(1 << doublings).min(RETRY_MAX_DELAY_NS);
+    let random = u64::from_be_bytes(Sha256::digest(seed)[..8].try_into().unwrap());
+    delay - random % (delay / 2 + 1)
+}
+
+// Lower runs first
+pub fn priority_rank(priority: &str) -> u8 {
+    match priority.to_ascii_lowercase().as_str() {
+        "high" => 0,
+        "low" => 2,
+        _ => 1,
+    }
+}
+
+pub fn task_priority(task: &Task) -> u8 {
+    task.config.as_ref().map_or(priority_rank(""), |config| priority_rank(&config.priority))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct QueueKey {
+    due_at: u64,
+    priority: u8,
+    // Task ids are unique, which keeps keys unique
+    task_id: String,
+}
+
+impl Storable for QueueKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.due_at.to_be_bytes().to_vec();
+        bytes.push(self.priority);
+        bytes.extend_from_slice(self.task_id.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        QueueKey {
+            due_at: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            priority: bytes[8],
+            task_id: String::from_utf8(bytes[9..].to_vec()).unwrap(),
+        }
+    }
+}
+
+impl BoundedStorable for QueueKey {
+    const MAX_SIZE: u32 = 8 + 1 + StorableString::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // task -> reference it belongs to
+    static TASK_QUEUE: RefCell<StableBTreeMap<QueueKey, StorableString, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::TASK_QUEUE)));
+
+    // The armed timer and the due time it was armed for
+    static NEXT_TIMER: RefCell<Option<(u64, TimerId)>> = RefCell::new(None);
+}
+
+pub fn enqueue(reference_id: &str, task_id: &str, due_at: u64, priority: u8) {
+    let key = QueueKey { due_at, priority, task_id: task_id.to_string() };
+    TASK_QUEUE.with(|queue| queue.borrow_mut().insert(key, StorableString(reference_id.to_string())));
+}
+
+// Remove and return up to `limit` (reference id, task id) pairs due by `now`,
+// highest priority first and earliest first within a priority
+pub fn take_due(now: u64, limit: usize) -> Vec<(String, String)> {
+    TASK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let mut due: Vec<(QueueKey, StorableString)> = queue.iter()
+            .take_while(|(key, _)| key.due_at <= now)
+            .collect();
+        due.sort_by(|(a, _), (b, _)| (a.priority, a.due_at).cmp(&(b.priority, b.due_at)));
+        due.truncate(limit);
+        due.into_iter()
+            .map(|(key, reference_id)| {
+                queue.remove(&key);
+                (reference_id.0, key.task_id)
+            })
+            .collect()
+    })
+}
+
+pub fn next_due() -> Option<u64> {
+    TASK_QUEUE.with(|queue| queue.borrow().iter().next().map(|(key, _)| key.due_at))
+}
+
+pub fn len() -> u64 {
+    TASK_QUEUE.with(|queue| queue.borrow().len())
+}
+
+// Arm the timer for the earliest queued task, unless it already is
+pub fn rearm(now: u64) {
+    let next = next_due();
+    NEXT_TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        if timer.as_ref().map(|(due_at, _)| *due_at) == next {
+            return;
+        }
+        if let Some((_, id)) = timer.take() {
+            ic_cdk_timers::clear_timer(id);
+        }
+        if let Some(due_at) = next {
+            let delay = Duration::from_nanos(due_at.saturating_sub(now));
+            *timer = Some((due_at, ic_cdk_timers::set_timer(delay, fire)));
+        }
+    });
+}
+
+fn fire() {
+    NEXT_TIMER.with(|timer| *timer.borrow_mut() = None);
+    let now = ic_cdk::api::time();
+    for (reference_id, task_id) in take_due(now, MAX_TASKS_PER_TICK) {
+        ic_cdk::spawn(crate::execute_single_task(reference_id, task_id));
+    }
+    rearm(now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_due_tasks_by_priority() {
+        enqueue("ref", "low", 10, priority_rank("low"));
+        enqueue("ref", "normal", 20, priority_rank("medium"));
+        enqueue("ref", "high", 30, priority_rank("HIGH"));
+        enqueue("ref", "later", 100, priority_rank("high"));
+        assert_eq!(next_due(), Some(10));
+
+        let ids = |taken: Vec<(String, String)>| taken.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+        assert!(take_due(5, MAX_TASKS_PER_TICK).is_empty());
+        assert_eq!(ids(take_due(30, MAX_TASKS_PER_TICK)), ["high", "normal", "low"]);
+        assert_eq!(next_due(), Some(100));
+        assert_eq!(take_due(100, MAX_TASKS_PER_TICK), [("ref".to_string(), "later".to_string())]);
+        assert_eq!(len(), 0);
+    }
+
+    #[test]
+    fn leaves_tasks_beyond_the_limit_queued() {
+        for i in 0..5 {
+            enqueue("ref", &format!("task-{}", i), i, 1);
+        }
+        enqueue("ref", "urgent", 4, 0);
+        let taken = take_due(10, 3);
+        assert_eq!(taken[0].1, "urgent");
+        assert_eq!(taken.len(), 3);
+        assert_eq!(len(), 3);
+        assert_eq!(next_due(), Some(2));
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_cap() {
+        for retry in 1..8 {
+            let delay = RETRY_BASE_DELAY_NS << (retry - 1);
+            for seed in 0u8..20 {
+                let jittered = backoff(retry, &[seed]);
+                assert!(jittered <= delay && jittered >= delay / 2, "retry {} gave {}", retry, jittered);
+            }
+        }
+        assert!(backoff(u32::MAX, b"seed") <= RETRY_MAX_DELAY_NS);
+        assert!(backoff(u32::MAX, b"seed") >= RETRY_MAX_DELAY_NS / 2);
+        // Different tasks spread out
+        assert_ne!(backoff(5, b"task-a"), backoff(5, b"task-b"));
+    }
+
+    #[test]
+    fn queue_keys_round_trip() {
+        let key = QueueKey { due_at: u64::MAX - 1, priority: 2, task_id: "4f9c2d1e-7a3b-4c5d-9e8f-0a1b2c3d4e5f".to_string() };
+        assert_eq!(QueueKey::from_bytes(key.to_bytes()), key);
+    }
+}
@@ -0,0 +1,337 @@
+use crate::icrc3::{self, BlockWithId, GetBlocksArgs, GetBlocksResult, Operation};
+use crate::memory::{self, Memory};
+use crate::merkle::{AccountScope, BalanceTree, TreeMemory};
+use crate::roots;
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::export::Principal;
+use ic_stable_structures::StableCell;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+
+// Balance trees built from a ledger's full block log. A snapshot replays every
+// ICRC-3 block of each registered ledger into a tree of its own, separate from the
+// live tree that generate_token_proof updates one wallet at a time. Once every
+// ledger has been replayed up to its tip the tree is frozen: its root is published
+// to the root history and proofs are generated against it until the next snapshot
+// starts.
+// Ledgers without icrc3_get_blocks cannot be snapshotted; the ICP ledger's native
+// blocks only name account identifiers, from which the owner cannot be recovered.
+//
+// Every subaccount is a leaf of its own. Aggregates over several subaccounts are
+// not part of a snapshot.
+
+// Blocks requested per icrc3_get_blocks call
+pub const PAGE_SIZE: u64 = 100;
+
+pub const SNAPSHOT_TREE_MEMORY: TreeMemory = TreeMemory {
+    nodes: memory::SNAPSHOT_NODES,
+    leaves: memory::SNAPSHOT_LEAVES,
+    leaf_index: memory::SNAPSHOT_LEAF_INDEX,
+};
+
+// Next block of `ledger` to replay into the leaves of `token_id`
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LedgerCursor {
+    pub ledger: Principal,
+    pub token_id: u64,
+    pub next_block: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum SnapshotStatus {
+    Empty,
+    Building { started_at: u64 },
+    Frozen { epoch: u64, root: Vec<u8>, frozen_at: u64 },
+    Failed { reason: String },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SnapshotState {
+    pub cursors: Vec<LedgerCursor>,
+    pub status: SnapshotStatus,
+}
+
+impl Default for SnapshotState {
+    fn default() -> Self {
+        SnapshotState { cursors: Vec::new(), status: SnapshotStatus::Empty }
+    }
+}
+
+thread_local! {
+    pub static SNAPSHOT_TREE: RefCell<BalanceTree> = RefCell::new(BalanceTree::init_in(&SNAPSHOT_TREE_MEMORY));
+
+    static SNAPSHOT_STATE: RefCell<StableCell<SnapshotState, Memory>> =
+        RefCell::new(StableCell::init(memory::get(memory::SNAPSHOT_STATE), SnapshotState::default())
+            .expect("failed to initialize the snapshot state"));
+
+    // Not stable: a snapshot interrupted by an upgrade can simply be started again
+    static RUNNING: Cell<bool> = Cell::new(false);
+}
+
+pub fn state() -> SnapshotState {
+    SNAPSHOT_STATE.with(|cell| cell.borrow().get().clone())
+}
+
+fn update_state(f: impl FnOnce(&mut SnapshotState)) {
+    SNAPSHOT_STATE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut state = cell.get().clone();
+        f(&mut state);
+        cell.set(state).expect("failed to write the snapshot state");
+    });
+}
+
+pub fn register_ledger(ledger: Principal, token_id: u64) -> Result<(), String> {
+    if RUNNING.with(|running| running.get()) {
+        return Err("A snapshot is being built".to_string());
+    }
+    let state = state();
+    if state.cursors.iter().any(|cursor| cursor.ledger == ledger || cursor.token_id == token_id) {
+        return Err("Ledger or token id is already registered".to_string());
+    }
+    update_state(|state| state.cursors.push(LedgerCursor { ledger, token_id, next_block: 0 }));
+    Ok(())
+}
+
+// The frozen snapshot's token id for `ledger`
+pub fn frozen_token_id(ledger: Principal) -> Result<u64, String> {
+    let state = state();
+    if !matches!(state.status, SnapshotStatus::Frozen { .. }) {
+        return Err("No frozen snapshot is available".to_string());
+    }
+    state.cursors.iter()
+        .find(|cursor| cursor.ledger == ledger)
+        .map(|cursor| cursor.token_id)
+        .ok_or_else(|| "Ledger is not part of the snapshot".to_string())
+}
+
+// Replay every registered ledger up to its tip, then freeze and publish the tree
+pub async fn build() -> Result<SnapshotStatus, String> {
+    if RUNNING.with(|running| running.replace(true)) {
+        return Err("A snapshot is already being built".to_string());
+    }
+    update_state(|state| state.status = SnapshotStatus::Building { started_at: ic_cdk::api::time() });
+
+    let mut result = Ok(());
+    for index in 0..state().cursors.len() {
+        result = replay_ledger(index).await;
+        if result.is_err() {
+            break;
+        }
+    }
+
+    let status = match result {
+        Ok(()) => freeze(ic_cdk::api::time()),
+        Err(reason) => SnapshotStatus::Failed { reason },
+    };
+    update_state(|state| state.status = status.clone());
+    RUNNING.with(|running| running.set(false));
+
+    match status {
+        SnapshotStatus::Failed { reason } => Err(reason),
+        status => Ok(status),
+    }
+}
+
+fn freeze(now: u64) -> SnapshotStatus {
+    let root = SNAPSHOT_TREE.with(|tree| tree.borrow().root_hash());
+    let record = roots::publish(&root, now);
+    SnapshotStatus::Frozen { epoch: record.epoch, root: root.to_vec(), frozen_at: now }
+}
+
+async fn replay_ledger(index: usize) -> Result<(), String> {
+    loop {
+        let cursor = state().cursors[index].clone();
+        let (log_length, blocks) = fetch_page(cursor.ledger, cursor.next_block).await?;
+
+        let mut advanced = cursor.clone();
+        let applied = SNAPSHOT_TREE.with(|tree| apply_blocks(&mut tree.borrow_mut(), &mut advanced, &blocks));
+        // Keep the blocks that did apply, so a retry resumes after them
+        update_state(|state| state.cursors[index] = advanced.clone());
+        applied?;
+
+        if advanced.next_block >= log_length || advanced.next_block == cursor.next_block {
+            return Ok(());
+        }
+    }
+}
+
+// One page of blocks from `start`, including any that were moved to archives
+async fn fetch_page(ledger: Principal, start: u64) -> Result<(u64, Vec<BlockWithId>), String> {
+    let args = vec![GetBlocksArgs { start: Nat::from(start), length: Nat::from(PAGE_SIZE) }];
+    let (result,): (GetBlocksResult,) = ic_cdk::call(ledger, "icrc3_get_blocks", (args,))
+        .await
+        .map_err(|(code, msg)| format!("icrc3_get_blocks failed: {} (code: {:?})", msg, code))?;
+
+    let mut blocks = Vec::new();
+    for archived in result.archived_blocks {
+        let (archive,): (GetBlocksResult,) =
+            ic_cdk::call(archived.callback.principal, &archived.callback.method, (archived.args,))
+                .await
+                .map_err(|(code, msg)| format!("Archive call failed: {} (code: {:?})", msg, code))?;
+        blocks.extend(archive.blocks);
+    }
+    blocks.extend(result.blocks);
+
+    Ok((icrc3::nat_to_u64(&result.log_length)?, blocks))
+}
+
+// Apply `blocks` in id order starting at the cursor, advancing it past each one
+pub fn apply_blocks(tree: &mut BalanceTree, cursor: &mut LedgerCursor, blocks: &[BlockWithId]) -> Result<(), String> {
+    let mut blocks: Vec<(u64, &BlockWithId)> = blocks.iter()
+        .map(|block| Ok((icrc3::nat_to_u64(&block.id)?, block)))
+        .collect::<Result<_, String>>()?;
+    blocks.sort_by_key(|(id, _)| *id);
+
+    for (id, block) in blocks {
+        if id < cursor.next_block {
+            continue;
+        }
+        if id > cursor.next_block {
+            return Err(format!("Block {} is missing from the ledger's response", cursor.next_block));
+        }
+        let operation = icrc3::parse_block(&block.block).map_err(|e| format!("Block {}: {}", id, e))?;
+        apply_operation(tree, cursor.token_id, &operation).map_err(|e| format!("Block {}: {}", id, e))?;
+        cursor.next_block += 1;
+    }
+    Ok(())
+}
+
+// Balances are worked out before any is written, so a failing block leaves the
+// tree as it was
+fn apply_operation(tree: &mut BalanceTree, token_id: u64, operation: &Operation) -> Result<(), String> {
+    // (account, credit, debit)
+    let changes = match operation {
+        Operation::Mint { to, amount } => vec![(to, *amount, 0)],
+        Operation::Burn { from, amount, fee } => vec![(from, 0, amount.saturating_add(*fee))],
+        Operation::Transfer { from, to, amount, fee } => {
+            vec![(from, 0, amount.saturating_add(*fee)), (to, *amount, 0)]
+        }
+        Operation::Approve { from, fee } => vec![(from, 0, *fee)],
+    };
+
+    let mut balances: Vec<(Principal, AccountScope, u64)> = Vec::new();
+    for (account, credit, debit) in changes {
+        let scope = AccountScope::of(account.subaccount);
+        let current = balances.iter()
+            .rev()
+            .find(|(owner, other, _)| *owner == account.owner && *other == scope)
+            .map(|(_, _, balance)| *balance)
+            .or_else(|| tree.balance(account.owner, token_id, &scope))
+            .unwrap_or(0);
+        let balance = current.checked_add(credit)
+            .and_then(|balance| balance.checked_sub(debit))
+            .ok_or_else(|| format!("balance of {} out of range", account.owner))?;
+        balances.push((account.owner, scope, balance));
+    }
+
+    for (owner, scope, balance) in balances {
+        tree.set_balance(owner, token_id, scope, balance)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icrc3::Value;
+
+    fn owner(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    fn account(n: u8) -> Value {
+        Value::Array(vec![Value::Blob(owner(n).as_slice().to_vec())])
+    }
+
+    fn block(id: u64, btype: &str, tx: Vec<(&str, Value)>) -> BlockWithId {
+        let tx = tx.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+        BlockWithId {
+            id: Nat::from(id),
+            block: Value::Map(vec![
+                ("btype".to_string(), Value::Text(btype.to_string())),
+                ("tx".to_string(), Value::Map(tx)),
+            ]),
+        }
+    }
+
+    fn nat(value: u64) -> Value {
+        Value::Nat(Nat::from(value))
+    }
+
+    fn cursor() -> LedgerCursor {
+        LedgerCursor { ledger: owner(99), token_id: 7, next_block: 0 }
+    }
+
+    #[test]
+    fn replays_blocks_into_balances() {
+        let mut tree = BalanceTree::init_in(&SNAPSHOT_TREE_MEMORY);
+        let mut cursor = cursor();
+        // Out of order, as pages mixing archived and live blocks may arrive
+        let blocks = vec![
+            block(2, "2approve", vec![("from", account(2)), ("fee", nat(1))]),
+            block(0, "1mint", vec![("to", account(1)), ("amt", nat(1_000))]),
+            block(1, "1xfer", vec![("from", account(1)), ("to", account(2)), ("amt", nat(300)), ("fee", nat(10))]),
+            block(3, "1burn", vec![("from", account(1)), ("amt", nat(90))]),
+        ];
+
+        apply_blocks(&mut tree, &mut cursor, &blocks).unwrap();
+        assert_eq!(cursor.next_block, 4);
+        assert_eq!(tree.balance(owner(1), 7, &AccountScope::Default), Some(600));
+        assert_eq!(tree.balance(owner(2), 7, &AccountScope::Default), Some(299));
+
+        // Replaying the same page again changes nothing
+        let root = tree.root_hash();
+        apply_blocks(&mut tree, &mut cursor, &blocks).unwrap();
+        assert_eq!(tree.root_hash(), root);
+    }
+
+    #[test]
+    fn tracks_subaccounts_separately() {
+        let mut tree = BalanceTree::init_in(&SNAPSHOT_TREE_MEMORY);
+        let mut cursor = cursor();
+        let savings = Value::Array(vec![Value::Blob(owner(1).as_slice().to_vec()), Value::Blob(vec![4; 32])]);
+        let blocks = vec![
+            block(0, "1mint", vec![("to", account(1)), ("amt", nat(100))]),
+            block(1, "1xfer", vec![("from", account(1)), ("to", savings), ("amt", nat(40))]),
+        ];
+
+        apply_blocks(&mut tree, &mut cursor, &blocks).unwrap();
+        assert_eq!(tree.balance(owner(1), 7, &AccountScope::Default), Some(60));
+        assert_eq!(tree.balance(owner(1), 7, &AccountScope::Subaccount([4; 32])), Some(40));
+    }
+
+    #[test]
+    fn stops_at_gaps_and_bad_blocks() {
+        let mut tree = BalanceTree::init_in(&SNAPSHOT_TREE_MEMORY);
+        let mut cursor = cursor();
+
+        let gap = vec![
+            block(0, "1mint", vec![("to", account(1)), ("amt", nat(5))]),
+            block(2, "1mint", vec![("to", account(1)), ("amt", nat(5))]),
+        ];
+        assert!(apply_blocks(&mut tree, &mut cursor, &gap).is_err());
+        assert_eq!(cursor.next_block, 1);
+
+        let overdraft = vec![block(1, "1xfer", vec![("from", account(1)), ("to", account(2)), ("amt", nat(6))])];
+        assert!(apply_blocks(&mut tree, &mut cursor, &overdraft).is_err());
+        assert_eq!(cursor.next_block, 1);
+        assert_eq!(tree.balance(owner(1), 7, &AccountScope::Default), Some(5));
+    }
+
+    #[test]
+    fn frozen_snapshot_is_published() {
+        assert!(frozen_token_id(owner(99)).is_err());
+        register_ledger(owner(99), 7).unwrap();
+        assert!(register_ledger(owner(99), 8).is_err());
+
+        SNAPSHOT_TREE.with(|tree| tree.borrow_mut().set_balance(owner(1), 7, AccountScope::Default, 10).unwrap());
+        let status = freeze(42);
+        update_state(|state| state.status = status.clone());
+
+        let SnapshotStatus::Frozen { epoch, root, .. } = status else { panic!("not frozen") };
+        assert_eq!(roots::latest().map(|record| (record.epoch, record.root)), Some((epoch, root)));
+        assert_eq!(frozen_token_id(owner(99)), Ok(7));
+        assert!(frozen_token_id(owner(98)).is_err());
+    }
+}
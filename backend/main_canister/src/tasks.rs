@@ -0,0 +1,304 @@
+use crate::Task;
+use candid::{CandidType, Deserialize, Encode};
+use serde::Serialize;
+
+// Bounds that keep a Reference under its stable MAX_SIZE (encoding.rs) however its
+// tasks run. A reference holds at most MAX_TASKS_PER_REFERENCE tasks of at most
+// MAX_TASK_SIZE bytes as assigned. A task runs at most max_attempts times, and
+// every error it records is cut to MAX_ERROR_LEN bytes. Its artifact, one per
+// task, is at most MAX_ARTIFACT_SIZE bytes.
+pub const MAX_TASKS_PER_REFERENCE: usize = 8;
+pub const MAX_TASK_SIZE: usize = 1024;
+pub const MAX_RETRY_ATTEMPTS: u8 = 7;
+pub const MAX_ERROR_LEN: usize = 128;
+pub const MAX_ARTIFACT_SIZE: usize = 1024;
+
+// Candid size of a task or artifact, as checked against the bounds above
+pub fn encoded_len<T: CandidType>(value: &T) -> usize {
+    Encode!(value).map_or(usize::MAX, |bytes| bytes.len())
+}
+
+// `error` cut to MAX_ERROR_LEN bytes, on a character boundary
+fn bounded_error(mut error: String) -> String {
+    if error.len() > MAX_ERROR_LEN {
+        let mut end = MAX_ERROR_LEN;
+        while !error.is_char_boundary(end) {
+            end -= 1;
+        }
+        error.truncate(end);
+    }
+    error
+}
+
+// Lifecycle of a task. Every status change goes through `transition`, which only
+// allows the moves below and keeps the task's attempt history:
+//
+//   Pending   -> Scheduled | Running | Cancelled
+//   Scheduled -> Running | Cancelled
+//   Running   -> Succeeded | Failed | Scheduled (to be retried)
+//
+// Succeeded, Failed and Cancelled are final.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum TaskStatus {
+    Pending,
+    Scheduled,
+    Running,
+    Succeeded,
+    Failed { reason: String },
+    Cancelled,
+}
+
+// TaskStatus without its payload, to filter tasks by
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum TaskStatusKind {
+    Pending,
+    Scheduled,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl TaskStatus {
+    // Statuses were free-form text before, only ever "pending" or "completed"
+    pub fn from_legacy(status: &str) -> Self {
+        match status {
+            "pending" => TaskStatus::Pending,
+            "completed" => TaskStatus::Succeeded,
+            other => TaskStatus::Failed { reason: format!("Unrecognised status {:?}", other) },
+        }
+    }
+
+    pub fn kind(&self) -> TaskStatusKind {
+        match self {
+            TaskStatus::Pending => TaskStatusKind::Pending,
+            TaskStatus::Scheduled => TaskStatusKind::Scheduled,
+            TaskStatus::Running => TaskStatusKind::Running,
+            TaskStatus::Succeeded => TaskStatusKind::Succeeded,
+            TaskStatus::Failed { .. } => TaskStatusKind::Failed,
+            TaskStatus::Cancelled => TaskStatusKind::Cancelled,
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(self, TaskStatus::Succeeded | TaskStatus::Failed { .. } | TaskStatus::Cancelled)
+    }
+
+    fn can_become(&self, next: &TaskStatus) -> bool {
+        use TaskStatus::*;
+        matches!(
+            (self, next),
+            (Pending, Scheduled | Running | Cancelled)
+                | (Scheduled, Running | Cancelled)
+                | (Running, Succeeded | Failed { .. } | Scheduled)
+        )
+    }
+}
+
+// One run of a task: opened when it starts running, closed when it stops
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TaskAttempt {
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+pub fn transition(task: &mut Task, next: TaskStatus, now: u64) -> Result<(), String> {
+    if !task.status.can_become(&next) {
+        return Err(format!("Task {} cannot go from {:?} to {:?}", task.id, task.status.kind(), next.kind()));
+    }
+    let next = match next {
+        TaskStatus::Failed { reason } => TaskStatus::Failed { reason: bounded_error(reason) },
+        next => next,
+    };
+
+    if task.status == TaskStatus::Running {
+        if let Some(attempt) = task.attempts.last_mut() {
+            attempt.finished_at = Some(now);
+            if let TaskStatus::Failed { reason } = &next {
+                attempt.error = Some(reason.clone());
+            }
+        }
+    }
+    if next == TaskStatus::Running {
+        task.attempts.push(TaskAttempt { started_at: now, finished_at: None, error: None });
+    }
+    task.status = next;
+    Ok(())
+}
+
+// Close the running attempt with `error` and wait to be run again
+pub fn retry(task: &mut Task, error: String, now: u64) -> Result<(), String> {
+    if task.status != TaskStatus::Running {
+        return Err(format!("Task {} is not running", task.id));
+    }
+    transition(task, TaskStatus::Scheduled, now)?;
+    if let Some(attempt) = task.attempts.last_mut() {
+        attempt.error = Some(bounded_error(error));
+    }
+    Ok(())
+}
+
+// Runs a task may have in all: the first, then up to retry_attempts retries. Tasks
+// stored before retry_attempts was bounded are held to the bound as well.
+pub fn max_attempts(task: &Task) -> usize {
+    task.config.as_ref().map_or(1, |config| config.retry_attempts.min(MAX_RETRY_ATTEMPTS) as usize + 1)
+}
+
+// Record how a run ended: succeed, fail for good once max_attempts runs have
+// failed, or wait to be run again
+pub fn finish(task: &mut Task, outcome: Result<(), String>, now: u64) -> Result<(), String> {
+    match outcome {
+        Ok(()) => transition(task, TaskStatus::Succeeded, now),
+        Err(error) if task.attempts.len() < max_attempts(task) => retry(task, error, now),
+        Err(reason) => transition(task, TaskStatus::Failed { reason }, now),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task() -> Task {
+        Task {
+            id: "task".to_string(),
+            description: String::new(),
+            status: TaskStatus::Pending,
+            timestamp: 0,
+            config: None,
+            claim: None,
+            attempts: vec![],
+        }
+    }
+
+    fn failed(reason: &str) -> TaskStatus {
+        TaskStatus::Failed { reason: reason.to_string() }
+    }
+
+    #[test]
+    fn runs_record_attempts() {
+        let mut task = task();
+        transition(&mut task, TaskStatus::Scheduled, 1).unwrap();
+        transition(&mut task, TaskStatus::Running, 2).unwrap();
+        assert_eq!(task.attempts, [TaskAttempt { started_at: 2, finished_at: None, error: None }]);
+
+        transition(&mut task, failed("ledger unavailable"), 3).unwrap();
+        assert_eq!(task.attempts, [TaskAttempt {
+            started_at: 2,
+            finished_at: Some(3),
+            error: Some("ledger unavailable".to_string()),
+        }]);
+        assert_eq!(task.status.kind(), TaskStatusKind::Failed);
+    }
+
+    #[test]
+    fn rejects_illegal_transitions() {
+        let mut task = task();
+        assert!(transition(&mut task, TaskStatus::Succeeded, 1).is_err());
+        assert!(transition(&mut task, failed("never ran"), 1).is_err());
+        assert_eq!(task.status, TaskStatus::Pending);
+
+        transition(&mut task, TaskStatus::Running, 1).unwrap();
+        assert!(transition(&mut task, TaskStatus::Cancelled, 2).is_err());
+        transition(&mut task, TaskStatus::Succeeded, 2).unwrap();
+
+        // Final statuses stay final
+        for next in [TaskStatus::Pending, TaskStatus::Scheduled, TaskStatus::Running, TaskStatus::Cancelled] {
+            assert!(transition(&mut task, next, 3).is_err());
+        }
+        assert_eq!(task.attempts.len(), 1);
+    }
+
+    #[test]
+    fn retries_keep_each_error() {
+        let mut task = task();
+        assert!(retry(&mut task, "not running".to_string(), 1).is_err());
+
+        transition(&mut task, TaskStatus::Running, 1).unwrap();
+        retry(&mut task, "timeout".to_string(), 2).unwrap();
+        assert_eq!(task.status, TaskStatus::Scheduled);
+        transition(&mut task, TaskStatus::Running, 5).unwrap();
+        retry(&mut task, "rejected".to_string(), 6).unwrap();
+        transition(&mut task, TaskStatus::Running, 9).unwrap();
+
+        transition(&mut task, TaskStatus::Succeeded, 10).unwrap();
+        let errors: Vec<_> = task.attempts.iter().map(|attempt| attempt.error.as_deref()).collect();
+        assert_eq!(errors, [Some("timeout"), Some("rejected"), None]);
+        assert_eq!(task.attempts[1], TaskAttempt {
+            started_at: 5,
+            finished_at: Some(6),
+            error: Some("rejected".to_string()),
+        });
+    }
+
+    fn with_retries(retry_attempts: u8) -> Task {
+        Task {
+            config: Some(crate::TaskConfig {
+                priority: "medium".to_string(),
+                execution_delay: 0,
+                retry_attempts,
+                disclosure_level: crate::disclosure::DisclosureLevel::Anonymous,
+                storage_type: "chain".to_string(),
+            }),
+            ..task()
+        }
+    }
+
+    // Fail every run; the retry numbers the backoff was asked for, and the status
+    fn fail_every_run(mut task: Task) -> (Vec<u32>, TaskStatus) {
+        let mut retries = Vec::new();
+        for now in 1.. {
+            transition(&mut task, TaskStatus::Running, now).unwrap();
+            finish(&mut task, Err("timeout".to_string()), now).unwrap();
+            if task.status != TaskStatus::Scheduled {
+                return (retries, task.status);
+            }
+            // execute_single_task asks scheduler::backoff for this retry
+            retries.push(task.attempts.len() as u32);
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn retry_attempts_count_retries_after_the_first_run() {
+        assert_eq!(max_attempts(&task()), 1);
+        assert_eq!(max_attempts(&with_retries(0)), 1);
+        assert_eq!(max_attempts(&with_retries(3)), 4);
+        assert_eq!(max_attempts(&with_retries(u8::MAX)), MAX_RETRY_ATTEMPTS as usize + 1);
+
+        assert_eq!(fail_every_run(task()), (vec![], failed("timeout")));
+        assert_eq!(fail_every_run(with_retries(0)), (vec![], failed("timeout")));
+        assert_eq!(fail_every_run(with_retries(1)), (vec![1], failed("timeout")));
+        assert_eq!(fail_every_run(with_retries(3)), (vec![1, 2, 3], failed("timeout")));
+
+        // A retry that succeeds ends the task
+        let mut task = with_retries(3);
+        transition(&mut task, TaskStatus::Running, 1).unwrap();
+        finish(&mut task, Err("timeout".to_string()), 2).unwrap();
+        transition(&mut task, TaskStatus::Running, 3).unwrap();
+        finish(&mut task, Ok(()), 4).unwrap();
+        assert_eq!((task.status, task.attempts.len()), (TaskStatus::Succeeded, 2));
+    }
+
+    #[test]
+    fn errors_are_bounded() {
+        let mut task = task();
+        transition(&mut task, TaskStatus::Running, 1).unwrap();
+        retry(&mut task, "x".repeat(MAX_ERROR_LEN + 1), 2).unwrap();
+        assert_eq!(task.attempts[0].error.as_ref().map(String::len), Some(MAX_ERROR_LEN));
+
+        // Multi-byte characters are not split
+        transition(&mut task, TaskStatus::Running, 3).unwrap();
+        transition(&mut task, failed(&"é".repeat(MAX_ERROR_LEN)), 4).unwrap();
+        let TaskStatus::Failed { reason } = &task.status else { panic!("task did not fail") };
+        assert_eq!(reason.len(), MAX_ERROR_LEN);
+        assert_eq!(task.attempts[1].error.as_ref(), Some(reason));
+    }
+
+    #[test]
+    fn legacy_statuses() {
+        assert_eq!(TaskStatus::from_legacy("pending"), TaskStatus::Pending);
+        assert_eq!(TaskStatus::from_legacy("completed"), TaskStatus::Succeeded);
+        assert!(TaskStatus::from_legacy("done").is_final());
+    }
+}
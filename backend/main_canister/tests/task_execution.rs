@@ -0,0 +1,207 @@
+// Runs the main_canister wasm in PocketIC and checks that task outcomes reach
+// stable storage: status changes made by timer-driven execution are visible
+// through get_tasks, including across an upgrade.
+//
+// The tests need a PocketIC server and the built wasm, so they are ignored by
+// default. Opt in with:
+//
+//   cargo build --target wasm32-unknown-unknown --release -p main_canister
+//   POCKET_IC_BIN=/path/to/pocket-ic cargo test -p main_canister --test task_execution -- --ignored
+//
+// The wasm is read from the build's target directory (CARGO_TARGET_DIR when set);
+// MAIN_CANISTER_WASM overrides the path.
+
+use candid::{decode_one, encode_args, encode_one, CandidType, Deserialize, Principal};
+use pocket_ic::{PocketIc, WasmResult};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Mirrors of the canister's Candid types; not every variant is built here
+#[allow(dead_code)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum DisclosureLevel {
+    Anonymous,
+    Redacted,
+    Full,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TaskConfig {
+    priority: String,
+    execution_delay: u64,
+    retry_attempts: u8,
+    disclosure_level: DisclosureLevel,
+    storage_type: String,
+}
+
+#[allow(dead_code)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct BalanceClaim {
+    owner: Principal,
+    token_id: u64,
+    subaccount: Option<Vec<u8>>,
+    min_balance: u64,
+}
+
+#[allow(dead_code)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum TaskStatus {
+    Pending,
+    Scheduled,
+    Running,
+    Succeeded,
+    Failed { reason: String },
+    Cancelled,
+}
+
+#[allow(dead_code)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TaskAttempt {
+    started_at: u64,
+    finished_at: Option<u64>,
+    error: Option<String>,
+}
+
+// Only the fields these tests look at
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Task {
+    id: String,
+    status: TaskStatus,
+    attempts: Vec<TaskAttempt>,
+}
+
+// The target directory this test binary was built into, which holds the wasm too
+fn target_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("CARGO_TARGET_DIR") {
+        return PathBuf::from(dir);
+    }
+    // <target>/<profile>/deps/task_execution-<hash>
+    let exe = std::env::current_exe().expect("test binary path");
+    exe.ancestors().nth(3).expect("test binary inside a target directory").to_path_buf()
+}
+
+fn wasm_path() -> PathBuf {
+    std::env::var_os("MAIN_CANISTER_WASM").map(PathBuf::from).unwrap_or_else(|| {
+        target_dir().join("wasm32-unknown-unknown/release/main_canister.wasm")
+    })
+}
+
+struct Env {
+    pic: PocketIc,
+    canister: Principal,
+    wasm: Vec<u8>,
+    user: Principal,
+}
+
+impl Env {
+    // Panics, saying what is missing, when PocketIC or the wasm is not available
+    fn new() -> Env {
+        if std::env::var_os("POCKET_IC_BIN").is_none() {
+            panic!("POCKET_IC_BIN must point to a PocketIC server binary");
+        }
+        let path = wasm_path();
+        let wasm = std::fs::read(&path).unwrap_or_else(|e| {
+            panic!("cannot read the main_canister wasm at {}: {}; build it first", path.display(), e)
+        });
+        let pic = PocketIc::new();
+        let canister = pic.create_canister();
+        pic.add_cycles(canister, 2_000_000_000_000);
+        pic.install_canister(canister, wasm.clone(), encode_args(()).unwrap(), None);
+        Env { pic, canister, wasm, user: Principal::from_slice(&[7; 29]) }
+    }
+
+    fn reply(result: Result<WasmResult, pocket_ic::UserError>, method: &str) -> Vec<u8> {
+        match result {
+            Ok(WasmResult::Reply(bytes)) => bytes,
+            Ok(WasmResult::Reject(reason)) => panic!("{} rejected: {}", method, reason),
+            Err(e) => panic!("{} failed: {:?}", method, e),
+        }
+    }
+
+    fn update(&self, method: &str, arg: Vec<u8>) -> Vec<u8> {
+        Self::reply(self.pic.update_call(self.canister, self.user, method, arg), method)
+    }
+
+    fn query(&self, method: &str, arg: Vec<u8>) -> Vec<u8> {
+        Self::reply(self.pic.query_call(self.canister, self.user, method, arg), method)
+    }
+
+    fn new_reference(&self) -> String {
+        decode_one(&self.update("generate_reference", encode_args(()).unwrap())).unwrap()
+    }
+
+    fn assign(&self, reference_id: &str, execution_delay: u64) -> String {
+        let config = TaskConfig {
+            priority: "high".to_string(),
+            execution_delay,
+            retry_attempts: 3,
+            disclosure_level: DisclosureLevel::Anonymous,
+            storage_type: "chain".to_string(),
+        };
+        let arg = encode_args((reference_id, "integration task", Some(config), None::<BalanceClaim>)).unwrap();
+        let task_id: Option<String> = decode_one(&self.update("assign_task", arg)).unwrap();
+        task_id.expect("reference exists")
+    }
+
+    fn execute(&self, reference_id: &str) -> Vec<Task> {
+        let tasks: Option<Vec<Task>> = decode_one(&self.update("execute_tasks", encode_one(reference_id).unwrap())).unwrap();
+        tasks.expect("reference exists")
+    }
+
+    fn task(&self, reference_id: &str, task_id: &str) -> Task {
+        let tasks: Option<Vec<Task>> = decode_one(&self.query("get_tasks", encode_one(reference_id).unwrap())).unwrap();
+        tasks.unwrap().into_iter().find(|task| task.id == task_id).expect("task exists")
+    }
+
+    // Let due timers fire and the tasks they spawn finish
+    fn settle(&self) {
+        for _ in 0..5 {
+            self.pic.tick();
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs PocketIC and the main_canister wasm; see the top of this file"]
+fn executed_tasks_record_their_outcome() {
+    let env = Env::new();
+    let reference_id = env.new_reference();
+    let task_id = env.assign(&reference_id, 0);
+    assert_eq!(env.task(&reference_id, &task_id).status, TaskStatus::Pending);
+
+    let returned = env.execute(&reference_id);
+    assert_eq!(returned[0].status, TaskStatus::Scheduled);
+    assert_eq!(env.task(&reference_id, &task_id).status, TaskStatus::Scheduled);
+
+    env.settle();
+    let task = env.task(&reference_id, &task_id);
+    assert_eq!(task.status, TaskStatus::Succeeded);
+    assert_eq!(task.attempts.len(), 1);
+    assert!(task.attempts[0].finished_at.is_some());
+    assert_eq!(task.attempts[0].error, None);
+}
+
+#[test]
+#[ignore = "needs PocketIC and the main_canister wasm; see the top of this file"]
+fn delayed_tasks_wait_and_survive_upgrades() {
+    let env = Env::new();
+    let reference_id = env.new_reference();
+    let delayed = env.assign(&reference_id, 60);
+    let cancelled = env.assign(&reference_id, 60);
+    env.execute(&reference_id);
+
+    env.settle();
+    assert_eq!(env.task(&reference_id, &delayed).status, TaskStatus::Scheduled);
+    let arg = encode_args((&reference_id, &cancelled)).unwrap();
+    env.update("cancel_task", arg);
+
+    env.pic.upgrade_canister(env.canister, env.wasm.clone(), encode_args(()).unwrap(), None)
+        .expect("upgrade succeeds");
+    env.pic.advance_time(Duration::from_secs(61));
+    env.settle();
+
+    assert_eq!(env.task(&reference_id, &delayed).status, TaskStatus::Succeeded);
+    let cancelled = env.task(&reference_id, &cancelled);
+    assert_eq!(cancelled.status, TaskStatus::Cancelled);
+    assert!(cancelled.attempts.is_empty());
+}
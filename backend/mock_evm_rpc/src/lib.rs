@@ -0,0 +1,228 @@
+use candid::{CandidType, Deserialize, Nat, Reserved};
+use ic_cdk_macros::update;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Local stand-in for the EVM RPC canister's eth_call, for testing the
+// main_canister's EVM reads. Contract state is set directly and shared by every
+// chain; the first `disagreeing` of three simulated providers answer with wrong
+// values, so consensus handling can be exercised.
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransactionRequest {
+    to: Option<String>,
+    input: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CallArgs {
+    transaction: TransactionRequest,
+    block: Option<Reserved>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum RpcError {
+    JsonRpcError { code: i64, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum CallResult {
+    Ok(String),
+    Err(RpcError),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum RpcService {
+    Provider(u64),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum MultiCallResult {
+    Consistent(CallResult),
+    Inconsistent(Vec<(RpcService, CallResult)>),
+}
+
+type Address = [u8; 20];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Erc20,
+    Erc721,
+    Erc1155,
+}
+
+#[derive(Default)]
+struct State {
+    kinds: BTreeMap<Address, Kind>,
+    // (contract, owner, token id) -> balance; token id 0 for ERC-20
+    balances: BTreeMap<(Address, Address, u128), u128>,
+    owners: BTreeMap<(Address, u128), Address>,
+    disagreeing: u8,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+const PROVIDERS: u8 = 3;
+
+fn address(text: &str) -> Address {
+    hex::decode(text.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .unwrap_or_else(|| ic_cdk::trap(&format!("{} is not an address", text)))
+}
+
+fn amount(value: &Nat) -> u128 {
+    u128::try_from(&value.0).unwrap_or_else(|_| ic_cdk::trap("amount does not fit in 128 bits"))
+}
+
+fn uint_word(value: u128) -> Vec<u8> {
+    let mut word = vec![0u8; 16];
+    word.extend_from_slice(&value.to_be_bytes());
+    word
+}
+
+fn address_word(address: &Address) -> Vec<u8> {
+    let mut word = vec![0u8; 12];
+    word.extend_from_slice(address);
+    word
+}
+
+fn arg_address(args: &[u8], n: usize) -> Option<Address> {
+    args.get(32 * n + 12..32 * (n + 1))?.try_into().ok()
+}
+
+fn arg_uint(args: &[u8], n: usize) -> Option<u128> {
+    let word = args.get(32 * n..32 * (n + 1))?;
+    if word[..16].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+    Some(u128::from_be_bytes(word[16..].try_into().unwrap()))
+}
+
+// The call's return data; None when it reverts
+fn answer(state: &State, contract: &Address, input: &[u8]) -> Option<Vec<u8>> {
+    let kind = match state.kinds.get(contract) {
+        Some(kind) => *kind,
+        // Calls to an address without code return nothing
+        None => return Some(Vec::new()),
+    };
+    let (selector, args) = input.split_at(4.min(input.len()));
+    match (selector, kind) {
+        // supportsInterface(bytes4); ERC-20 contracts do not implement ERC-165
+        ([0x01, 0xff, 0xc9, 0xa7], Kind::Erc20) => None,
+        ([0x01, 0xff, 0xc9, 0xa7], _) => {
+            let interface = args.get(..4)?;
+            let supported = interface == [0x01, 0xff, 0xc9, 0xa7]
+                || (kind == Kind::Erc721 && interface == [0x80, 0xac, 0x58, 0xcd])
+                || (kind == Kind::Erc1155 && interface == [0xd9, 0xb6, 0x7a, 0x26]);
+            Some(uint_word(supported as u128))
+        }
+        // balanceOf(address)
+        ([0x70, 0xa0, 0x82, 0x31], Kind::Erc20) => {
+            let owner = arg_address(args, 0)?;
+            Some(uint_word(state.balances.get(&(*contract, owner, 0)).copied().unwrap_or(0)))
+        }
+        ([0x70, 0xa0, 0x82, 0x31], Kind::Erc721) => {
+            let owner = arg_address(args, 0)?;
+            let held = state.owners.iter()
+                .filter(|((token_contract, _), holder)| token_contract == contract && **holder == owner)
+                .count();
+            Some(uint_word(held as u128))
+        }
+        // ownerOf(uint256) reverts for tokens that do not exist
+        ([0x63, 0x52, 0x21, 0x1e], Kind::Erc721) => {
+            let owner = state.owners.get(&(*contract, arg_uint(args, 0)?))?;
+            Some(address_word(owner))
+        }
+        // balanceOf(address, uint256)
+        ([0x00, 0xfd, 0xd5, 0x8e], Kind::Erc1155) => {
+            let key = (*contract, arg_address(args, 0)?, arg_uint(args, 1)?);
+            Some(uint_word(state.balances.get(&key).copied().unwrap_or(0)))
+        }
+        _ => None,
+    }
+}
+
+fn call_result(data: Option<Vec<u8>>) -> CallResult {
+    match data {
+        Some(data) => CallResult::Ok(format!("0x{}", hex::encode(data))),
+        None => CallResult::Err(RpcError::JsonRpcError { code: 3, message: "execution reverted".to_string() }),
+    }
+}
+
+fn set_kind(state: &mut State, contract: Address, kind: Kind) {
+    let existing = *state.kinds.entry(contract).or_insert(kind);
+    if existing != kind {
+        ic_cdk::trap(&format!("contract is already an {:?} contract", existing));
+    }
+}
+
+#[update]
+fn set_erc20_balance(contract: String, owner: String, balance: Nat) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let contract = address(&contract);
+        set_kind(&mut state, contract, Kind::Erc20);
+        state.balances.insert((contract, address(&owner), 0), amount(&balance));
+    });
+}
+
+#[update]
+fn set_erc721_owner(contract: String, token_id: Nat, owner: String) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let contract = address(&contract);
+        set_kind(&mut state, contract, Kind::Erc721);
+        state.owners.insert((contract, amount(&token_id)), address(&owner));
+    });
+}
+
+#[update]
+fn set_erc1155_balance(contract: String, owner: String, token_id: Nat, balance: Nat) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let contract = address(&contract);
+        set_kind(&mut state, contract, Kind::Erc1155);
+        state.balances.insert((contract, address(&owner), amount(&token_id)), amount(&balance));
+    });
+}
+
+#[update]
+fn set_disagreeing_providers(count: u8) {
+    STATE.with(|state| state.borrow_mut().disagreeing = count.min(PROVIDERS));
+}
+
+// An update, like the real method, which is paid for in cycles
+#[update]
+fn eth_call(_services: Reserved, _config: Option<Reserved>, args: CallArgs) -> MultiCallResult {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let contract = address(args.transaction.to.as_deref().unwrap_or_default());
+        let input = hex::decode(args.transaction.input.unwrap_or_default().trim_start_matches("0x"))
+            .unwrap_or_else(|_| ic_cdk::trap("input is not hex"));
+        let data = answer(&state, &contract, &input);
+        if state.disagreeing == 0 {
+            return MultiCallResult::Consistent(call_result(data));
+        }
+
+        // Each wrong provider gives a different wrong answer, so they never outvote
+        // the right one
+        let wrong = |provider: u8| match &data {
+            Some(data) if !data.is_empty() => {
+                let mut data = data.clone();
+                *data.last_mut().unwrap() ^= provider + 1;
+                Some(data)
+            }
+            _ => Some(uint_word(provider as u128 + 1)),
+        };
+        let results = (0..PROVIDERS)
+            .map(|provider| {
+                let data = if provider < state.disagreeing { wrong(provider) } else { data.clone() };
+                (RpcService::Provider(provider as u64), call_result(data))
+            })
+            .collect();
+        MultiCallResult::Inconsistent(results)
+    })
+}
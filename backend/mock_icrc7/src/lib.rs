@@ -0,0 +1,69 @@
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::export::Principal;
+use ic_cdk_macros::{query, update};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Local stand-in for an ICRC-7 NFT collection, for testing the main_canister's
+// ownership checks. Anyone may mint and burn; a token is owned by whoever it was
+// last minted to.
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct SupportedStandard {
+    name: String,
+    url: String,
+}
+
+thread_local! {
+    static OWNERS: RefCell<BTreeMap<Nat, Account>> = RefCell::new(BTreeMap::new());
+}
+
+// A missing subaccount is the all-zero one
+fn same_account(a: &Account, b: &Account) -> bool {
+    let subaccount = |account: &Account| account.subaccount.clone().unwrap_or_else(|| vec![0; 32]);
+    a.owner == b.owner && subaccount(a) == subaccount(b)
+}
+
+#[update]
+fn mint(token_id: Nat, to: Account) {
+    OWNERS.with(|owners| owners.borrow_mut().insert(token_id, to));
+}
+
+#[update]
+fn burn(token_id: Nat) {
+    OWNERS.with(|owners| owners.borrow_mut().remove(&token_id));
+}
+
+#[query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    OWNERS.with(|owners| {
+        let owners = owners.borrow();
+        token_ids.iter().map(|token_id| owners.get(token_id).cloned()).collect()
+    })
+}
+
+#[query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    OWNERS.with(|owners| {
+        let owners = owners.borrow();
+        accounts.iter()
+            .map(|account| Nat::from(owners.values().filter(|owner| same_account(owner, account)).count()))
+            .collect()
+    })
+}
+
+#[query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    ["ICRC-7", "ICRC-10"].iter()
+        .map(|name| SupportedStandard {
+            name: name.to_string(),
+            url: format!("https://github.com/dfinity/ICRC/ICRCs/{}", name),
+        })
+        .collect()
+}
@@ -0,0 +1,232 @@
+use candid::{CandidType, Deserialize, Func, Int, Nat};
+use ic_cdk::export::Principal;
+use ic_cdk_macros::{query, update};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Local stand-in for an ICRC-1 ledger with an ICRC-3 block log, for testing the
+// main_canister's balance lookups and snapshots. Anyone may mint, transfer and burn; nothing
+// is checked beyond what the block log needs. Blocks below `archive` are only
+// served through the archive callback, like a ledger that has spilled into an
+// archive canister.
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct BlockWithId {
+    id: Nat,
+    block: Value,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: Func,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(Default)]
+struct Ledger {
+    blocks: Vec<Value>,
+    archived: u64,
+    fee: u64,
+    balances: BTreeMap<(Principal, [u8; 32]), u64>,
+}
+
+const DECIMALS: u8 = 8;
+const SYMBOL: &str = "MOCK";
+
+// A missing subaccount is the all-zero one
+fn balance_key(account: &Account) -> (Principal, [u8; 32]) {
+    let mut subaccount = [0u8; 32];
+    if let Some(bytes) = &account.subaccount {
+        let len = bytes.len().min(32);
+        subaccount[..len].copy_from_slice(&bytes[..len]);
+    }
+    (account.owner, subaccount)
+}
+
+fn credit(account: &Account, amount: u64) {
+    LEDGER.with(|ledger| {
+        let mut ledger = ledger.borrow_mut();
+        let balance = ledger.balances.entry(balance_key(account)).or_default();
+        *balance = balance.saturating_add(amount);
+    });
+}
+
+fn debit(account: &Account, amount: u64) {
+    LEDGER.with(|ledger| {
+        let mut ledger = ledger.borrow_mut();
+        let balance = ledger.balances.entry(balance_key(account)).or_default();
+        *balance = balance.saturating_sub(amount);
+    });
+}
+
+fn fee() -> u64 {
+    LEDGER.with(|ledger| ledger.borrow().fee)
+}
+
+thread_local! {
+    static LEDGER: RefCell<Ledger> = RefCell::new(Ledger::default());
+}
+
+fn account_value(account: &Account) -> Value {
+    let mut parts = vec![Value::Blob(account.owner.as_slice().to_vec())];
+    if let Some(subaccount) = &account.subaccount {
+        parts.push(Value::Blob(subaccount.clone()));
+    }
+    Value::Array(parts)
+}
+
+fn append(btype: &str, tx: Vec<(&str, Value)>, charges_fee: bool) -> u64 {
+    LEDGER.with(|ledger| {
+        let mut ledger = ledger.borrow_mut();
+        let mut block = vec![
+            ("btype".to_string(), Value::Text(btype.to_string())),
+            ("ts".to_string(), Value::Nat(Nat::from(ic_cdk::api::time()))),
+        ];
+        if charges_fee {
+            block.push(("fee".to_string(), Value::Nat(Nat::from(ledger.fee))));
+        }
+        let tx = tx.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+        block.push(("tx".to_string(), Value::Map(tx)));
+        ledger.blocks.push(Value::Map(block));
+        ledger.blocks.len() as u64 - 1
+    })
+}
+
+#[update]
+fn set_fee(fee: u64) {
+    LEDGER.with(|ledger| ledger.borrow_mut().fee = fee);
+}
+
+#[update]
+fn mint(to: Account, amount: u64) -> u64 {
+    credit(&to, amount);
+    append("1mint", vec![("to", account_value(&to)), ("amt", Value::Nat(Nat::from(amount)))], false)
+}
+
+#[update]
+fn transfer(from: Account, to: Account, amount: u64) -> u64 {
+    debit(&from, amount.saturating_add(fee()));
+    credit(&to, amount);
+    append("1xfer", vec![
+        ("from", account_value(&from)),
+        ("to", account_value(&to)),
+        ("amt", Value::Nat(Nat::from(amount))),
+    ], true)
+}
+
+#[update]
+fn burn(from: Account, amount: u64) -> u64 {
+    debit(&from, amount);
+    append("1burn", vec![("from", account_value(&from)), ("amt", Value::Nat(Nat::from(amount)))], false)
+}
+
+#[query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    LEDGER.with(|ledger| {
+        Nat::from(ledger.borrow().balances.get(&balance_key(&account)).copied().unwrap_or(0))
+    })
+}
+
+#[query]
+fn icrc1_decimals() -> u8 {
+    DECIMALS
+}
+
+#[query]
+fn icrc1_symbol() -> String {
+    SYMBOL.to_string()
+}
+
+// Move every block below `up_to` behind the archive callback
+#[update]
+fn archive(up_to: u64) {
+    LEDGER.with(|ledger| {
+        let mut ledger = ledger.borrow_mut();
+        ledger.archived = up_to.min(ledger.blocks.len() as u64);
+    });
+}
+
+fn range(args: &GetBlocksArgs, from: u64, to: u64) -> (u64, u64) {
+    let start = u64::try_from(&args.start.0).unwrap_or(u64::MAX).max(from);
+    let length = u64::try_from(&args.length.0).unwrap_or(u64::MAX);
+    (start, start.saturating_add(length).min(to).max(start))
+}
+
+fn blocks(ledger: &Ledger, start: u64, end: u64) -> Vec<BlockWithId> {
+    (start..end)
+        .map(|id| BlockWithId { id: Nat::from(id), block: ledger.blocks[id as usize].clone() })
+        .collect()
+}
+
+#[query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    LEDGER.with(|ledger| {
+        let ledger = ledger.borrow();
+        let log_length = ledger.blocks.len() as u64;
+        let mut result = GetBlocksResult {
+            log_length: Nat::from(log_length),
+            blocks: Vec::new(),
+            archived_blocks: Vec::new(),
+        };
+
+        for requested in &args {
+            let (start, end) = range(requested, 0, ledger.archived);
+            if start < end {
+                result.archived_blocks.push(ArchivedBlocks {
+                    args: vec![GetBlocksArgs { start: Nat::from(start), length: Nat::from(end - start) }],
+                    callback: Func { principal: ic_cdk::id(), method: "get_archived_blocks".to_string() },
+                });
+            }
+            let (start, end) = range(requested, ledger.archived, log_length);
+            result.blocks.extend(blocks(&ledger, start, end));
+        }
+        result
+    })
+}
+
+// The archive callback handed out by icrc3_get_blocks
+#[query]
+fn get_archived_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    LEDGER.with(|ledger| {
+        let ledger = ledger.borrow();
+        let mut result = GetBlocksResult {
+            log_length: Nat::from(ledger.blocks.len() as u64),
+            blocks: Vec::new(),
+            archived_blocks: Vec::new(),
+        };
+        for requested in &args {
+            let (start, end) = range(requested, 0, ledger.archived);
+            result.blocks.extend(blocks(&ledger, start, end));
+        }
+        result
+    })
+}
@@ -1,10 +1,11 @@
 use candid::{CandidType, Deserialize};
 use serde::{Serialize};
-use ic_cdk_macros::{query, update};
+use ic_cdk_macros::{init, post_upgrade, query, update};
 use ic_cdk::api::call::call;
 use ic_cdk::api::time;
 use ic_cdk::export::Principal;
-use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable, memory_manager::VirtualMemory, DefaultMemoryImpl, Memory};
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable, memory_manager::VirtualMemory, DefaultMemoryImpl, Memory};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
 use serde_json::json;
 use serde_json::to_vec;
 use uuid::Uuid;
@@ -104,27 +105,143 @@ thread_local! {
         StableBTreeMap::new(VirtualMemory::new(DefaultMemoryImpl::default()));
 }
 
+// One manager hands out the virtual memory of every stable map below, so no two
+// of them can alias
+thread_local! {
+    static MEMORY_MANAGER: std::cell::RefCell<MemoryManager<DefaultMemoryImpl>> =
+        std::cell::RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+fn stable_memory(id: u8) -> VirtualMemory<DefaultMemoryImpl> {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(MemoryId::new(id)))
+}
+
 // Storage for verification results
 thread_local! {
     static VERIFICATION_RESULTS: std::cell::RefCell<StableBTreeMap<StorableString, VerificationResult, VirtualMemory<DefaultMemoryImpl>>> = 
-        std::cell::RefCell::new(StableBTreeMap::init(
-            ic_stable_structures::memory_manager::MemoryManager::init(DefaultMemoryImpl::default())
-                .get(2)
-        ));
+        std::cell::RefCell::new(StableBTreeMap::init(stable_memory(2)));
+}
+
+// Published merkle roots by epoch, kept in stable memory so that outstanding proofs
+// stay verifiable across upgrades. A proof is accepted while fewer than
+// `max_epochs` newer roots have been published and its root is at most
+// `max_age_ns` old; the root admin sets both with set_merkle_root_window.
+const MERKLE_ROOT_HISTORY_CAPACITY: u64 = 256;
+const MAX_MERKLE_ROOT_LEN: usize = 128;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MerkleRootRecord {
+    pub epoch: u64,
+    pub root: String,
+    pub published_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct MerkleRootWindow {
+    pub max_epochs: u64,
+    pub max_age_ns: u64,
+}
+
+const DEFAULT_MERKLE_ROOT_WINDOW: MerkleRootWindow = MerkleRootWindow {
+    max_epochs: 16,
+    max_age_ns: 24 * 60 * 60 * 1_000_000_000,
+};
+
+impl Storable for MerkleRootRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+}
+
+impl BoundedStorable for MerkleRootRecord {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for MerkleRootWindow {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
 }
 
-// Storage for merkle root
 thread_local! {
-    static MERKLE_ROOT: std::cell::RefCell<String> = std::cell::RefCell::new(String::new());
+    static MERKLE_ROOTS: std::cell::RefCell<StableBTreeMap<u64, MerkleRootRecord, VirtualMemory<DefaultMemoryImpl>>> =
+        std::cell::RefCell::new(StableBTreeMap::init(stable_memory(4)));
+
+    static MERKLE_ROOT_WINDOW: std::cell::RefCell<StableCell<MerkleRootWindow, VirtualMemory<DefaultMemoryImpl>>> =
+        std::cell::RefCell::new(StableCell::init(stable_memory(5), DEFAULT_MERKLE_ROOT_WINDOW)
+            .expect("failed to initialize the merkle root window"));
+}
+
+// Principal allowed to change the root window, empty until set. ic-cdk 0.7 cannot
+// ask whether the caller is a controller, so the installer is recorded instead;
+// an install that predates this cell records the principal upgrading it.
+thread_local! {
+    static MERKLE_ROOT_ADMIN: std::cell::RefCell<StableCell<StorableString, VirtualMemory<DefaultMemoryImpl>>> =
+        std::cell::RefCell::new(StableCell::init(stable_memory(6), StorableString(String::new()))
+            .expect("failed to initialize the merkle root admin"));
+}
+
+fn seed_merkle_root_admin(principal: Principal) {
+    if principal == Principal::anonymous() {
+        return;
+    }
+    MERKLE_ROOT_ADMIN.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        if cell.get().0.is_empty() {
+            cell.set(StorableString(principal.to_text()))
+                .expect("failed to write the merkle root admin");
+        }
+    });
+}
+
+fn is_merkle_root_admin(principal: &Principal) -> bool {
+    MERKLE_ROOT_ADMIN.with(|cell| cell.borrow().get().0 == principal.to_text())
+}
+
+#[init]
+fn init() {
+    seed_merkle_root_admin(ic_cdk::caller());
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    seed_merkle_root_admin(ic_cdk::caller());
+}
+
+fn current_merkle_root() -> Option<MerkleRootRecord> {
+    // At most MERKLE_ROOT_HISTORY_CAPACITY entries, so a scan is cheap
+    MERKLE_ROOTS.with(|roots| roots.borrow().iter().last().map(|(_, record)| record))
+}
+
+fn merkle_root_window() -> MerkleRootWindow {
+    MERKLE_ROOT_WINDOW.with(|window| *window.borrow().get())
+}
+
+fn is_root_accepted(root: &str, now: u64) -> bool {
+    let Some(latest) = current_merkle_root() else { return false };
+    let window = merkle_root_window();
+    MERKLE_ROOTS.with(|roots| {
+        roots.borrow().iter().any(|(_, record)| {
+            record.root == root
+                && latest.epoch - record.epoch < window.max_epochs
+                && now.saturating_sub(record.published_at) <= window.max_age_ns
+        })
+    })
 }
 
 // Storage for token proofs
 thread_local! {
     static TOKEN_PROOFS: std::cell::RefCell<StableBTreeMap<StorableString, TokenProofResult, VirtualMemory<DefaultMemoryImpl>>> = 
-        std::cell::RefCell::new(StableBTreeMap::init(
-            ic_stable_structures::memory_manager::MemoryManager::init(DefaultMemoryImpl::default())
-                .get(3)
-        ));
+        std::cell::RefCell::new(StableBTreeMap::init(stable_memory(3)));
 }
 
 #[update]
@@ -380,10 +497,9 @@ async fn generate_token_proof(request: TokenProofRequest) -> Result<TokenProofRe
     }
 
     // Get current merkle root
-    let merkle_root = MERKLE_ROOT.with(|root| root.borrow().clone());
-    if merkle_root.is_empty() {
-        return Err("Merkle root not initialized".to_string());
-    }
+    let merkle_root = current_merkle_root()
+        .ok_or_else(|| "Merkle root not initialized".to_string())?
+        .root;
 
     // Generate proof data (in production, this would use the ZK circuit)
     let proof_data = generate_zk_token_proof(&request, &merkle_root);
@@ -417,9 +533,8 @@ fn verify_token_proof(request: TokenVerificationRequest) -> bool {
                 return false;
             }
             
-            // Verify the merkle root is still valid
-            let current_root = MERKLE_ROOT.with(|root| root.borrow().clone());
-            if proof.merkle_root != current_root {
+            // Verify the merkle root is still inside the accepted window
+            if !is_root_accepted(&proof.merkle_root, time()) {
                 return false;
             }
             
@@ -438,14 +553,39 @@ fn verify_token_proof(request: TokenVerificationRequest) -> bool {
 
 #[query]
 fn get_merkle_root() -> Result<String, String> {
-    MERKLE_ROOT.with(|root| {
-        let root = root.borrow().clone();
-        if root.is_empty() {
-            Err("Merkle root not initialized".to_string())
-        } else {
-            Ok(root)
-        }
-    })
+    current_merkle_root()
+        .map(|record| record.root)
+        .ok_or_else(|| "Merkle root not initialized".to_string())
+}
+
+// Newest first
+#[query]
+fn get_merkle_roots() -> Vec<MerkleRootRecord> {
+    let mut records: Vec<MerkleRootRecord> =
+        MERKLE_ROOTS.with(|roots| roots.borrow().iter().map(|(_, record)| record).collect());
+    records.reverse();
+    records
+}
+
+#[query]
+fn get_merkle_root_window() -> MerkleRootWindow {
+    merkle_root_window()
+}
+
+#[update]
+fn set_merkle_root_window(window: MerkleRootWindow) -> Result<(), String> {
+    if !is_merkle_root_admin(&ic_cdk::caller()) {
+        return Err("Only the merkle root admin can change the merkle root window".to_string());
+    }
+    if window.max_epochs == 0 || window.max_epochs > MERKLE_ROOT_HISTORY_CAPACITY {
+        return Err(format!("max_epochs must be between 1 and {}", MERKLE_ROOT_HISTORY_CAPACITY));
+    }
+    MERKLE_ROOT_WINDOW.with(|cell| {
+        cell.borrow_mut()
+            .set(window)
+            .expect("failed to write the merkle root window");
+    });
+    Ok(())
 }
 
 #[update]
@@ -453,9 +593,21 @@ fn update_merkle_root(root: String) -> Result<(), String> {
     if root.is_empty() {
         return Err("Merkle root cannot be empty".to_string());
     }
+    if root.len() > MAX_MERKLE_ROOT_LEN {
+        return Err(format!("Merkle root must be at most {} bytes", MAX_MERKLE_ROOT_LEN));
+    }
     
-    MERKLE_ROOT.with(|current_root| {
-        *current_root.borrow_mut() = root;
+    let latest = current_merkle_root();
+    if latest.as_ref().map_or(false, |latest| latest.root == root) {
+        return Ok(());
+    }
+    let epoch = latest.map_or(1, |latest| latest.epoch + 1);
+    MERKLE_ROOTS.with(|roots| {
+        let mut roots = roots.borrow_mut();
+        roots.insert(epoch, MerkleRootRecord { epoch, root, published_at: time() });
+        if epoch > MERKLE_ROOT_HISTORY_CAPACITY {
+            roots.remove(&(epoch - MERKLE_ROOT_HISTORY_CAPACITY));
+        }
     });
     
     Ok(())
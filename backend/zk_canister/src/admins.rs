@@ -0,0 +1,119 @@
+use crate::memory::{self, Memory, StorableString};
+use crate::registry::KeyError;
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Principals allowed to manage keys and uploads, with the time each was added.
+// ic-cdk 0.7 cannot ask the system whether the caller is a controller, so the
+// canister keeps its own list. init adds the installer; an install that predates
+// the list gains the principal that upgrades it.
+thread_local! {
+    static ADMINS: RefCell<StableBTreeMap<StorableString, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::ADMINS)));
+}
+
+// (Re)open the admin map; run from post_upgrade
+pub fn init() {
+    ADMINS.with(|admins| {
+        *admins.borrow_mut() = StableBTreeMap::init(memory::get(memory::ADMINS));
+    });
+}
+
+fn key(principal: &Principal) -> StorableString {
+    StorableString(principal.to_text())
+}
+
+pub fn is_admin(principal: &Principal) -> bool {
+    ADMINS.with(|admins| admins.borrow().contains_key(&key(principal)))
+}
+
+// Make `principal` the first admin, unless there already is one. The anonymous
+// principal is never added.
+pub fn seed(principal: Principal, now: u64) {
+    if principal == Principal::anonymous() {
+        return;
+    }
+    ADMINS.with(|admins| {
+        let mut admins = admins.borrow_mut();
+        if admins.is_empty() {
+            admins.insert(key(&principal), now);
+        }
+    });
+}
+
+pub fn add(principal: Principal, now: u64) -> Result<(), KeyError> {
+    if principal == Principal::anonymous() {
+        return Err(KeyError::NotAuthorized);
+    }
+    ADMINS.with(|admins| {
+        let mut admins = admins.borrow_mut();
+        if !admins.contains_key(&key(&principal)) {
+            admins.insert(key(&principal), now);
+        }
+    });
+    Ok(())
+}
+
+// The last admin cannot be removed, which would lock every admin endpoint for good
+pub fn remove(principal: Principal) -> Result<(), KeyError> {
+    ADMINS.with(|admins| {
+        let mut admins = admins.borrow_mut();
+        if !admins.contains_key(&key(&principal)) {
+            return Ok(());
+        }
+        if admins.len() == 1 {
+            return Err(KeyError::LastAdmin);
+        }
+        admins.remove(&key(&principal));
+        Ok(())
+    })
+}
+
+pub fn list() -> Vec<Principal> {
+    ADMINS.with(|admins| {
+        admins
+            .borrow()
+            .iter()
+            .filter_map(|(principal, _)| Principal::from_text(principal.0).ok())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    #[test]
+    fn seeds_once_and_keeps_the_last_admin() {
+        seed(Principal::anonymous(), 0);
+        assert!(list().is_empty());
+
+        seed(principal(1), 1);
+        seed(principal(2), 2);
+        assert_eq!(list(), vec![principal(1)]);
+        assert!(is_admin(&principal(1)));
+        assert!(!is_admin(&principal(2)));
+
+        add(principal(2), 3).unwrap();
+        assert_eq!(add(Principal::anonymous(), 3), Err(KeyError::NotAuthorized));
+        remove(principal(1)).unwrap();
+        assert_eq!(list(), vec![principal(2)]);
+        assert_eq!(remove(principal(2)), Err(KeyError::LastAdmin));
+        assert!(is_admin(&principal(2)));
+    }
+
+    #[test]
+    fn admins_survive_upgrade() {
+        seed(principal(1), 1);
+
+        memory::simulate_upgrade();
+        init();
+
+        assert!(is_admin(&principal(1)));
+    }
+}
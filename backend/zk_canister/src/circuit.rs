@@ -0,0 +1,280 @@
+use crate::poseidon;
+use ark_bn254::{Bn254, Fr};
+use ark_groth16::{Groth16, Proof, ProvingKey, VerifyingKey};
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+use ark_snark::{CircuitSpecificSetupSNARK, SNARK};
+use ark_std::rand::{CryptoRng, RngCore};
+
+// Fixed depth of the balance tree, mirroring `merkle_path: [Field; 32]` in circuits/src/main.nr
+pub const TREE_DEPTH: usize = 32;
+
+// Public part of the token-ownership relation, in the order the circuit exposes it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Statement {
+    pub token_id: Fr,
+    pub min_balance: u64,
+    pub merkle_root: Fr,
+    pub tree_depth: u8,
+}
+
+impl Statement {
+    pub fn public_inputs(&self) -> Vec<Fr> {
+        vec![
+            self.token_id,
+            Fr::from(self.min_balance),
+            self.merkle_root,
+            Fr::from(self.tree_depth as u64),
+        ]
+    }
+}
+
+// Private part of the relation: the holder's leaf and its authentication path
+#[derive(Clone, Debug, PartialEq)]
+pub struct Witness {
+    pub wallet: Fr,
+    pub balance: u64,
+    pub merkle_path: Vec<Fr>,
+    pub path_indices: Vec<bool>,
+}
+
+impl Default for Witness {
+    fn default() -> Self {
+        Witness {
+            wallet: Fr::from(0u64),
+            balance: 0,
+            merkle_path: vec![Fr::from(0u64); TREE_DEPTH],
+            path_indices: vec![false; TREE_DEPTH],
+        }
+    }
+}
+
+// Leaf commitment, identical to `compute_leaf` in the Noir circuit
+pub fn leaf_hash(wallet: Fr, token_id: Fr, balance: u64) -> Fr {
+    poseidon::hash_3([wallet, token_id, Fr::from(balance)])
+}
+
+// Native version of `compute_merkle_root`: index bit 0 keeps the current node on the left
+pub fn compute_root(leaf: Fr, merkle_path: &[Fr], path_indices: &[bool], depth: u8) -> Fr {
+    merkle_path
+        .iter()
+        .zip(path_indices.iter())
+        .take(depth as usize)
+        .fold(leaf, |current, (sibling, is_right)| {
+            if *is_right {
+                poseidon::hash_2([*sibling, current])
+            } else {
+                poseidon::hash_2([current, *sibling])
+            }
+        })
+}
+
+// R1CS form of circuits/src/main.nr: balance >= min_balance and
+// Poseidon(wallet, token_id, balance) is a leaf under merkle_root at depth tree_depth.
+#[derive(Clone, Debug, Default)]
+pub struct TokenOwnershipCircuit {
+    pub statement: Statement,
+    pub witness: Witness,
+}
+
+impl TokenOwnershipCircuit {
+    pub fn new(statement: Statement, witness: Witness) -> Self {
+        TokenOwnershipCircuit { statement, witness }
+    }
+
+    // Evaluate the relation natively so callers get a readable error instead of a failed proof
+    pub fn check(&self) -> Result<(), String> {
+        let Statement { token_id, min_balance, merkle_root, tree_depth } = &self.statement;
+        let witness = &self.witness;
+
+        if *tree_depth == 0 || *tree_depth as usize > TREE_DEPTH {
+            return Err(format!("Tree depth must be between 1 and {}", TREE_DEPTH));
+        }
+        if witness.merkle_path.len() != TREE_DEPTH || witness.path_indices.len() != TREE_DEPTH {
+            return Err(format!("Merkle path must be padded to {} levels", TREE_DEPTH));
+        }
+        if witness.balance < *min_balance {
+            return Err("Balance is below the requested minimum".to_string());
+        }
+
+        let leaf = leaf_hash(witness.wallet, *token_id, witness.balance);
+        let root = compute_root(leaf, &witness.merkle_path, &witness.path_indices, *tree_depth);
+        if root != *merkle_root {
+            return Err("Merkle path does not lead to the given root".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for TokenOwnershipCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let statement = self.statement;
+        let witness = self.witness;
+
+        // Public inputs
+        let token_id = FpVar::new_input(cs.clone(), || Ok(statement.token_id))?;
+        let min_balance = FpVar::new_input(cs.clone(), || Ok(Fr::from(statement.min_balance)))?;
+        let merkle_root = FpVar::new_input(cs.clone(), || Ok(statement.merkle_root))?;
+        let tree_depth = FpVar::new_input(cs.clone(), || Ok(Fr::from(statement.tree_depth as u64)))?;
+
+        // Private inputs
+        let wallet = FpVar::new_witness(cs.clone(), || Ok(witness.wallet))?;
+        let balance = FpVar::new_witness(cs.clone(), || Ok(Fr::from(witness.balance)))?;
+
+        // balance >= min_balance, with both sides range-checked to 64 bits so the
+        // difference cannot wrap around the field modulus
+        let difference = witness.balance.wrapping_sub(statement.min_balance);
+        enforce_u64(cs.clone(), &balance, witness.balance)?;
+        enforce_u64(cs.clone(), &min_balance, statement.min_balance)?;
+        enforce_u64(cs.clone(), &(&balance - &min_balance), difference)?;
+
+        // Level i of the path is used iff i < tree_depth. The flags must be a run of
+        // ones followed by zeros whose sum equals the public depth.
+        let mut active = Vec::with_capacity(TREE_DEPTH);
+        for level in 0..TREE_DEPTH {
+            let flag = Boolean::new_witness(cs.clone(), || Ok(level < statement.tree_depth as usize))?;
+            if let Some(previous) = active.last() {
+                let previous: &Boolean<Fr> = previous;
+                flag.and(&previous.not())?.enforce_equal(&Boolean::FALSE)?;
+            } else {
+                flag.enforce_equal(&Boolean::TRUE)?;
+            }
+            active.push(flag);
+        }
+        let depth_sum = active
+            .iter()
+            .fold(FpVar::zero(), |acc, flag| acc + FpVar::from(flag.clone()));
+        depth_sum.enforce_equal(&tree_depth)?;
+
+        let mut current = poseidon::hash_gadget(&[wallet, token_id, balance])?;
+        for (level, is_active) in active.iter().enumerate() {
+            let sibling = FpVar::new_witness(cs.clone(), || {
+                witness.merkle_path.get(level).copied().ok_or(SynthesisError::AssignmentMissing)
+            })?;
+            let is_right = Boolean::new_witness(cs.clone(), || {
+                witness.path_indices.get(level).copied().ok_or(SynthesisError::AssignmentMissing)
+            })?;
+
+            let left = is_right.select(&sibling, &current)?;
+            let right = is_right.select(&current, &sibling)?;
+            let parent = poseidon::hash_gadget(&[left, right])?;
+            current = is_active.select(&parent, &current)?;
+        }
+
+        current.enforce_equal(&merkle_root)
+    }
+}
+
+fn enforce_u64(
+    cs: ConstraintSystemRef<Fr>,
+    value: &FpVar<Fr>,
+    native: u64,
+) -> Result<(), SynthesisError> {
+    let bits = (0..64)
+        .map(|i| Boolean::new_witness(cs.clone(), || Ok((native >> i) & 1 == 1)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Boolean::le_bits_to_fp_var(&bits)?.enforce_equal(value)
+}
+
+// Circuit-specific Groth16 setup. Keys produced this way are only as trustworthy as
+// the randomness behind `rng`; production keys should come from a ceremony.
+pub fn generate_keys<R: RngCore + CryptoRng>(
+    rng: &mut R,
+) -> Result<(ProvingKey<Bn254>, VerifyingKey<Bn254>), SynthesisError> {
+    Groth16::<Bn254>::setup(TokenOwnershipCircuit::default(), rng)
+}
+
+pub fn prove<R: RngCore + CryptoRng>(
+    proving_key: &ProvingKey<Bn254>,
+    circuit: TokenOwnershipCircuit,
+    rng: &mut R,
+) -> Result<Proof<Bn254>, String> {
+    circuit.check()?;
+    Groth16::<Bn254>::prove(proving_key, circuit, rng)
+        .map_err(|e| format!("Proof generation failed: {}", e))
+}
+
+pub fn verify(
+    verifying_key: &VerifyingKey<Bn254>,
+    statement: &Statement,
+    proof: &Proof<Bn254>,
+) -> Result<bool, String> {
+    if statement.tree_depth == 0 || statement.tree_depth as usize > TREE_DEPTH {
+        return Ok(false);
+    }
+    Groth16::<Bn254>::verify(verifying_key, &statement.public_inputs(), proof)
+        .map_err(|e| format!("Proof verification failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_std::rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    // Builds a tree of depth 3 holding the leaf at index 5 and returns a matching circuit
+    fn sample_circuit() -> TokenOwnershipCircuit {
+        let token_id = Fr::from(1u64);
+        let wallet = Fr::from(456u64);
+        let balance = 200;
+        let depth = 3u8;
+
+        let mut merkle_path: Vec<Fr> = (0..TREE_DEPTH as u64).map(|i| Fr::from(1000 + i)).collect();
+        merkle_path[0] = leaf_hash(Fr::from(789u64), token_id, 50);
+        let mut path_indices = vec![false; TREE_DEPTH];
+        path_indices[0] = true;
+        path_indices[2] = true;
+
+        let leaf = leaf_hash(wallet, token_id, balance);
+        let merkle_root = compute_root(leaf, &merkle_path, &path_indices, depth);
+
+        TokenOwnershipCircuit::new(
+            Statement { token_id, min_balance: 100, merkle_root, tree_depth: depth },
+            Witness { wallet, balance, merkle_path, path_indices },
+        )
+    }
+
+    #[test]
+    fn proves_and_verifies_from_public_inputs() {
+        let mut rng = ChaCha20Rng::seed_from_u64(7);
+        let (pk, vk) = generate_keys(&mut rng).unwrap();
+        let circuit = sample_circuit();
+        let statement = circuit.statement.clone();
+
+        let proof = prove(&pk, circuit, &mut rng).unwrap();
+        assert!(verify(&vk, &statement, &proof).unwrap());
+
+        // The same proof must not verify for a different statement
+        let stronger = Statement { min_balance: 201, ..statement.clone() };
+        assert!(!verify(&vk, &stronger, &proof).unwrap());
+        let other_root = Statement { merkle_root: Fr::from(123u64), ..statement };
+        assert!(!verify(&vk, &other_root, &proof).unwrap());
+    }
+
+    #[test]
+    fn rejects_unsatisfied_relation() {
+        let mut circuit = sample_circuit();
+        circuit.statement.min_balance = 500;
+        assert!(circuit.check().is_err());
+
+        let mut circuit = sample_circuit();
+        circuit.witness.path_indices[1] = true;
+        assert!(circuit.check().is_err());
+    }
+
+    #[test]
+    fn constraints_are_satisfied_by_valid_witness() {
+        use ark_relations::r1cs::ConstraintSystem;
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        sample_circuit().generate_constraints(cs.clone()).unwrap();
+        assert!(cs.is_satisfied().unwrap());
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let mut circuit = sample_circuit();
+        circuit.witness.balance = 99;
+        circuit.generate_constraints(cs.clone()).unwrap();
+        assert!(!cs.is_satisfied().unwrap());
+    }
+}
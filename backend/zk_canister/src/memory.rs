@@ -0,0 +1,66 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Stable memory layout of the zk_canister. Every stable structure gets its own
+// virtual memory from the single MemoryManager below. Never renumber or reuse an id.
+//
+//   id | structure       | contents
+//   ---+-----------------+------------------------------------------
+//    0 | KEY_RECORDS     | param_id -> KeyRecord
+//    1 | PROVING_KEYS    | (param_id, chunk index) -> proving key bytes
+//    2 | UPLOAD_SESSIONS | upload_id -> UploadSession
+//    3 | UPLOAD_CHUNKS   | ("upload-<id>", chunk index) -> staged bytes
+//    4 | NEXT_UPLOAD_ID  | u64 counter
+//    5 | VERIFIED_PROOFS | proof_id -> IssuedProof
+//    6 | ADMINS          | principal -> time added
+pub const KEY_RECORDS: MemoryId = MemoryId::new(0);
+pub const PROVING_KEYS: MemoryId = MemoryId::new(1);
+pub const UPLOAD_SESSIONS: MemoryId = MemoryId::new(2);
+pub const UPLOAD_CHUNKS: MemoryId = MemoryId::new(3);
+pub const NEXT_UPLOAD_ID: MemoryId = MemoryId::new(4);
+pub const VERIFIED_PROOFS: MemoryId = MemoryId::new(5);
+pub const ADMINS: MemoryId = MemoryId::new(6);
+
+thread_local! {
+    // Off-chain (in unit tests) this is a heap vector; keeping one instance lets
+    // tests rebuild the canister state from it the way an upgrade would.
+    static STABLE_MEMORY: DefaultMemoryImpl = DefaultMemoryImpl::default();
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(STABLE_MEMORY.with(|memory| memory.clone())));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+// Drop everything derived from stable memory, as a canister upgrade does with the
+// heap. Callers must then run post_upgrade to reopen the stable structures.
+#[cfg(test)]
+pub fn simulate_upgrade() {
+    MEMORY_MANAGER.with(|manager| {
+        *manager.borrow_mut() = MemoryManager::init(STABLE_MEMORY.with(|memory| memory.clone()));
+    });
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StorableString(pub String);
+
+impl Storable for StorableString {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.as_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StorableString(String::from_utf8(bytes.to_vec()).unwrap())
+    }
+}
+
+impl BoundedStorable for StorableString {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
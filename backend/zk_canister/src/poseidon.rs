@@ -0,0 +1,110 @@
+use ark_bn254::Fr;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::SynthesisError;
+use light_poseidon::parameters::bn254_x5::get_poseidon_parameters;
+use light_poseidon::{Poseidon, PoseidonHasher, PoseidonParameters};
+
+// Poseidon over BN254 with the circom/Noir parameters (x^5 S-box, 8 full rounds).
+// `hash_2` and `hash_3` match `std::hash::poseidon::bn254::hash_2` / `hash_3`
+// used by circuits/src/main.nr, so leaves and nodes agree with the Noir circuit.
+
+pub fn hash_2(inputs: [Fr; 2]) -> Fr {
+    hash(&inputs)
+}
+
+pub fn hash_3(inputs: [Fr; 3]) -> Fr {
+    hash(&inputs)
+}
+
+fn hash(inputs: &[Fr]) -> Fr {
+    Poseidon::<Fr>::new_circom(inputs.len())
+        .and_then(|mut poseidon| poseidon.hash(inputs))
+        .expect("supported Poseidon width")
+}
+
+// In-circuit counterpart of `hash`. Each S-box costs three constraints; the
+// round constants and MDS mixing are linear and come for free.
+pub fn hash_gadget(inputs: &[FpVar<Fr>]) -> Result<FpVar<Fr>, SynthesisError> {
+    let width = inputs.len() + 1;
+    let params: PoseidonParameters<Fr> =
+        get_poseidon_parameters(width as u8).map_err(|_| SynthesisError::Unsatisfiable)?;
+
+    let mut state = Vec::with_capacity(width);
+    state.push(FpVar::zero());
+    state.extend(inputs.iter().cloned());
+
+    let half_full = params.full_rounds / 2;
+    let total_rounds = params.full_rounds + params.partial_rounds;
+
+    for round in 0..total_rounds {
+        for (i, element) in state.iter_mut().enumerate() {
+            *element += params.ark[round * width + i];
+        }
+
+        let is_full_round = round < half_full || round >= half_full + params.partial_rounds;
+        if is_full_round {
+            for element in state.iter_mut() {
+                *element = sbox(element)?;
+            }
+        } else {
+            state[0] = sbox(&state[0])?;
+        }
+
+        state = (0..width)
+            .map(|i| {
+                state
+                    .iter()
+                    .zip(params.mds[i].iter())
+                    .fold(FpVar::zero(), |acc, (element, coeff)| acc + element * *coeff)
+            })
+            .collect();
+    }
+
+    Ok(state.swap_remove(0))
+}
+
+fn sbox(x: &FpVar<Fr>) -> Result<FpVar<Fr>, SynthesisError> {
+    let x2 = x.square()?;
+    let x4 = x2.square()?;
+    Ok(x4 * x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_ff::PrimeField;
+    use ark_relations::r1cs::ConstraintSystem;
+    use std::str::FromStr;
+
+    // Reference values from circomlib's poseidon test vectors, which Noir's
+    // bn254 Poseidon reproduces.
+    #[test]
+    fn matches_circom_vectors() {
+        let expected_2 = Fr::from_str(
+            "7853200120776062878684798364095072458815029376092732009249414926327459813530",
+        )
+        .unwrap();
+        assert_eq!(hash_2([Fr::from(1u64), Fr::from(2u64)]), expected_2);
+
+        let expected_3 = Fr::from_str(
+            "6542985608222806190361240322586112750744169038454362455181422643027100751666",
+        )
+        .unwrap();
+        assert_eq!(hash_3([Fr::from(1u64), Fr::from(2u64), Fr::from(3u64)]), expected_3);
+    }
+
+    #[test]
+    fn gadget_matches_native() {
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let inputs = [Fr::from(11u64), Fr::from_be_bytes_mod_order(&[7u8; 32])];
+        let vars: Vec<_> = inputs
+            .iter()
+            .map(|x| FpVar::new_witness(cs.clone(), || Ok(*x)).unwrap())
+            .collect();
+
+        let out = hash_gadget(&vars).unwrap();
+        assert_eq!(out.value().unwrap(), hash_2(inputs));
+        assert!(cs.is_satisfied().unwrap());
+    }
+}
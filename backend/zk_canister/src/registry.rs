@@ -0,0 +1,427 @@
+use crate::memory::{self, Memory, StorableString};
+use ark_bn254::Bn254;
+use ark_groth16::{ProvingKey, VerifyingKey};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Proving keys are split into chunks so they fit bounded stable-map values
+pub const PROVING_KEY_CHUNK_SIZE: usize = 16 * 1024;
+
+const MAX_CIRCUIT_NAME_LEN: usize = 64;
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum KeyStatus {
+    // Uploaded but not yet usable
+    Pending,
+    // Accepted by prove_ownership and the verify endpoints
+    Active,
+    // Permanently rejected; kept for audit
+    Retired,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct KeyRecord {
+    pub param_id: String,
+    pub circuit: String,
+    pub version: u32,
+    pub status: KeyStatus,
+    pub verifying_key: Vec<u8>,
+    pub verifying_key_hash: String,
+    pub proving_key_hash: Option<String>,
+    pub proving_key_size: u64,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum KeyError {
+    NotAuthorized,
+    InvalidCircuitName(String),
+    InvalidKey(String),
+    UnknownKey(String),
+    InactiveKey(String),
+    RetiredKey(String),
+    MissingProvingKey(String),
+    SetupFailed(String),
+    LastAdmin,
+}
+
+impl Storable for KeyRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl BoundedStorable for KeyRecord {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Chunk `index` of the blob stored under `owner`. Ordering by (owner, index) keeps
+// the chunks of one blob contiguous so they can be read back with a range scan.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChunkKey {
+    pub owner: String,
+    pub index: u32,
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.index.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.owner.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ChunkKey {
+            index: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            owner: String::from_utf8(bytes[4..].to_vec()).unwrap(),
+        }
+    }
+}
+
+impl BoundedStorable for ChunkKey {
+    const MAX_SIZE: u32 = 4 + StorableString::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk(pub Vec<u8>);
+
+impl Storable for Chunk {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Chunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for Chunk {
+    const MAX_SIZE: u32 = PROVING_KEY_CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static KEY_RECORDS: RefCell<StableBTreeMap<StorableString, KeyRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::KEY_RECORDS)));
+
+    static PROVING_KEYS: RefCell<StableBTreeMap<ChunkKey, Chunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::PROVING_KEYS)));
+}
+
+// (Re)open the registry's stable maps; run from post_upgrade
+pub fn init() {
+    KEY_RECORDS.with(|records| {
+        *records.borrow_mut() = StableBTreeMap::init(memory::get(memory::KEY_RECORDS));
+    });
+    PROVING_KEYS.with(|chunks| {
+        *chunks.borrow_mut() = StableBTreeMap::init(memory::get(memory::PROVING_KEYS));
+    });
+}
+
+pub fn content_hash(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+pub fn validate_circuit_name(circuit: &str) -> Result<(), KeyError> {
+    let valid = !circuit.is_empty()
+        && circuit.len() <= MAX_CIRCUIT_NAME_LEN
+        && circuit.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(KeyError::InvalidCircuitName(format!(
+            "Circuit names must be 1-{} characters of [a-z0-9_-]",
+            MAX_CIRCUIT_NAME_LEN
+        )))
+    }
+}
+
+fn next_version(circuit: &str) -> u32 {
+    KEY_RECORDS.with(|records| {
+        records.borrow()
+            .iter()
+            .filter(|(_, record)| record.circuit == circuit)
+            .map(|(_, record)| record.version)
+            .max()
+            .unwrap_or(0)
+            + 1
+    })
+}
+
+// Register a new key version for `circuit`. With a proving key the verifying key is
+// taken from it; otherwise the entry can only be used for verification.
+pub fn register(
+    circuit: &str,
+    proving_key: Option<&[u8]>,
+    verifying_key: Option<&[u8]>,
+    now: u64,
+) -> Result<KeyRecord, KeyError> {
+    validate_circuit_name(circuit)?;
+
+    let verifying_key = match (proving_key, verifying_key) {
+        (Some(pk_bytes), _) => {
+            let pk = ProvingKey::<Bn254>::deserialize_uncompressed_unchecked(pk_bytes)
+                .map_err(|e| KeyError::InvalidKey(format!("Malformed proving key: {}", e)))?;
+            let mut vk_bytes = Vec::new();
+            pk.vk.serialize_compressed(&mut vk_bytes)
+                .map_err(|e| KeyError::InvalidKey(e.to_string()))?;
+            if let Some(expected) = verifying_key {
+                if expected != vk_bytes.as_slice() {
+                    return Err(KeyError::InvalidKey(
+                        "Verifying key does not belong to the proving key".to_string(),
+                    ));
+                }
+            }
+            vk_bytes
+        }
+        (None, Some(vk_bytes)) => {
+            VerifyingKey::<Bn254>::deserialize_compressed(vk_bytes)
+                .map_err(|e| KeyError::InvalidKey(format!("Malformed verifying key: {}", e)))?;
+            vk_bytes.to_vec()
+        }
+        (None, None) => {
+            return Err(KeyError::InvalidKey("No key material supplied".to_string()));
+        }
+    };
+
+    if let Some(pk_bytes) = proving_key {
+        let record = insert_record(circuit, verifying_key, Some(content_hash(pk_bytes)), pk_bytes.len() as u64, now);
+        write_proving_key(&record.param_id, pk_bytes);
+        Ok(record)
+    } else {
+        Ok(insert_record(circuit, verifying_key, None, 0, now))
+    }
+}
+
+// Register the next version of `circuit` for a proving key of `size` bytes that is
+// installed later, chunk by chunk, with write_proving_key_chunk. Until
+// finish_proving_key the record has no proving_key_hash and cannot be activated.
+// `verifying_key` is the compressed verifying key the caller took from the proving key.
+pub fn reserve_proving_key(circuit: &str, verifying_key: &[u8], size: u64, now: u64) -> Result<KeyRecord, KeyError> {
+    validate_circuit_name(circuit)?;
+    VerifyingKey::<Bn254>::deserialize_compressed(verifying_key)
+        .map_err(|e| KeyError::InvalidKey(format!("Malformed verifying key: {}", e)))?;
+    Ok(insert_record(circuit, verifying_key.to_vec(), None, size, now))
+}
+
+pub fn write_proving_key_chunk(param_id: &str, index: u32, chunk: Chunk) {
+    PROVING_KEYS.with(|chunks| {
+        chunks.borrow_mut().insert(ChunkKey { owner: param_id.to_string(), index }, chunk);
+    });
+}
+
+// Mark a reserved proving key as fully installed, its content hash being `hash`
+pub fn finish_proving_key(param_id: &str, hash: &str, now: u64) -> Result<KeyRecord, KeyError> {
+    KEY_RECORDS.with(|records| {
+        let mut records = records.borrow_mut();
+        let key = StorableString(param_id.to_string());
+        let mut record = records.get(&key)
+            .ok_or_else(|| KeyError::UnknownKey(param_id.to_string()))?;
+        if !installing(&record) {
+            return Err(KeyError::InvalidKey(format!("{} is not awaiting a proving key", param_id)));
+        }
+        record.proving_key_hash = Some(hash.to_string());
+        record.updated_at = now;
+        records.insert(key, record.clone());
+        Ok(record)
+    })
+}
+
+// A proving key was announced for the record but has not been fully written
+fn installing(record: &KeyRecord) -> bool {
+    record.proving_key_size > 0 && record.proving_key_hash.is_none()
+}
+
+fn insert_record(
+    circuit: &str,
+    verifying_key: Vec<u8>,
+    proving_key_hash: Option<String>,
+    proving_key_size: u64,
+    now: u64,
+) -> KeyRecord {
+    let version = next_version(circuit);
+    let param_id = format!("{}-v{}", circuit, version);
+
+    let record = KeyRecord {
+        param_id: param_id.clone(),
+        circuit: circuit.to_string(),
+        version,
+        status: KeyStatus::Pending,
+        verifying_key_hash: content_hash(&verifying_key),
+        verifying_key,
+        proving_key_hash,
+        proving_key_size,
+        created_at: now,
+        updated_at: now,
+    };
+
+    KEY_RECORDS.with(|records| {
+        records.borrow_mut().insert(StorableString(param_id), record.clone());
+    });
+
+    record
+}
+
+fn write_proving_key(param_id: &str, bytes: &[u8]) {
+    PROVING_KEYS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for (index, chunk) in bytes.chunks(PROVING_KEY_CHUNK_SIZE).enumerate() {
+            let key = ChunkKey { owner: param_id.to_string(), index: index as u32 };
+            chunks.insert(key, Chunk(chunk.to_vec()));
+        }
+    });
+}
+
+fn read_proving_key(param_id: &str) -> Vec<u8> {
+    let start = ChunkKey { owner: param_id.to_string(), index: 0 };
+    let end = ChunkKey { owner: param_id.to_string(), index: u32::MAX };
+    PROVING_KEYS.with(|chunks| {
+        chunks.borrow()
+            .range(start..=end)
+            .flat_map(|(_, chunk)| chunk.0)
+            .collect()
+    })
+}
+
+fn set_status(param_id: &str, status: KeyStatus, now: u64) -> Result<KeyRecord, KeyError> {
+    KEY_RECORDS.with(|records| {
+        let mut records = records.borrow_mut();
+        let key = StorableString(param_id.to_string());
+        let mut record = records.get(&key)
+            .ok_or_else(|| KeyError::UnknownKey(param_id.to_string()))?;
+
+        if record.status == KeyStatus::Retired {
+            return Err(KeyError::RetiredKey(param_id.to_string()));
+        }
+
+        record.status = status;
+        record.updated_at = now;
+        records.insert(key, record.clone());
+        Ok(record)
+    })
+}
+
+pub fn activate(param_id: &str, now: u64) -> Result<KeyRecord, KeyError> {
+    if get(param_id).map_or(false, |record| installing(&record)) {
+        return Err(KeyError::MissingProvingKey(param_id.to_string()));
+    }
+    set_status(param_id, KeyStatus::Active, now)
+}
+
+// Retirement is final: a retired param_id can never be reactivated
+pub fn retire(param_id: &str, now: u64) -> Result<KeyRecord, KeyError> {
+    set_status(param_id, KeyStatus::Retired, now)
+}
+
+pub fn get(param_id: &str) -> Option<KeyRecord> {
+    KEY_RECORDS.with(|records| records.borrow().get(&StorableString(param_id.to_string())))
+}
+
+pub fn list(circuit: Option<&str>) -> Vec<KeyRecord> {
+    KEY_RECORDS.with(|records| {
+        records.borrow()
+            .iter()
+            .map(|(_, record)| record)
+            .filter(|record| circuit.map_or(true, |c| record.circuit == c))
+            .collect()
+    })
+}
+
+fn usable(param_id: &str) -> Result<KeyRecord, KeyError> {
+    let record = get(param_id).ok_or_else(|| KeyError::UnknownKey(param_id.to_string()))?;
+    match record.status {
+        KeyStatus::Active => Ok(record),
+        KeyStatus::Pending => Err(KeyError::InactiveKey(param_id.to_string())),
+        KeyStatus::Retired => Err(KeyError::RetiredKey(param_id.to_string())),
+    }
+}
+
+pub fn proving_key(param_id: &str) -> Result<ProvingKey<Bn254>, KeyError> {
+    let record = usable(param_id)?;
+    if record.proving_key_hash.is_none() {
+        return Err(KeyError::MissingProvingKey(param_id.to_string()));
+    }
+    // Keys are only installed by controllers, so skip the per-point subgroup checks
+    ProvingKey::deserialize_uncompressed_unchecked(read_proving_key(param_id).as_slice())
+        .map_err(|e| KeyError::InvalidKey(format!("Corrupted proving key: {}", e)))
+}
+
+pub fn verifying_key(param_id: &str) -> Result<VerifyingKey<Bn254>, KeyError> {
+    let record = usable(param_id)?;
+    VerifyingKey::deserialize_compressed(record.verifying_key.as_slice())
+        .map_err(|e| KeyError::InvalidKey(format!("Corrupted verifying key: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit;
+    use ark_std::rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn test_keys(seed: u64) -> (Vec<u8>, Vec<u8>) {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let (pk, vk) = circuit::generate_keys(&mut rng).unwrap();
+        let mut pk_bytes = Vec::new();
+        pk.serialize_uncompressed(&mut pk_bytes).unwrap();
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        (pk_bytes, vk_bytes)
+    }
+
+    #[test]
+    fn key_lifecycle() {
+        let (pk_bytes, vk_bytes) = test_keys(1);
+
+        let first = register("ownership", Some(&pk_bytes), None, 10).unwrap();
+        assert_eq!(first.param_id, "ownership-v1");
+        assert_eq!(first.status, KeyStatus::Pending);
+        assert_eq!(first.verifying_key, vk_bytes);
+        assert_eq!(first.proving_key_hash, Some(content_hash(&pk_bytes)));
+        assert!(pk_bytes.len() > PROVING_KEY_CHUNK_SIZE);
+
+        // Pending keys cannot be used yet
+        assert_eq!(proving_key("ownership-v1").err(), Some(KeyError::InactiveKey("ownership-v1".to_string())));
+
+        activate("ownership-v1", 20).unwrap();
+        assert_eq!(proving_key("ownership-v1").unwrap().vk, verifying_key("ownership-v1").unwrap());
+        assert_eq!(read_proving_key("ownership-v1"), pk_bytes);
+
+        // A verify-only entry becomes the next version of the same circuit
+        let second = register("ownership", None, Some(&vk_bytes), 30).unwrap();
+        assert_eq!(second.version, 2);
+        activate("ownership-v2", 40).unwrap();
+        assert_eq!(proving_key("ownership-v2").err(), Some(KeyError::MissingProvingKey("ownership-v2".to_string())));
+        assert!(verifying_key("ownership-v2").is_ok());
+
+        retire("ownership-v1", 50).unwrap();
+        assert_eq!(verifying_key("ownership-v1").err(), Some(KeyError::RetiredKey("ownership-v1".to_string())));
+        assert_eq!(activate("ownership-v1", 60).err(), Some(KeyError::RetiredKey("ownership-v1".to_string())));
+        assert_eq!(verifying_key("missing-v1").err(), Some(KeyError::UnknownKey("missing-v1".to_string())));
+
+        let listed = list(Some("ownership"));
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].status, KeyStatus::Retired);
+        assert_eq!(listed[0].updated_at, 50);
+        assert!(list(Some("other")).is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_material() {
+        assert!(matches!(register("Bad Name", None, Some(&[1, 2, 3]), 0), Err(KeyError::InvalidCircuitName(_))));
+        assert!(matches!(register("circuit", None, Some(&[1, 2, 3]), 0), Err(KeyError::InvalidKey(_))));
+        assert!(matches!(register("circuit", None, None, 0), Err(KeyError::InvalidKey(_))));
+    }
+}
@@ -0,0 +1,618 @@
+use crate::memory::{self, Memory};
+use crate::registry::{self, Chunk, ChunkKey, KeyError, KeyRecord, PROVING_KEY_CHUNK_SIZE};
+use ark_bn254::{Bn254, G1Affine, G2Affine};
+use ark_groth16::VerifyingKey;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, StableCell, Storable};
+use serde::Serialize;
+use sha2::compress256;
+use sha2::digest::generic_array::GenericArray;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Upper bound for a single parameter blob. prove_ownership reads the whole proving
+// key back in one message, so a key has to fit comfortably within one.
+pub const MAX_UPLOAD_SIZE: u64 = 32 * 1024 * 1024;
+
+// Staged chunks moved into the registry by one commit_upload call (4 MiB)
+pub const COMMIT_CHUNKS_PER_CALL: u32 = 256;
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum UploadKind {
+    ProvingKey,
+    VerifyingKey,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct UploadSession {
+    pub upload_id: u64,
+    pub circuit: String,
+    pub kind: UploadKind,
+    pub total_size: u64,
+    pub sha256: String,
+    pub received: u64,
+    pub created_at: u64,
+    // SHA-256 of the bytes received so far: the compression state after the last
+    // full block, and the bytes of the block not yet full
+    pub digest_state: Vec<u8>,
+    pub digest_tail: Vec<u8>,
+    // Set once commit has checked the upload and registered its key; the proving
+    // key is then moved over COMMIT_CHUNKS_PER_CALL chunks at a time
+    pub param_id: Option<String>,
+    pub installed_chunks: u32,
+}
+
+// Outcome of one commit_upload call
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum CommitProgress {
+    // Call commit_upload again to move the next chunks
+    Installing(UploadSession),
+    Registered(KeyRecord),
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum UploadError {
+    NotAuthorized,
+    InvalidRequest(String),
+    UnknownUpload(u64),
+    OffsetMismatch { expected: u64, actual: u64 },
+    ExceedsDeclaredSize { declared: u64, attempted: u64 },
+    Incomplete { received: u64, total_size: u64 },
+    HashMismatch { expected: String, actual: String },
+    Key(KeyError),
+}
+
+impl Storable for UploadSession {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl BoundedStorable for UploadSession {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static UPLOAD_SESSIONS: RefCell<StableBTreeMap<u64, UploadSession, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::UPLOAD_SESSIONS)));
+
+    static UPLOAD_CHUNKS: RefCell<StableBTreeMap<ChunkKey, Chunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::UPLOAD_CHUNKS)));
+
+    static NEXT_UPLOAD_ID: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(memory::get(memory::NEXT_UPLOAD_ID), 1)
+            .expect("failed to initialize the upload id counter"));
+}
+
+// (Re)open the upload stable structures; run from post_upgrade
+pub fn init() {
+    UPLOAD_SESSIONS.with(|sessions| {
+        *sessions.borrow_mut() = StableBTreeMap::init(memory::get(memory::UPLOAD_SESSIONS));
+    });
+    UPLOAD_CHUNKS.with(|chunks| {
+        *chunks.borrow_mut() = StableBTreeMap::init(memory::get(memory::UPLOAD_CHUNKS));
+    });
+    NEXT_UPLOAD_ID.with(|counter| {
+        *counter.borrow_mut() = StableCell::init(memory::get(memory::NEXT_UPLOAD_ID), 1)
+            .expect("failed to initialize the upload id counter");
+    });
+}
+
+fn chunk_owner(upload_id: u64) -> String {
+    format!("upload-{}", upload_id)
+}
+
+fn session(upload_id: u64) -> Result<UploadSession, UploadError> {
+    UPLOAD_SESSIONS.with(|sessions| sessions.borrow().get(&upload_id))
+        .ok_or(UploadError::UnknownUpload(upload_id))
+}
+
+fn save(session: &UploadSession) {
+    UPLOAD_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(session.upload_id, session.clone());
+    });
+}
+
+fn chunk_count(size: u64) -> u32 {
+    (size as usize).div_ceil(PROVING_KEY_CHUNK_SIZE) as u32
+}
+
+const SHA256_INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+// SHA-256 of a blob that arrives over many messages. Between messages only the
+// compression state and the tail of an unfinished block are kept, in the session.
+struct RunningSha256 {
+    state: [u32; 8],
+    tail: Vec<u8>,
+}
+
+impl RunningSha256 {
+    fn new() -> Self {
+        RunningSha256 { state: SHA256_INITIAL_STATE, tail: Vec::new() }
+    }
+
+    fn resume(session: &UploadSession) -> Self {
+        let mut state = [0u32; 8];
+        for (word, bytes) in state.iter_mut().zip(session.digest_state.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        RunningSha256 { state, tail: session.digest_tail.clone() }
+    }
+
+    fn save(&self, session: &mut UploadSession) {
+        session.digest_state = self.state.iter().flat_map(|word| word.to_be_bytes()).collect();
+        session.digest_tail = self.tail.clone();
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        self.tail.extend_from_slice(bytes);
+        let full = self.tail.len() - self.tail.len() % 64;
+        let blocks: Vec<_> = self.tail[..full].chunks_exact(64).map(GenericArray::clone_from_slice).collect();
+        compress256(&mut self.state, &blocks);
+        self.tail.drain(..full);
+    }
+
+    // Hex digest of the `length` bytes fed to update
+    fn finalize(mut self, length: u64) -> String {
+        let zeros = (64 + 55 - self.tail.len()) % 64;
+        let mut padding = vec![0x80];
+        padding.resize(1 + zeros, 0);
+        padding.extend_from_slice(&length.wrapping_mul(8).to_be_bytes());
+        self.update(&padding);
+        hex::encode(self.state.iter().flat_map(|word| word.to_be_bytes()).collect::<Vec<u8>>())
+    }
+}
+
+// Open an upload for a blob of `total_size` bytes whose SHA-256 is `sha256` (hex)
+pub fn begin(
+    circuit: &str,
+    kind: UploadKind,
+    total_size: u64,
+    sha256: &str,
+    now: u64,
+) -> Result<UploadSession, UploadError> {
+    // commit registers the key under `circuit`; checking the name up front also
+    // keeps the stored session inside its bound
+    registry::validate_circuit_name(circuit).map_err(UploadError::Key)?;
+    if total_size == 0 || total_size > MAX_UPLOAD_SIZE {
+        return Err(UploadError::InvalidRequest(format!(
+            "Upload size must be between 1 and {} bytes",
+            MAX_UPLOAD_SIZE
+        )));
+    }
+    let sha256 = sha256.to_lowercase();
+    if sha256.len() != 64 || hex::decode(&sha256).is_err() {
+        return Err(UploadError::InvalidRequest("sha256 must be 64 hex characters".to_string()));
+    }
+
+    let upload_id = NEXT_UPLOAD_ID.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let id = *counter.get();
+        counter.set(id + 1).expect("failed to bump the upload id counter");
+        id
+    });
+
+    let mut session = UploadSession {
+        upload_id,
+        circuit: circuit.to_string(),
+        kind,
+        total_size,
+        sha256,
+        received: 0,
+        created_at: now,
+        digest_state: Vec::new(),
+        digest_tail: Vec::new(),
+        param_id: None,
+        installed_chunks: 0,
+    };
+    RunningSha256::new().save(&mut session);
+    save(&session);
+
+    Ok(session)
+}
+
+// Append `bytes` at `offset`. The offset must equal the bytes received so far, which
+// makes a retried append of an already stored piece fail instead of duplicating data.
+pub fn append(upload_id: u64, offset: u64, bytes: &[u8]) -> Result<UploadSession, UploadError> {
+    let mut session = session(upload_id)?;
+
+    if offset != session.received {
+        return Err(UploadError::OffsetMismatch { expected: session.received, actual: offset });
+    }
+    let attempted = session.received + bytes.len() as u64;
+    if attempted > session.total_size {
+        return Err(UploadError::ExceedsDeclaredSize { declared: session.total_size, attempted });
+    }
+
+    let owner = chunk_owner(upload_id);
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let mut position = session.received as usize;
+        let mut remaining = bytes;
+
+        while !remaining.is_empty() {
+            let key = ChunkKey { owner: owner.clone(), index: (position / PROVING_KEY_CHUNK_SIZE) as u32 };
+            // Top up a partially filled chunk before starting a new one
+            let mut chunk = chunks.get(&key).map(|c| c.0).unwrap_or_default();
+            let take = (PROVING_KEY_CHUNK_SIZE - chunk.len()).min(remaining.len());
+            chunk.extend_from_slice(&remaining[..take]);
+            chunks.insert(key, Chunk(chunk));
+
+            position += take;
+            remaining = &remaining[take..];
+        }
+    });
+
+    // Hash as the bytes arrive, so commit never has to read the blob back whole
+    let mut digest = RunningSha256::resume(&session);
+    digest.update(bytes);
+    digest.save(&mut session);
+    session.received = attempted;
+    save(&session);
+
+    Ok(session)
+}
+
+#[cfg(test)]
+fn read_chunks(owner: &str) -> Vec<u8> {
+    let start = ChunkKey { owner: owner.to_string(), index: 0 };
+    let end = ChunkKey { owner: owner.to_string(), index: u32::MAX };
+    UPLOAD_CHUNKS.with(|chunks| {
+        chunks.borrow()
+            .range(start..=end)
+            .flat_map(|(_, chunk)| chunk.0)
+            .collect()
+    })
+}
+
+// Bytes [offset, offset + len) of the staged blob, read from the chunks holding them
+fn read_range(owner: &str, offset: u64, len: u64) -> Vec<u8> {
+    if len == 0 {
+        return Vec::new();
+    }
+    let chunk_size = PROVING_KEY_CHUNK_SIZE as u64;
+    let first = offset / chunk_size;
+    let last = (offset + len - 1) / chunk_size;
+    let start = ChunkKey { owner: owner.to_string(), index: first as u32 };
+    let end = ChunkKey { owner: owner.to_string(), index: last as u32 };
+    let bytes: Vec<u8> = UPLOAD_CHUNKS.with(|chunks| {
+        chunks.borrow()
+            .range(start..=end)
+            .flat_map(|(_, chunk)| chunk.0)
+            .collect()
+    });
+    let skip = (offset - first * chunk_size) as usize;
+    bytes[skip..skip + len as usize].to_vec()
+}
+
+fn invalid_key(reason: String) -> UploadError {
+    UploadError::Key(KeyError::InvalidKey(reason))
+}
+
+// Check that a staged proving key is laid out the way ark-groth16 serializes one
+// uncompressed: its verifying key, two G1 points, then five length-prefixed point
+// vectors that end exactly at `total_size`. Only the verifying key is parsed, with
+// the usual curve checks; the other points are trusted as for every proving key
+// (see registry::proving_key). Returns the verifying key, compressed.
+fn check_proving_key_layout(owner: &str, total_size: u64) -> Result<Vec<u8>, UploadError> {
+    let g1 = G1Affine::default().uncompressed_size() as u64;
+    let g2 = G2Affine::default().uncompressed_size() as u64;
+
+    // Skip a vector of `point_size` points at `offset`, returning where it ends
+    let vector_end = |offset: u64, point_size: u64| -> Result<u64, UploadError> {
+        if offset + 8 > total_size {
+            return Err(invalid_key(format!("Proving key ends at byte {} inside its layout", total_size)));
+        }
+        let len = u64::from_le_bytes(read_range(owner, offset, 8).try_into().unwrap());
+        len.checked_mul(point_size)
+            .and_then(|size| size.checked_add(offset + 8))
+            .filter(|end| *end <= total_size)
+            .ok_or_else(|| invalid_key(format!("Vector of {} points at byte {} overruns the proving key", len, offset)))
+    };
+
+    // alpha_g1, beta_g2, gamma_g2, delta_g2, then gamma_abc_g1
+    let vk_end = vector_end(g1 + 3 * g2, g1)?;
+    let verifying_key = VerifyingKey::<Bn254>::deserialize_uncompressed(read_range(owner, 0, vk_end).as_slice())
+        .map_err(|e| invalid_key(format!("Malformed verifying key in proving key: {}", e)))?;
+
+    // beta_g1 and delta_g1, then a_query, b_g1_query, b_g2_query, h_query, l_query
+    let mut offset = vk_end + 2 * g1;
+    for point_size in [g1, g1, g2, g1, g1] {
+        offset = vector_end(offset, point_size)?;
+    }
+    if offset != total_size {
+        return Err(invalid_key(format!("Proving key layout ends at byte {}, upload has {}", offset, total_size)));
+    }
+
+    let mut vk_bytes = Vec::new();
+    verifying_key.serialize_compressed(&mut vk_bytes)
+        .map_err(|e| invalid_key(e.to_string()))?;
+    Ok(vk_bytes)
+}
+
+// Commit a fully received upload in bounded steps. The first call checks the digest
+// and layout and registers the key as a new Pending version; proving key chunks are
+// then moved into the registry COMMIT_CHUNKS_PER_CALL at a time, and the key can only
+// be activated once the last call returns Registered. On a digest mismatch the session
+// is kept so the caller can inspect and cancel it.
+pub fn commit(upload_id: u64, now: u64) -> Result<CommitProgress, UploadError> {
+    commit_chunks(upload_id, now, COMMIT_CHUNKS_PER_CALL)
+}
+
+fn commit_chunks(upload_id: u64, now: u64, max_chunks: u32) -> Result<CommitProgress, UploadError> {
+    let mut session = session(upload_id)?;
+    if session.received != session.total_size {
+        return Err(UploadError::Incomplete { received: session.received, total_size: session.total_size });
+    }
+    let owner = chunk_owner(upload_id);
+
+    let param_id = match session.param_id.clone() {
+        Some(param_id) => param_id,
+        None => {
+            let actual = RunningSha256::resume(&session).finalize(session.total_size);
+            if actual != session.sha256 {
+                return Err(UploadError::HashMismatch { expected: session.sha256, actual });
+            }
+
+            let record = match session.kind {
+                UploadKind::VerifyingKey => {
+                    // Verifying keys are a few hundred bytes
+                    let vk_bytes = read_range(&owner, 0, session.total_size);
+                    let record = registry::register(&session.circuit, None, Some(&vk_bytes), now)
+                        .map_err(UploadError::Key)?;
+                    cancel(upload_id)?;
+                    return Ok(CommitProgress::Registered(record));
+                }
+                UploadKind::ProvingKey => {
+                    let vk_bytes = check_proving_key_layout(&owner, session.total_size)?;
+                    registry::reserve_proving_key(&session.circuit, &vk_bytes, session.total_size, now)
+                        .map_err(UploadError::Key)?
+                }
+            };
+            session.param_id = Some(record.param_id.clone());
+            record.param_id
+        }
+    };
+
+    let total_chunks = chunk_count(session.total_size);
+    let end = session.installed_chunks.saturating_add(max_chunks).min(total_chunks);
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in session.installed_chunks..end {
+            let chunk = chunks.remove(&ChunkKey { owner: owner.clone(), index })
+                .expect("a fully received upload has every chunk");
+            registry::write_proving_key_chunk(&param_id, index, chunk);
+        }
+    });
+    session.installed_chunks = end;
+
+    if end < total_chunks {
+        save(&session);
+        return Ok(CommitProgress::Installing(session));
+    }
+    let record = registry::finish_proving_key(&param_id, &session.sha256, now).map_err(UploadError::Key)?;
+    UPLOAD_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().remove(&upload_id);
+    });
+    Ok(CommitProgress::Registered(record))
+}
+
+// Drop a session together with its staged chunks. An upload whose key has been
+// registered can only be finished with commit.
+pub fn cancel(upload_id: u64) -> Result<(), UploadError> {
+    let session = session(upload_id)?;
+    if let Some(param_id) = &session.param_id {
+        return Err(UploadError::InvalidRequest(format!(
+            "Upload is being installed as {}; finish it with commit_upload",
+            param_id
+        )));
+    }
+    let owner = chunk_owner(upload_id);
+
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in 0..chunk_count(session.received) {
+            chunks.remove(&ChunkKey { owner: owner.clone(), index });
+        }
+    });
+    UPLOAD_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().remove(&upload_id);
+    });
+    Ok(())
+}
+
+pub fn list() -> Vec<UploadSession> {
+    UPLOAD_SESSIONS.with(|sessions| {
+        sessions.borrow().iter().map(|(_, session)| session).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit;
+    use crate::registry::KeyStatus;
+    use ark_std::rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use sha2::{Digest, Sha256};
+
+    fn digest(bytes: &[u8]) -> String {
+        hex::encode(Sha256::digest(bytes))
+    }
+
+    fn proving_key_bytes(seed: u64) -> Vec<u8> {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let (pk, _) = circuit::generate_keys(&mut rng).unwrap();
+        let mut pk_bytes = Vec::new();
+        pk.serialize_uncompressed(&mut pk_bytes).unwrap();
+        pk_bytes
+    }
+
+    fn upload(kind: UploadKind, bytes: &[u8], piece: usize) -> UploadSession {
+        let session = begin("ownership", kind, bytes.len() as u64, &digest(bytes), 1).unwrap();
+        let mut offset = 0;
+        for piece in bytes.chunks(piece) {
+            offset = append(session.upload_id, offset, piece).unwrap().received;
+        }
+        session
+    }
+
+    #[test]
+    fn running_digest_matches_sha256() {
+        let data: Vec<u8> = (0..300u32).map(|i| (i * 7) as u8).collect();
+        for len in [0, 1, 55, 56, 63, 64, 65, 119, 120, 128, 300] {
+            for piece in [1, 13, 64, 100] {
+                let mut running = RunningSha256::new();
+                for bytes in data[..len].chunks(piece) {
+                    running.update(bytes);
+                }
+                assert_eq!(running.finalize(len as u64), digest(&data[..len]), "{} bytes in pieces of {}", len, piece);
+            }
+        }
+    }
+
+    #[test]
+    fn uploads_proving_key_in_uneven_pieces() {
+        let pk_bytes = proving_key_bytes(3);
+        let session = upload(UploadKind::ProvingKey, &pk_bytes, PROVING_KEY_CHUNK_SIZE + 1234);
+        assert_eq!(read_chunks(&chunk_owner(session.upload_id)), pk_bytes);
+
+        let CommitProgress::Registered(record) = commit(session.upload_id, 2).unwrap() else {
+            panic!("a key this small is installed in one call");
+        };
+        assert_eq!(record.status, KeyStatus::Pending);
+        assert_eq!(record.proving_key_hash, Some(digest(&pk_bytes)));
+        assert_eq!(record.proving_key_size, pk_bytes.len() as u64);
+        registry::activate(&record.param_id, 3).unwrap();
+        assert!(registry::proving_key(&record.param_id).is_ok());
+
+        // Staged data is gone once the key is registered
+        assert!(list().is_empty());
+        assert!(read_chunks(&chunk_owner(session.upload_id)).is_empty());
+        assert_eq!(commit(session.upload_id, 3), Err(UploadError::UnknownUpload(session.upload_id)));
+    }
+
+    #[test]
+    fn commits_in_bounded_steps() {
+        let pk_bytes = proving_key_bytes(4);
+        let total_chunks = chunk_count(pk_bytes.len() as u64);
+        assert!(total_chunks > 2);
+        let session = upload(UploadKind::ProvingKey, &pk_bytes, 100_000);
+
+        let CommitProgress::Installing(installing) = commit_chunks(session.upload_id, 2, 2).unwrap() else {
+            panic!("expected more chunks to move");
+        };
+        let param_id = installing.param_id.clone().unwrap();
+        assert_eq!(installing.installed_chunks, 2);
+        // The key is registered but cannot be used, and the upload must be finished
+        let reserved = registry::get(&param_id).unwrap();
+        assert_eq!(reserved.proving_key_hash, None);
+        assert_eq!(registry::activate(&param_id, 3), Err(KeyError::MissingProvingKey(param_id.clone())));
+        assert!(matches!(cancel(session.upload_id), Err(UploadError::InvalidRequest(_))));
+
+        let mut calls = 1;
+        let record = loop {
+            calls += 1;
+            match commit_chunks(session.upload_id, 4, 2).unwrap() {
+                CommitProgress::Installing(progress) => assert_eq!(progress.installed_chunks, 2 * calls),
+                CommitProgress::Registered(record) => break record,
+            }
+        };
+        assert_eq!(calls, total_chunks.div_ceil(2));
+        assert_eq!(record.param_id, param_id);
+        assert_eq!(record.proving_key_hash, Some(digest(&pk_bytes)));
+        assert_eq!(record.verifying_key, reserved.verifying_key);
+        registry::activate(&param_id, 5).unwrap();
+        assert!(registry::proving_key(&param_id).is_ok());
+        assert!(list().is_empty());
+    }
+
+    #[test]
+    fn rejects_proving_keys_with_a_broken_layout() {
+        let pk_bytes = proving_key_bytes(5);
+        let truncated = &pk_bytes[..pk_bytes.len() - 64];
+        let session = upload(UploadKind::ProvingKey, truncated, PROVING_KEY_CHUNK_SIZE);
+        assert!(matches!(commit(session.upload_id, 2), Err(UploadError::Key(KeyError::InvalidKey(_)))));
+        cancel(session.upload_id).unwrap();
+
+        let mut padded = pk_bytes.clone();
+        padded.extend_from_slice(&[0; 64]);
+        let session = upload(UploadKind::ProvingKey, &padded, PROVING_KEY_CHUNK_SIZE);
+        assert!(matches!(commit(session.upload_id, 3), Err(UploadError::Key(KeyError::InvalidKey(_)))));
+
+        // A length prefix pointing far past the end is caught before anything is read
+        let mut overrun = pk_bytes;
+        let vk_fixed = (G1Affine::default().uncompressed_size() + 3 * G2Affine::default().uncompressed_size()) as usize;
+        overrun[vk_fixed..vk_fixed + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let session = upload(UploadKind::ProvingKey, &overrun, PROVING_KEY_CHUNK_SIZE);
+        assert!(matches!(commit(session.upload_id, 4), Err(UploadError::Key(KeyError::InvalidKey(_)))));
+        assert!(registry::list(Some("ownership")).is_empty());
+    }
+
+    #[test]
+    fn largest_session_fits_its_bound() {
+        let session = UploadSession {
+            upload_id: u64::MAX,
+            circuit: "c".repeat(64),
+            kind: UploadKind::VerifyingKey,
+            total_size: MAX_UPLOAD_SIZE,
+            sha256: "ab".repeat(32),
+            received: MAX_UPLOAD_SIZE,
+            created_at: u64::MAX,
+            digest_state: vec![0xff; 32],
+            digest_tail: vec![0xff; 63],
+            param_id: Some(format!("{}-v{}", "c".repeat(64), u32::MAX)),
+            installed_chunks: u32::MAX,
+        };
+        assert!(session.to_bytes().len() <= UploadSession::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn enforces_offsets_size_and_digest() {
+        let data = vec![7u8; 100];
+        let session = begin("ownership", UploadKind::VerifyingKey, 100, &digest(&[0u8; 100]), 1).unwrap();
+
+        assert_eq!(
+            append(session.upload_id, 10, &data[..10]),
+            Err(UploadError::OffsetMismatch { expected: 0, actual: 10 })
+        );
+        append(session.upload_id, 0, &data[..60]).unwrap();
+        assert_eq!(
+            commit(session.upload_id, 2),
+            Err(UploadError::Incomplete { received: 60, total_size: 100 })
+        );
+        assert_eq!(
+            append(session.upload_id, 60, &data),
+            Err(UploadError::ExceedsDeclaredSize { declared: 100, attempted: 160 })
+        );
+        append(session.upload_id, 60, &data[60..]).unwrap();
+
+        assert_eq!(
+            commit(session.upload_id, 3),
+            Err(UploadError::HashMismatch { expected: digest(&[0u8; 100]), actual: digest(&data) })
+        );
+        cancel(session.upload_id).unwrap();
+        assert!(list().is_empty());
+
+        assert!(matches!(begin("ownership", UploadKind::ProvingKey, 0, &digest(&data), 1), Err(UploadError::InvalidRequest(_))));
+        assert!(matches!(begin("ownership", UploadKind::ProvingKey, MAX_UPLOAD_SIZE + 1, &digest(&data), 1), Err(UploadError::InvalidRequest(_))));
+        assert!(matches!(begin("ownership", UploadKind::ProvingKey, 10, "abc", 1), Err(UploadError::InvalidRequest(_))));
+        assert!(matches!(
+            begin(&"c".repeat(1000), UploadKind::ProvingKey, 10, &digest(&data), 1),
+            Err(UploadError::Key(KeyError::InvalidCircuitName(_)))
+        ));
+        assert!(matches!(
+            begin("Ownership", UploadKind::ProvingKey, 10, &digest(&data), 1),
+            Err(UploadError::Key(KeyError::InvalidCircuitName(_)))
+        ));
+        assert!(list().is_empty());
+    }
+}
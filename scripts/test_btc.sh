@@ -0,0 +1,79 @@
+#!/bin/bash
+
+# Attests a Bitcoin balance against a local regtest node.
+#
+# Needs bitcoind in regtest mode and a replica with the Bitcoin integration:
+#   bitcoind -regtest -txindex -fallbackfee=0.0002 -rpcuser=ic-btc-integration \
+#     -rpcpassword=ic-btc-integration -port=18444 -daemon
+#   dfx start --clean --background --enable-bitcoin --bitcoin-node 127.0.0.1:18444
+#
+# Bitcoin Core cannot produce BIP-322 signatures, so ADDRESS (a bcrt1q... or
+# bcrt1p... address) and SIGNATURE (its BIP-322 simple signature of the message
+# printed below, e.g. from the bip322-js package) are passed in. The script funds
+# ADDRESS by mining to it.
+
+# Colors for output
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+NC='\033[0m' # No Color
+YELLOW='\033[1;33m'
+
+# Function to print section headers
+print_header() {
+    echo -e "\n${YELLOW}=== $1 ===${NC}\n"
+}
+
+# Function to check command success
+check_success() {
+    if [ $? -eq 0 ]; then
+        echo -e "${GREEN}✓ $1 succeeded${NC}"
+    else
+        echo -e "${RED}✗ $1 failed${NC}"
+        exit 1
+    fi
+}
+
+BITCOIN_CLI="bitcoin-cli -regtest -rpcuser=ic-btc-integration -rpcpassword=ic-btc-integration"
+
+attest() {
+    dfx canister call main_canister attest_btc_balance "(record { address = \"$ADDRESS\"; signature = \"$1\"; min_satoshis = $2 : nat64 })"
+}
+
+print_header "Checking DFX Status"
+dfx ping
+check_success "DFX status check"
+$BITCOIN_CLI getblockchaininfo > /dev/null
+check_success "Regtest node check"
+
+print_header "Deploying Canisters"
+dfx deploy main_canister
+check_success "Main canister deployment"
+dfx canister call main_canister set_btc_config '(record { network = variant { Regtest }; min_confirmations = 1 : nat32 })'
+check_success "Switching to regtest"
+
+print_header "Message To Sign"
+dfx canister call main_canister get_btc_attestation_message "(\"${ADDRESS:-bcrt1q...}\")"
+if [ -z "$ADDRESS" ] || [ -z "$SIGNATURE" ]; then
+    echo -e "${RED}Sign the message above with ADDRESS and rerun with ADDRESS and SIGNATURE set${NC}"
+    exit 1
+fi
+
+print_header "Funding The Address"
+# Coinbase outputs of the first block mature after 100 more
+$BITCOIN_CLI generatetoaddress 101 "$ADDRESS" > /dev/null
+check_success "Mining to $ADDRESS"
+# Give the replica time to sync the new blocks
+sleep 10
+
+print_header "Attesting Balances"
+attest "$SIGNATURE" 100_000_000 | grep -q "tip_height"
+check_success "Balance of at least 1 BTC"
+attest "$SIGNATURE" 100_000_000_000_000 | grep -q "below"
+check_success "Balance below the threshold"
+# Any valid base64 that is not a signature by ADDRESS
+attest "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=" 1 | grep -q "Err"
+check_success "Signature from another key"
+dfx canister call --identity anonymous main_canister attest_btc_balance "(record { address = \"$ADDRESS\"; signature = \"$SIGNATURE\"; min_satoshis = 1 : nat64 })" | grep -q "Anonymous"
+check_success "Anonymous callers"
+
+echo -e "\n${GREEN}All Bitcoin tests completed!${NC}"
@@ -0,0 +1,90 @@
+#!/bin/bash
+
+# Reads EVM balances and NFT ownership through the local mock EVM RPC canister
+
+# Colors for output
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+NC='\033[0m' # No Color
+YELLOW='\033[1;33m'
+
+# Function to print section headers
+print_header() {
+    echo -e "\n${YELLOW}=== $1 ===${NC}\n"
+}
+
+# Function to check command success
+check_success() {
+    if [ $? -eq 0 ]; then
+        echo -e "${GREEN}✓ $1 succeeded${NC}"
+    else
+        echo -e "${RED}✗ $1 failed${NC}"
+        exit 1
+    fi
+}
+
+balance() {
+    dfx canister call main_canister get_evm_balance "(record { chain_id = 1 : nat64; contract_address = \"$1\"; wallet_address = \"$WALLET\"; token_standard = variant { $2 }; token_id = $3 })"
+}
+
+verify() {
+    dfx canister call main_canister verify_nft_ownership "(record { wallet_address = \"$WALLET\"; chain_id = \"eth\"; nft_contract_address = opt \"$1\"; token_id = $2 })"
+}
+
+print_header "Checking DFX Status"
+dfx ping
+check_success "DFX status check"
+
+print_header "Deploying Canisters"
+dfx deploy mock_evm_rpc
+check_success "Mock EVM RPC deployment"
+dfx deploy main_canister
+check_success "Main canister deployment"
+
+RPC=$(dfx canister id mock_evm_rpc)
+dfx canister call main_canister set_evm_config "(record { rpc_canister = principal \"$RPC\"; min_agreeing = 2 : nat8; cycles_per_call = 0 : nat64 })"
+check_success "Pointing the main canister at the mock"
+
+WALLET="0x00000000000000000000000000000000000000aa"
+OTHER="0x00000000000000000000000000000000000000bb"
+ERC20="0x0000000000000000000000000000000000000020"
+ERC721="0x0000000000000000000000000000000000000721"
+ERC1155="0x0000000000000000000000000000000000001155"
+
+print_header "Setting Contract State"
+dfx canister call mock_evm_rpc set_erc20_balance "(\"$ERC20\", \"$WALLET\", 5_000_000_000_000_000_000_000 : nat)"
+dfx canister call mock_evm_rpc set_erc721_owner "(\"$ERC721\", 7 : nat, \"$WALLET\")"
+dfx canister call mock_evm_rpc set_erc721_owner "(\"$ERC721\", 8 : nat, \"$OTHER\")"
+dfx canister call mock_evm_rpc set_erc1155_balance "(\"$ERC1155\", \"$WALLET\", 3 : nat, 12 : nat)"
+check_success "Contract state"
+
+print_header "Reading Balances"
+balance "$ERC20" ERC20 null | grep -q "5_000_000_000_000_000_000_000"
+check_success "ERC-20 balance beyond 64 bits"
+balance "$ERC721" ERC721 null | grep -q "Ok = 1 "
+check_success "ERC-721 token count"
+balance "$ERC1155" ERC1155 "opt (3 : nat)" | grep -q "Ok = 12 "
+check_success "ERC-1155 balance"
+
+print_header "Checking NFT Ownership"
+verify "$ERC721" 'opt "7"' | grep -q "is_verified = true"
+check_success "Owner of ERC-721 token 7"
+verify "$ERC721" 'opt "8"' | grep -q "is_verified = false"
+check_success "Not the owner of ERC-721 token 8"
+verify "$ERC721" 'opt "9"' | grep -q "is_verified = false"
+check_success "ERC-721 token that does not exist"
+verify "$ERC1155" 'opt "3"' | grep -q "is_verified = true"
+check_success "Holder of ERC-1155 token 3"
+verify "$ERC20" 'opt "1"' | grep -q "UnsupportedCollection"
+check_success "ERC-20 contract is not a collection"
+
+print_header "Checking Provider Consensus"
+dfx canister call mock_evm_rpc set_disagreeing_providers '(1 : nat8)'
+balance "$ERC1155" ERC1155 "opt (3 : nat)" | grep -q "Ok = 12 "
+check_success "Two of three providers agree"
+dfx canister call mock_evm_rpc set_disagreeing_providers '(2 : nat8)'
+balance "$ERC1155" ERC1155 "opt (3 : nat)" | grep -q "Err"
+check_success "Too few providers agree"
+dfx canister call mock_evm_rpc set_disagreeing_providers '(0 : nat8)'
+
+echo -e "\n${GREEN}All EVM tests completed!${NC}"
@@ -0,0 +1,64 @@
+#!/bin/bash
+
+# Checks NFT ownership against the local mock ICRC-7 collection
+
+# Colors for output
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+NC='\033[0m' # No Color
+YELLOW='\033[1;33m'
+
+# Function to print section headers
+print_header() {
+    echo -e "\n${YELLOW}=== $1 ===${NC}\n"
+}
+
+# Function to check command success
+check_success() {
+    if [ $? -eq 0 ]; then
+        echo -e "${GREEN}✓ $1 succeeded${NC}"
+    else
+        echo -e "${RED}✗ $1 failed${NC}"
+        exit 1
+    fi
+}
+
+verify() {
+    dfx canister call main_canister verify_nft_ownership "(record { wallet_address = \"$1\"; chain_id = \"$2\"; nft_contract_address = opt \"$3\"; token_id = $4 })"
+}
+
+print_header "Checking DFX Status"
+dfx ping
+check_success "DFX status check"
+
+print_header "Deploying Canisters"
+dfx deploy mock_icrc7
+check_success "Mock ICRC-7 deployment"
+dfx deploy main_canister
+check_success "Main canister deployment"
+
+COLLECTION=$(dfx canister id mock_icrc7)
+MAIN=$(dfx canister id main_canister)
+ALICE=$(dfx identity get-principal)
+
+print_header "Minting"
+dfx canister call mock_icrc7 mint "(1 : nat, record { owner = principal \"$ALICE\"; subaccount = null })"
+check_success "Mint"
+
+print_header "Checking Ownership"
+verify "$ALICE" icp "$COLLECTION" 'opt "1"' | grep -q "is_verified = true"
+check_success "Owner of token 1"
+verify "$ALICE" icp "$COLLECTION" 'opt "2"' | grep -q "is_verified = false"
+check_success "Not the owner of an unminted token"
+verify "$ALICE" icp "$COLLECTION" null | grep -q "is_verified = true"
+check_success "Holder of any token"
+
+print_header "Checking Errors"
+verify "$ALICE" solana "$COLLECTION" null | grep -q "UnsupportedChain"
+check_success "Unsupported chain"
+verify "$ALICE" icp "$MAIN" 'opt "1"' | grep -q "UnsupportedCollection"
+check_success "Unsupported collection"
+verify "aaaaa-aa" icp "$COLLECTION" null | grep -q "Unauthorized"
+check_success "Other principal without delegation"
+
+echo -e "\n${GREEN}All NFT tests completed!${NC}"
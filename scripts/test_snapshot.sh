@@ -0,0 +1,66 @@
+#!/bin/bash
+
+# Reads balances from the local mock ledger and builds a balance snapshot of it
+
+# Colors for output
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+NC='\033[0m' # No Color
+YELLOW='\033[1;33m'
+
+# Function to print section headers
+print_header() {
+    echo -e "\n${YELLOW}=== $1 ===${NC}\n"
+}
+
+# Function to check command success
+check_success() {
+    if [ $? -eq 0 ]; then
+        echo -e "${GREEN}✓ $1 succeeded${NC}"
+    else
+        echo -e "${RED}✗ $1 failed${NC}"
+        exit 1
+    fi
+}
+
+print_header "Checking DFX Status"
+dfx ping
+check_success "DFX status check"
+
+print_header "Deploying Canisters"
+dfx deploy mock_ledger
+check_success "Mock ledger deployment"
+dfx deploy main_canister
+check_success "Main canister deployment"
+
+LEDGER=$(dfx canister id mock_ledger)
+ALICE=$(dfx identity get-principal)
+BOB="aaaaa-aa"
+
+print_header "Writing Ledger Blocks"
+dfx canister call mock_ledger set_fee '(10 : nat64)'
+dfx canister call mock_ledger mint "(record { owner = principal \"$ALICE\"; subaccount = null }, 1_000 : nat64)"
+check_success "Mint"
+dfx canister call mock_ledger transfer "(record { owner = principal \"$ALICE\"; subaccount = null }, record { owner = principal \"$BOB\"; subaccount = null }, 300 : nat64)"
+check_success "Transfer"
+# Serve the mint from the archive callback
+dfx canister call mock_ledger archive '(1 : nat64)'
+check_success "Archive"
+
+print_header "Checking ICRC-1 Balances"
+dfx canister call mock_ledger icrc1_balance_of "(record { owner = principal \"$ALICE\"; subaccount = null })" | grep -q "690"
+check_success "Balance after transfer and fee"
+dfx canister call mock_ledger icrc1_symbol | grep -q "MOCK"
+check_success "Ledger symbol"
+
+print_header "Building Snapshot"
+dfx canister call main_canister register_snapshot_ledger "(principal \"$LEDGER\", 2 : nat64)"
+check_success "Ledger registration"
+dfx canister call main_canister build_balance_snapshot | grep -q "Frozen"
+check_success "Snapshot build"
+dfx canister call main_canister get_snapshot_state | grep -q "next_block = 2"
+check_success "Snapshot replayed every block"
+dfx canister call main_canister get_merkle_roots
+check_success "Snapshot root published"
+
+echo -e "\n${GREEN}All snapshot tests completed!${NC}"